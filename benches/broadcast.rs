@@ -0,0 +1,193 @@
+//! Starts the server binary and measures how it copes with many clients: how long they take to
+//! connect and register, what they cost the server while idle, and how long a channel message
+//! takes to reach every member
+//!
+//! Run with `cargo bench`, BENCH_CLIENTS and BENCH_ROUNDS change the client count (default
+//! 1000) and the number of messages broadcast (default 100)
+//!
+//! BENCH_SERVER runs another build of the server instead of this one, so that a change can be
+//! compared with the revision before it on the same machine, eg for the move to tokio:
+//!
+//! ```text
+//! git worktree add ../irc-threads 6d35229~
+//! cargo build --release --manifest-path ../irc-threads/Cargo.toml
+//! BENCH_SERVER=../irc-threads/target/release/irc cargo bench
+//! cargo bench
+//! ```
+//!
+//! Results depend heavily on the machine, so only compare runs made on the same one
+//! Broadcast latency is mostly the benchmark's own reader threads waking up, registration
+//! rate and the idle server's footprint are where changes to the networking core show
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const CHANNEL: &str = "#bench";
+
+enum Event {
+    Registered,
+    Received(usize),
+}
+
+struct Server {
+    process: Child,
+    address: String,
+    state_file: PathBuf,
+}
+
+impl Server {
+    fn start() -> Self {
+        // Grab a free port, then let the server have it
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let address = format!("127.0.0.1:{port}");
+        let state_file =
+            std::env::temp_dir().join(format!("irc-bench-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&state_file);
+        let program =
+            std::env::var_os("BENCH_SERVER").unwrap_or_else(|| env!("CARGO_BIN_EXE_irc").into());
+        println!("benchmarking {}", program.to_string_lossy());
+
+        let process = Command::new(program)
+            .arg(&state_file)
+            .env("IRC_LISTEN", &address)
+            // The broadcasting client sends far faster than flood protection allows
+            .env("IRC_FLOOD_EXEMPT", "127.0.0.1")
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while TcpStream::connect(&address).is_err() {
+            assert!(Instant::now() < deadline, "the server didn't start");
+            thread::sleep(Duration::from_millis(10));
+        }
+        Server {
+            process,
+            address,
+            state_file,
+        }
+    }
+
+    /// The server's resident memory in KiB and its thread count, from /proc
+    fn usage(&self) -> Option<(u64, u64)> {
+        let status = std::fs::read_to_string(format!("/proc/{}/status", self.process.id())).ok()?;
+        let field = |name: &str| {
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix(name))?
+                .split_whitespace()
+                .next()?
+                .parse()
+                .ok()
+        };
+        Some((field("VmRSS:")?, field("Threads:")?))
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+        let mut journal = self.state_file.clone().into_os_string();
+        journal.push(".journal");
+        let _ = std::fs::remove_file(&self.state_file);
+        let _ = std::fs::remove_file(journal);
+    }
+}
+
+/// Connects a client that registers and joins the bench channel, reporting what it sees
+fn connect(address: &str, id: usize, events: Sender<(usize, Event)>) -> TcpStream {
+    let mut stream = TcpStream::connect(address).unwrap();
+    write!(
+        stream,
+        "NICK bench{id}\r\nUSER bench 0 * :Bench\r\nJOIN {CHANNEL}\r\n"
+    )
+    .unwrap();
+
+    let reader = BufReader::new(stream.try_clone().unwrap());
+    thread::spawn(move || {
+        for line in reader.lines() {
+            let Ok(line) = line else { break };
+            let event = if line.contains(" 366 ") {
+                Event::Registered
+            } else if let Some((_, round)) = line.split_once(" :round ") {
+                Event::Received(round.parse().unwrap())
+            } else {
+                continue;
+            };
+            if events.send((id, event)).is_err() {
+                break;
+            }
+        }
+    });
+    stream
+}
+
+fn main() {
+    let clients: usize = env_or("BENCH_CLIENTS", 1000);
+    let rounds: usize = env_or("BENCH_ROUNDS", 100);
+    let server = Server::start();
+    let (events, received) = mpsc::channel();
+
+    let start = Instant::now();
+    let mut streams: Vec<TcpStream> = (0..clients)
+        .map(|id| connect(&server.address, id, events.clone()))
+        .collect();
+    let mut registered = 0;
+    while registered < clients {
+        if let (_, Event::Registered) = received.recv().unwrap() {
+            registered += 1;
+        }
+    }
+    let connect_time = start.elapsed();
+    println!(
+        "{clients} clients connected, registered and joined {CHANNEL} in {connect_time:?} ({:.0}/s)",
+        clients as f64 / connect_time.as_secs_f64()
+    );
+    if let Some((rss, threads)) = server.usage() {
+        println!("server while idle: {rss} KiB resident, {threads} threads");
+    }
+
+    let mut latencies = Vec::with_capacity(rounds);
+    for round in 0..rounds {
+        let sent = Instant::now();
+        write!(streams[0], "PRIVMSG {CHANNEL} :round {round}\r\n").unwrap();
+        let mut delivered = 0;
+        while delivered < clients - 1 {
+            if let (_, Event::Received(seen)) = received.recv().unwrap()
+                && seen == round
+            {
+                delivered += 1;
+            }
+        }
+        latencies.push(sent.elapsed());
+    }
+    latencies.sort();
+    let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100];
+    println!(
+        "broadcast to {} members over {rounds} rounds: p50 {:?}, p99 {:?}, max {:?}",
+        clients - 1,
+        percentile(50),
+        percentile(99),
+        percentile(100)
+    );
+
+    for stream in &mut streams {
+        let _ = stream.write_all(b"QUIT\r\n");
+    }
+}
+
+fn env_or(name: &str, default: usize) -> usize {
+    std::env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
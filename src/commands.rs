@@ -1,15 +1,24 @@
-#[derive(Debug, PartialEq)]
+use std::fmt;
+
+#[derive(Debug, PartialEq, Clone)]
 pub struct Command {
     pub prefix: Option<String>,
     pub kind: CommandKind,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum CommandKind {
+    Pass {
+        password: String,
+    },
     Join {
         channels: Vec<String>,
         keys: Option<Vec<String>>,
     },
+    Part {
+        channels: Vec<String>,
+        part_message: Option<String>,
+    },
     Nick {
         nickname: String,
     },
@@ -22,11 +31,183 @@ pub enum CommandKind {
         source_server: String,
         target_server: Option<String>,
     },
+    Pong {
+        source_server: String,
+        target_server: Option<String>,
+    },
     PrivMsg {
-        message_target: String,
+        message_targets: Vec<String>,
+        message_text: String,
+    },
+    Notice {
+        message_targets: Vec<String>,
         message_text: String,
     },
     Quit {
         quit_message: Option<String>,
     },
+    Mode {
+        target: String,
+        /// eg "+kl-n", left out to ask for the current modes
+        modes: Option<String>,
+        /// The parameters of the modes that take one, in order
+        params: Vec<String>,
+    },
+    Kick {
+        /// Either a single channel, or one for each user
+        channels: Vec<String>,
+        users: Vec<String>,
+        comment: Option<String>,
+    },
+    Topic {
+        channel: String,
+        /// Left out to ask for the topic, empty to clear it
+        topic: Option<String>,
+    },
+    Who {
+        /// A channel, or a mask matched against nicknames, hostnames and real names
+        mask: Option<String>,
+        /// Only list IRC operators
+        operators: bool,
+    },
+    Names {
+        /// Empty to ask for every channel
+        channels: Vec<String>,
+    },
+    Oper {
+        name: String,
+        password: String,
+    },
+    /// Writes the state file straight away
+    Flush,
+    /// Registers a channel, so that it and its details are kept when it empties and across
+    /// restarts
+    RegChan {
+        channel: String,
+    },
+}
+
+impl Command {
+    /// Renders the command as a complete line, including the CRLF terminator
+    pub fn to_wire(&self) -> String {
+        format!("{self}\r\n")
+    }
+}
+
+/// Adds the ':' that marks a final parameter as trailing, if the parameter needs it
+fn last_param(param: &str) -> String {
+    if param.is_empty() || param.contains(' ') || param.starts_with(':') {
+        format!(":{param}")
+    } else {
+        param.to_owned()
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(prefix) = &self.prefix {
+            write!(f, ":{prefix} ")?;
+        }
+        match &self.kind {
+            CommandKind::Pass { password } => write!(f, "PASS {}", last_param(password)),
+            CommandKind::Join { channels, keys } => {
+                write!(f, "JOIN {}", channels.join(","))?;
+                match keys {
+                    Some(keys) => write!(f, " {}", last_param(&keys.join(","))),
+                    None => Ok(()),
+                }
+            }
+            CommandKind::Part {
+                channels,
+                part_message,
+            } => match part_message {
+                Some(part_message) => write!(f, "PART {} :{part_message}", channels.join(",")),
+                None => write!(f, "PART {}", channels.join(",")),
+            },
+            CommandKind::Nick { nickname } => write!(f, "NICK {nickname}"),
+            CommandKind::User {
+                user_name,
+                mode,
+                real_name,
+            } => write!(f, "USER {user_name} {mode} * :{real_name}"),
+            CommandKind::Ping {
+                source_server,
+                target_server,
+            } => match target_server {
+                Some(target_server) => {
+                    write!(f, "PING {source_server} {}", last_param(target_server))
+                }
+                None => write!(f, "PING {}", last_param(source_server)),
+            },
+            CommandKind::Pong {
+                source_server,
+                target_server,
+            } => match target_server {
+                Some(target_server) => {
+                    write!(f, "PONG {source_server} {}", last_param(target_server))
+                }
+                None => write!(f, "PONG {}", last_param(source_server)),
+            },
+            CommandKind::PrivMsg {
+                message_targets,
+                message_text,
+            } => write!(f, "PRIVMSG {} :{message_text}", message_targets.join(",")),
+            CommandKind::Notice {
+                message_targets,
+                message_text,
+            } => write!(f, "NOTICE {} :{message_text}", message_targets.join(",")),
+            CommandKind::Quit { quit_message } => match quit_message {
+                Some(quit_message) => write!(f, "QUIT :{quit_message}"),
+                None => write!(f, "QUIT"),
+            },
+            CommandKind::Mode {
+                target,
+                modes,
+                params,
+            } => {
+                write!(f, "MODE {target}")?;
+                if let Some(modes) = modes {
+                    write!(f, " {modes}")?;
+                }
+                match params.split_last() {
+                    Some((last, params)) => {
+                        for param in params {
+                            write!(f, " {param}")?;
+                        }
+                        write!(f, " {}", last_param(last))
+                    }
+                    None => Ok(()),
+                }
+            }
+            CommandKind::Kick {
+                channels,
+                users,
+                comment,
+            } => {
+                write!(f, "KICK {} {}", channels.join(","), users.join(","))?;
+                match comment {
+                    Some(comment) => write!(f, " :{comment}"),
+                    None => Ok(()),
+                }
+            }
+            CommandKind::Topic { channel, topic } => match topic {
+                Some(topic) => write!(f, "TOPIC {channel} :{topic}"),
+                None => write!(f, "TOPIC {channel}"),
+            },
+            CommandKind::Who { mask, operators } => match (mask, operators) {
+                (Some(mask), true) => write!(f, "WHO {mask} o"),
+                (Some(mask), false) => write!(f, "WHO {}", last_param(mask)),
+                (None, _) => write!(f, "WHO"),
+            },
+            CommandKind::Names { channels } => match channels.is_empty() {
+                true => write!(f, "NAMES"),
+                false => write!(f, "NAMES {}", channels.join(",")),
+            },
+            CommandKind::Oper { name, password } => {
+                write!(f, "OPER {name} {}", last_param(password))
+            }
+            CommandKind::Flush => write!(f, "FLUSH"),
+            CommandKind::RegChan { channel } => write!(f, "REGCHAN {channel}"),
+        }
+    }
 }
@@ -0,0 +1,499 @@
+use anyhow::{Context, Result, bail};
+use serde::Deserialize;
+use std::fmt;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::state::{
+    DEFAULT_ADMIN_FLOOD, DEFAULT_CLIENT_FLOOD, DEFAULT_FLUSH_INTERVAL, DEFAULT_MAX_LIST_ENTRIES,
+    DEFAULT_PING_INTERVAL, DEFAULT_SENDQ, DEFAULT_SERVER_NAME, DEFAULT_TOPIC_LEN,
+};
+
+pub const DEFAULT_STATE_PATH: &str = "irc-state.json";
+pub const DEFAULT_LISTEN: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1667));
+
+pub const USAGE: &str = "\
+Usage: irc [OPTIONS] [STATE_FILE]
+
+Options:
+  -c, --config <FILE>   Read settings from a TOML config file
+  -s, --state <FILE>    Where to keep the server state (default: irc-state.json)
+      --check-config    Check the configuration and exit
+  -V, --version         Print the version and exit
+  -h, --help            Print this message and exit
+
+Environment:
+  IRC_CONFIG, IRC_STATE_FILE, IRC_SERVER_NAME, IRC_LISTEN, IRC_PING_INTERVAL,
+  IRC_FLUSH_INTERVAL, IRC_SENDQ, IRC_INVALID_UTF8 (reject, lossy or latin1),
+  IRC_FLOOD_EXEMPT (comma separated hosts), IRC_MAX_LIST_ENTRIES, IRC_EXTENDED_RANKS
+  (true or false) and IRC_TOPIC_LEN, which may also be set in a .env file
+Command line options take priority over the environment, which takes priority over the
+config file";
+
+/// What the command line asked for
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    Run(Config),
+    CheckConfig(Config),
+    PrintVersion,
+    PrintHelp,
+}
+
+/// What a listener's connections are for
+#[derive(Deserialize, Debug, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ListenerKind {
+    #[default]
+    Client,
+    /// For operators and local tools
+    Admin,
+}
+
+/// What to do with a line from a client that isn't valid UTF-8
+#[derive(Deserialize, Debug, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum InvalidUtf8 {
+    /// Drop the line
+    Reject,
+    /// Replace the invalid bytes with U+FFFD
+    Lossy,
+    /// Read the whole line as latin-1, which is what older clients usually send
+    #[default]
+    Latin1,
+}
+
+impl FromStr for InvalidUtf8 {
+    type Err = anyhow::Error;
+
+    fn from_str(policy: &str) -> Result<Self> {
+        match policy {
+            "reject" => Ok(InvalidUtf8::Reject),
+            "lossy" => Ok(InvalidUtf8::Lossy),
+            "latin1" => Ok(InvalidUtf8::Latin1),
+            _ => bail!("Invalid invalid_utf8 \"{policy}\", expected reject, lossy or latin1"),
+        }
+    }
+}
+
+/// Where a listener accepts connections, written as "127.0.0.1:6667", "[::]:6667" or
+/// "unix:/run/irc.sock"
+#[derive(Debug, PartialEq, Clone)]
+pub enum ListenAddress {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddress {
+    type Err = anyhow::Error;
+
+    fn from_str(address: &str) -> Result<Self> {
+        match address.strip_prefix("unix:") {
+            Some("") => bail!("Invalid listen address \"{address}\", the socket path is missing"),
+            Some(path) => Ok(ListenAddress::Unix(PathBuf::from(path))),
+            None => address
+                .parse()
+                .map(ListenAddress::Tcp)
+                .with_context(|| format!("Invalid listen address \"{address}\"")),
+        }
+    }
+}
+
+impl fmt::Display for ListenAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ListenAddress::Tcp(address) => write!(f, "{address}"),
+            ListenAddress::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// The PEM files a TLS listener presents to clients
+#[derive(Debug, PartialEq, Clone)]
+pub struct TlsPaths {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ListenerConfig {
+    pub address: ListenAddress,
+    pub kind: ListenerKind,
+    /// Set for TLS listeners, which are conventionally on port 6697
+    pub tls: Option<TlsPaths>,
+}
+
+/// How fast a class of connections may send commands
+/// Each command costs some units, a client can spend `burst` units at once and then one
+/// every `interval`, commands beyond that are held back rather than refused
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct FloodLimits {
+    pub burst: u32,
+    pub interval: Duration,
+    /// How many units a client may fall behind by before it is disconnected for flooding
+    pub excess: u32,
+}
+
+/// The flood limits for each kind of listener
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct FloodClasses {
+    pub client: FloodLimits,
+    pub admin: FloodLimits,
+}
+
+impl FloodClasses {
+    pub fn get(&self, kind: ListenerKind) -> FloodLimits {
+        match kind {
+            ListenerKind::Client => self.client,
+            ListenerKind::Admin => self.admin,
+        }
+    }
+}
+
+/// Someone who may become an IRC operator with OPER
+#[derive(Deserialize, PartialEq, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct OperConfig {
+    pub name: String,
+    pub password: String,
+}
+
+// Configs are printed by --check-config, which mustn't give away the passwords
+impl fmt::Debug for OperConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("OperConfig")
+            .field("name", &self.name)
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
+
+/// Everything `run` needs to know to start a server
+#[derive(Debug, PartialEq, Clone)]
+pub struct Config {
+    pub state_path: PathBuf,
+    pub server_name: String,
+    /// Every listener accepts into the same server
+    pub listeners: Vec<ListenerConfig>,
+    /// How long a connection may be idle before it is sent a PING
+    pub ping_interval: Duration,
+    /// How often the state file is rewritten, if anything has changed
+    pub flush_interval: Duration,
+    /// How many bytes may be queued for a client before it is disconnected for not reading
+    pub sendq: usize,
+    pub invalid_utf8: InvalidUtf8,
+    pub flood: FloodClasses,
+    /// Hosts that are never held back or disconnected for flooding
+    pub flood_exempt: Vec<String>,
+    /// The most entries each of a channel's +b, +e and +I lists may hold
+    pub max_list_entries: usize,
+    /// Offers the owner (~), admin (&) and halfop (%) channel ranks as well as op and voice
+    pub extended_ranks: bool,
+    /// The longest topic a channel may have, in bytes
+    pub topic_len: usize,
+    pub opers: Vec<OperConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            state_path: PathBuf::from(DEFAULT_STATE_PATH),
+            server_name: DEFAULT_SERVER_NAME.to_owned(),
+            listeners: vec![ListenerConfig {
+                address: ListenAddress::Tcp(DEFAULT_LISTEN),
+                kind: ListenerKind::Client,
+                tls: None,
+            }],
+            ping_interval: DEFAULT_PING_INTERVAL,
+            flush_interval: DEFAULT_FLUSH_INTERVAL,
+            sendq: DEFAULT_SENDQ,
+            invalid_utf8: InvalidUtf8::default(),
+            flood: FloodClasses {
+                client: DEFAULT_CLIENT_FLOOD,
+                admin: DEFAULT_ADMIN_FLOOD,
+            },
+            flood_exempt: Vec::new(),
+            max_list_entries: DEFAULT_MAX_LIST_ENTRIES,
+            extended_ranks: false,
+            topic_len: DEFAULT_TOPIC_LEN,
+            opers: Vec::new(),
+        }
+    }
+}
+
+/// The settings that can be given in the config file, or as environment variables
+/// Durations are written the human way, eg "2m" or "90s"
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct Settings {
+    state_file: Option<PathBuf>,
+    server_name: Option<String>,
+    /// Written as [[listeners]] tables in the config file
+    listeners: Option<Vec<ListenerSettings>>,
+    ping_interval: Option<String>,
+    flush_interval: Option<String>,
+    /// In bytes
+    sendq: Option<usize>,
+    invalid_utf8: Option<InvalidUtf8>,
+    /// Written as [flood.client] and [flood.admin] tables in the config file
+    flood: Option<FloodClassSettings>,
+    flood_exempt: Option<Vec<String>>,
+    max_list_entries: Option<usize>,
+    extended_ranks: Option<bool>,
+    topic_len: Option<usize>,
+    /// Written as [[opers]] tables in the config file, passwords are never read from the
+    /// environment
+    opers: Option<Vec<OperConfig>>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct FloodClassSettings {
+    client: Option<FloodSettings>,
+    admin: Option<FloodSettings>,
+}
+
+/// Anything left out keeps its default
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct FloodSettings {
+    burst: Option<u32>,
+    interval: Option<String>,
+    excess: Option<u32>,
+}
+
+impl FloodSettings {
+    fn apply(self, class: &str, limits: &mut FloodLimits) -> Result<()> {
+        if let Some(burst) = self.burst {
+            if burst == 0 {
+                bail!("flood.{class}.burst must be more than zero");
+            }
+            limits.burst = burst;
+        }
+        if let Some(interval) = self.interval {
+            limits.interval = parse_duration(&format!("flood.{class}.interval"), &interval)?;
+        }
+        if let Some(excess) = self.excess {
+            limits.excess = excess;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct ListenerSettings {
+    address: String,
+    #[serde(default)]
+    kind: ListenerKind,
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+}
+
+impl Settings {
+    fn from_file(path: &Path) -> Result<Self> {
+        let data = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        toml::from_str(&data).with_context(|| format!("Malformed config file {}", path.display()))
+    }
+
+    fn from_env(env: &impl Fn(&str) -> Option<String>) -> Result<Self> {
+        Ok(Settings {
+            state_file: env("IRC_STATE_FILE").map(PathBuf::from),
+            server_name: env("IRC_SERVER_NAME"),
+            // a comma separated list of client listeners
+            listeners: env("IRC_LISTEN").map(|addresses| {
+                addresses
+                    .split(',')
+                    .map(|address| ListenerSettings {
+                        address: address.trim().to_owned(),
+                        kind: ListenerKind::Client,
+                        tls_cert: None,
+                        tls_key: None,
+                    })
+                    .collect()
+            }),
+            ping_interval: env("IRC_PING_INTERVAL"),
+            flush_interval: env("IRC_FLUSH_INTERVAL"),
+            sendq: env("IRC_SENDQ")
+                .map(|sendq| {
+                    sendq
+                        .parse()
+                        .with_context(|| format!("Invalid IRC_SENDQ \"{sendq}\""))
+                })
+                .transpose()?,
+            invalid_utf8: env("IRC_INVALID_UTF8")
+                .map(|policy| policy.parse())
+                .transpose()?,
+            flood: None,
+            // a comma separated list of hosts
+            flood_exempt: env("IRC_FLOOD_EXEMPT").map(|hosts| {
+                hosts
+                    .split(',')
+                    .map(|host| host.trim().to_owned())
+                    .filter(|host| !host.is_empty())
+                    .collect()
+            }),
+            max_list_entries: env("IRC_MAX_LIST_ENTRIES")
+                .map(|max| {
+                    max.parse()
+                        .with_context(|| format!("Invalid IRC_MAX_LIST_ENTRIES \"{max}\""))
+                })
+                .transpose()?,
+            extended_ranks: env("IRC_EXTENDED_RANKS")
+                .map(|extended| {
+                    extended.parse().with_context(|| {
+                        format!("Invalid IRC_EXTENDED_RANKS \"{extended}\", expected true or false")
+                    })
+                })
+                .transpose()?,
+            topic_len: env("IRC_TOPIC_LEN")
+                .map(|len| {
+                    len.parse()
+                        .with_context(|| format!("Invalid IRC_TOPIC_LEN \"{len}\""))
+                })
+                .transpose()?,
+            opers: None,
+        })
+    }
+
+    /// Applies these settings over `config`, anything left unset is kept as it was
+    fn apply(self, config: &mut Config) -> Result<()> {
+        if let Some(state_path) = self.state_file {
+            config.state_path = state_path;
+        }
+        if let Some(server_name) = self.server_name {
+            config.server_name = server_name;
+        }
+        if let Some(listeners) = self.listeners {
+            if listeners.is_empty() {
+                bail!("At least one listener is needed");
+            }
+            config.listeners = listeners
+                .into_iter()
+                .map(|listener| {
+                    let tls = match (listener.tls_cert, listener.tls_key) {
+                        (Some(cert), Some(key)) => Some(TlsPaths { cert, key }),
+                        (None, None) => None,
+                        _ => bail!(
+                            "Listener {} needs both tls_cert and tls_key to use TLS",
+                            listener.address
+                        ),
+                    };
+                    let address = listener.address.parse()?;
+                    if tls.is_some() && matches!(address, ListenAddress::Unix(_)) {
+                        bail!("Listener {address} is a Unix socket, which can't use TLS");
+                    }
+                    Ok(ListenerConfig {
+                        address,
+                        kind: listener.kind,
+                        tls,
+                    })
+                })
+                .collect::<Result<_>>()?;
+        }
+        if let Some(interval) = self.ping_interval {
+            config.ping_interval = parse_duration("ping_interval", &interval)?;
+        }
+        if let Some(interval) = self.flush_interval {
+            config.flush_interval = parse_duration("flush_interval", &interval)?;
+        }
+        if let Some(sendq) = self.sendq {
+            if sendq == 0 {
+                bail!("sendq must be more than zero");
+            }
+            config.sendq = sendq;
+        }
+        if let Some(policy) = self.invalid_utf8 {
+            config.invalid_utf8 = policy;
+        }
+        if let Some(flood) = self.flood {
+            if let Some(client) = flood.client {
+                client.apply("client", &mut config.flood.client)?;
+            }
+            if let Some(admin) = flood.admin {
+                admin.apply("admin", &mut config.flood.admin)?;
+            }
+        }
+        if let Some(hosts) = self.flood_exempt {
+            config.flood_exempt = hosts;
+        }
+        if let Some(max) = self.max_list_entries {
+            config.max_list_entries = max;
+        }
+        if let Some(extended) = self.extended_ranks {
+            config.extended_ranks = extended;
+        }
+        if let Some(len) = self.topic_len {
+            if len == 0 {
+                bail!("topic_len must be more than zero");
+            }
+            config.topic_len = len;
+        }
+        if let Some(opers) = self.opers {
+            if let Some(oper) = opers
+                .iter()
+                .find(|oper| oper.name.is_empty() || oper.password.is_empty())
+            {
+                bail!("Oper \"{}\" needs both a name and a password", oper.name);
+            }
+            config.opers = opers;
+        }
+        Ok(())
+    }
+}
+
+fn parse_duration(name: &str, value: &str) -> Result<Duration> {
+    let duration =
+        humantime::parse_duration(value).with_context(|| format!("Invalid {name} \"{value}\""))?;
+    if duration.is_zero() {
+        bail!("{name} must be longer than zero");
+    }
+    Ok(duration)
+}
+
+impl Config {
+    /// Builds the config from the command line arguments (without the program name) and the
+    /// environment, reading the config file if one is given in either
+    pub fn from_args(args: Vec<String>, env: impl Fn(&str) -> Option<String>) -> Result<Action> {
+        let mut config_path = env("IRC_CONFIG").map(PathBuf::from);
+        let mut state_path = None;
+        let mut check_config = false;
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(Action::PrintHelp),
+                "-V" | "--version" => return Ok(Action::PrintVersion),
+                "--check-config" => check_config = true,
+                "-c" | "--config" => config_path = Some(option_value(&arg, args.next())?),
+                "-s" | "--state" => state_path = Some(option_value(&arg, args.next())?),
+                _ if arg.starts_with('-') => bail!("Unknown option {arg}\n\n{USAGE}"),
+                _ if state_path.is_none() => state_path = Some(PathBuf::from(arg)),
+                _ => bail!("Unexpected argument {arg}\n\n{USAGE}"),
+            }
+        }
+
+        let mut config = Config::default();
+        if let Some(path) = &config_path {
+            Settings::from_file(path)?.apply(&mut config)?;
+        }
+        Settings::from_env(&env)?.apply(&mut config)?;
+        if let Some(state_path) = state_path {
+            config.state_path = state_path;
+        }
+
+        Ok(match check_config {
+            true => Action::CheckConfig(config),
+            false => Action::Run(config),
+        })
+    }
+}
+
+fn option_value(option: &str, value: Option<String>) -> Result<PathBuf> {
+    match value {
+        Some(value) => Ok(PathBuf::from(value)),
+        None => bail!("{option} needs a value\n\n{USAGE}"),
+    }
+}
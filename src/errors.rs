@@ -3,53 +3,57 @@ use thiserror::Error;
 #[allow(dead_code)]
 #[derive(Error, Debug)]
 pub enum IrcError {
-    #[error("{nickname:?} :No such nick/channel")]
+    #[error("{nickname} :No such nick/channel")]
     NoSuchNick { nickname: String },
-    #[error("{server:?} :No such server")]
+    #[error("{server} :No such server")]
     NoSuchServer { server: String },
-    #[error("{channel:?} :No such channel")]
+    #[error("{channel} :No such channel")]
     NoSuchChannel { channel: String },
-    #[error("{channel:?} :Cannot send to channel")]
+    #[error("{channel} :Cannot send to channel")]
     CannotSendToChan { channel: String },
-    #[error("{channel:?} :You have joined too many channels")]
+    #[error("{channel} :You have joined too many channels")]
     TooManyChannels { channel: String },
-    #[error("{nickname:?} :There was no such nickname")]
+    #[error("{nickname} :There was no such nickname")]
     WasNoSuchNick { nickname: String },
-    #[error("{target:?} :Duplicate recipients. No message delivered")]
+    #[error("{target} :Duplicate recipients. No message delivered")]
     TooManyTargets { target: String },
     #[error(":No origin specified")]
     NoOrigin,
-    #[error(":No recipient given (?:command)")]
+    #[error(":No recipient given ({command})")]
     NoRecipient { command: String },
-    #[error("No text to send")]
+    #[error(":No text to send")]
     NoTextToSend,
-    #[error("{mask:?} :No toplevel domain specified")]
+    #[error("{mask} :No toplevel domain specified")]
     NoTopLevel { mask: String },
-    #[error("{mask:?} :Wildcard in toplevel domain")]
+    #[error("{mask} :Wildcard in toplevel domain")]
     WildTopLevel { mask: String },
-    #[error("{command:?} :Unknown command")]
+    #[error(":Input line was too long")]
+    InputTooLong,
+    #[error("{command} :Unknown command")]
     UnknownCommand { command: String },
     #[error(":MOTD File is missing")]
     NoMotd,
-    #[error("{server:?} :No administrative info available")]
+    #[error("{server} :No administrative info available")]
     NoAdminInfo { server: String },
-    #[error(":File error doing (?:file_op) on (?:file)")]
+    #[error(":File error doing {file_op} on {file}")]
     FileError { file_op: String, file: String },
     #[error(":No nickname given")]
     NoNickNameGiven,
-    #[error("{nickname:?} :Erroneus nickname")]
+    #[error("{nickname} :Erroneus nickname")]
     ErroneusNickname { nickname: String },
-    #[error("{nickname:?} :Nickname is already in use")]
+    #[error("{nickname} :Nickname is already in use")]
     NicknameInUse { nickname: String },
-    #[error("{nickname:?} :Nickname collision KILL")]
+    #[error("{channel} :Cannot change nickname while banned on channel")]
+    BanNickChange { channel: String },
+    #[error("{nickname} :Nickname collision KILL")]
     NickCollision { nickname: String },
-    #[error("{nickname:?} {channel} :They aren't on that channel")]
+    #[error("{nickname} {channel} :They aren't on that channel")]
     UserNotInChannel { nickname: String, channel: String },
-    #[error("{channel:?} :You're not on that channel")]
+    #[error("{channel} :You're not on that channel")]
     NotOnChannel { channel: String },
-    #[error("{user:?} {channel:?} :is already on channel")]
+    #[error("{user} {channel} :is already on channel")]
     UserOnChannel { user: String, channel: String },
-    #[error("{user:?} :User not logged in")]
+    #[error("{user} :User not logged in")]
     NoLogin { user: String },
     #[error(":SUMMON has been disabled")]
     SummonDisabled,
@@ -57,7 +61,7 @@ pub enum IrcError {
     UsersDisabled,
     #[error(":You have not registered")]
     NotRegistered,
-    #[error("{command:?} :Not enough parameters")]
+    #[error("{command} :Not enough parameters")]
     NeedMoreParams { command: String },
     #[error(":You may not reregister")]
     AlreadyRegistered,
@@ -67,21 +71,23 @@ pub enum IrcError {
     PasswdMismatch,
     #[error(":You are banned from this server")]
     YoureBannedCreep,
-    #[error("{channel:?} :Channel key already set")]
+    #[error("{channel} :Channel key already set")]
     KeySet { channel: String },
-    #[error("{channel:?} :Channot join channel (+l)")]
+    #[error("{channel} :Cannot join channel (+l)")]
     ChannelIsFull { channel: String },
-    #[error("{char:?} :is unknown mode char to me")]
+    #[error("{char} :is unknown mode char to me")]
     UnknownMode { char: char },
-    #[error("{channel:?} :Cannot join channel (+i)")]
+    #[error("{channel} :Cannot join channel (+i)")]
     InviteOnlyChan { channel: String },
-    #[error("{channel:?} :Cannot join channel (+b)")]
+    #[error("{channel} :Cannot join channel (+b)")]
     BannedFromChan { channel: String },
-    #[error("{channel:?} :Channot join channel (+k)")]
+    #[error("{channel} {char} :Channel list is full")]
+    BanListFull { channel: String, char: char },
+    #[error("{channel} :Cannot join channel (+k)")]
     BadChannelKey { channel: String },
     #[error(":Permission Denied - You're not an IRC operator")]
     NoPrivileges,
-    #[error("{channel:?} :You're not channel operator")]
+    #[error("{channel} :You're not channel operator")]
     ChanOPrivsNeeded { channel: String },
     #[error(":You cant kill a server!")]
     CantKillServer,
@@ -109,6 +115,7 @@ impl IrcError {
             IrcError::NoTextToSend => 412,
             IrcError::NoTopLevel { .. } => 413,
             IrcError::WildTopLevel { .. } => 414,
+            IrcError::InputTooLong => 417,
             IrcError::UnknownCommand { .. } => 421,
             IrcError::NoMotd => 422,
             IrcError::NoAdminInfo { .. } => 423,
@@ -116,6 +123,7 @@ impl IrcError {
             IrcError::NoNickNameGiven => 431,
             IrcError::ErroneusNickname { .. } => 432,
             IrcError::NicknameInUse { .. } => 433,
+            IrcError::BanNickChange { .. } => 435,
             IrcError::NickCollision { .. } => 436,
             IrcError::UserNotInChannel { .. } => 441,
             IrcError::NotOnChannel { .. } => 442,
@@ -134,6 +142,7 @@ impl IrcError {
             IrcError::UnknownMode { .. } => 472,
             IrcError::InviteOnlyChan { .. } => 473,
             IrcError::BannedFromChan { .. } => 474,
+            IrcError::BanListFull { .. } => 478,
             IrcError::BadChannelKey { .. } => 475,
             IrcError::NoPrivileges => 481,
             IrcError::ChanOPrivsNeeded { .. } => 482,
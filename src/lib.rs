@@ -1,64 +1,110 @@
 use crate::commands::*;
-use crate::state::State;
-use anyhow::{Context, Result};
-use std::net::TcpListener;
-use std::path::PathBuf;
-use std::sync::{
-    Arc, RwLock,
-    atomic::{AtomicBool, Ordering},
-};
-use std::thread::{self, JoinHandle};
+use crate::listener::Listener;
+use crate::state::{Persistence, State};
+use anyhow::{Result, anyhow};
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::Duration;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+
+pub use config::{Action, Config, USAGE};
+pub use server::VERSION;
 
 mod commands;
+mod config;
+mod listener;
 mod server;
 mod state;
+mod tls;
 mod errors;
+mod replies;
 
-pub fn run(path: String) -> Result<()> {
-    let state = Arc::new(RwLock::new(State::build(PathBuf::from(&path))?));
-    let exit_flag = Arc::new(AtomicBool::new(false));
+/// How long clients get to finish up once the server starts shutting down
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
 
-    start_exit_check_loop(&state, &exit_flag);
+pub fn run(config: Config) -> Result<()> {
+    let state = Arc::new(RwLock::new(State::build(&config)?));
+    // Bind everything before accepting anything, so a bad listener stops the server starting
+    let listeners = config
+        .listeners
+        .iter()
+        .map(Listener::bind)
+        .collect::<Result<Vec<_>>>()?;
 
-    let listener =
-        TcpListener::bind("127.0.0.1:1667").context("Failed to bind the TcpListenter")?;
+    let persistence = Persistence::start(&state, config.flush_interval)?;
+    let served = tokio::runtime::Runtime::new()?.block_on(serve(&state, listeners));
+    // The final save happens however serving ended
+    persistence.shutdown()?;
+    served
+}
 
-    let mut handles: Vec<JoinHandle<Result<()>>> = Vec::new();
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                let state = Arc::clone(&state);
-                let handle = thread::spawn(move || (server::handle_client(state, stream)));
-                handles.push(handle);
-            }
-            // Connection failed
-            Err(e) => println!("Error: {e}"),
-        };
-    }
+/// Accepts and serves clients until SIGINT or SIGTERM, then sends them all away
+/// SIGHUP reloads the state file in the meantime
+async fn serve(state: &Arc<RwLock<State>>, listeners: Vec<Listener>) -> Result<()> {
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut hangup = signal(SignalKind::hangup())?;
 
-    match state.write() {
-        Ok(mut state) => state.save(),
-        Err(_e) => todo!(),
+    let (stop, stopped) = watch::channel(false);
+    let handles = listeners
+        .into_iter()
+        .map(|listener| listener.spawn(state, stopped.clone()))
+        .collect::<Result<Vec<_>>>()?;
+
+    loop {
+        tokio::select! {
+            _ = interrupt.recv() => break println!("Received SIGINT, shutting down"),
+            _ = terminate.recv() => break println!("Received SIGTERM, shutting down"),
+            _ = hangup.recv() => reload_state(state),
+        }
+    }
+    stop.send_replace(true);
+    let mut clients = Vec::new();
+    for handle in handles {
+        clients.push(
+            handle
+                .await
+                .map_err(|_| anyhow!("A listener task panicked"))?,
+        );
     }
-}
 
-pub fn parse_env_args(_args: Vec<String>) -> Result<String> {
-    todo!()
+    // The server is going away regardless, so even a poisoned state is good enough to say
+    // goodbye to everyone with
+    server::shutdown(&mut state.write().unwrap_or_else(PoisonError::into_inner));
+    join_clients(clients, SHUTDOWN_TIMEOUT).await;
+    Ok(())
 }
 
-fn start_exit_check_loop(state: &Arc<RwLock<State>>, exit_flag: &Arc<AtomicBool>) {
-    let state = Arc::clone(state);
-    let exit_flag = Arc::clone(exit_flag);
+/// Reloads the state file, as an admin asks for with SIGHUP after editing it by hand
+/// A file that can't be loaded is reported and the running state is kept as it was
+fn reload_state(state: &RwLock<State>) {
+    println!("Received SIGHUP, reloading the state file");
+    let reloaded = state
+        .write()
+        .map_err(|_| anyhow!("The state lock is poisoned"))
+        .and_then(|mut state| state.reload_from_file());
+    if let Err(e) = reloaded {
+        println!("Error: {e:#}");
+    }
+}
 
-    thread::spawn(move || {
-        loop {
-            if exit_flag.load(Ordering::Relaxed) {
-                break;
+/// Waits for client tasks to finish, any still running after `timeout` are aborted
+async fn join_clients(clients: Vec<JoinSet<Result<()>>>, timeout: Duration) {
+    let joined = tokio::time::timeout(timeout, async {
+        for mut listener_clients in clients {
+            while let Some(client) = listener_clients.join_next().await {
+                listener::log_client_result(client);
             }
         }
-        match state.write() {
-            Ok(mut state) => state.save(),
-            Err(_e) => todo!(),
-        }
     });
+    if joined.await.is_err() {
+        println!("Error: some clients did not finish in time");
+    }
+}
+
+/// Works out what to do from the command line arguments (without the program name), the
+/// environment and the config file
+pub fn parse_env_args(args: Vec<String>) -> Result<Action> {
+    Config::from_args(args, |name| std::env::var(name).ok())
 }
@@ -0,0 +1,224 @@
+use anyhow::{Context, Result};
+use std::fs;
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream, UnixListener};
+use tokio::sync::watch;
+use tokio::task::{JoinError, JoinHandle, JoinSet};
+use tokio_rustls::TlsAcceptor;
+
+use crate::config::{ListenAddress, ListenerConfig, ListenerKind};
+use crate::server::{self, stream::Stream};
+use crate::state::State;
+use crate::tls::CertificateStore;
+
+/// How long a client gets to finish the TLS handshake
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long to wait before accepting again after a failure, so running out of file
+/// descriptors doesn't turn the accept loop into a busy loop
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Bound with std, so binding doesn't need the runtime, and handed over to tokio once spawned
+enum Socket {
+    Tcp(std::net::TcpListener),
+    Tls(std::net::TcpListener, Arc<CertificateStore>, TlsAcceptor),
+    Unix(std::os::unix::net::UnixListener, SocketFile),
+}
+
+/// Removes a Unix socket's file once the listener is done with it
+struct SocketFile(PathBuf);
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// A bound listener, accepting connections into the server
+pub struct Listener {
+    socket: Socket,
+    kind: ListenerKind,
+}
+
+impl Listener {
+    pub fn bind(config: &ListenerConfig) -> Result<Self> {
+        let socket = match &config.address {
+            ListenAddress::Tcp(address) => {
+                let listener = std::net::TcpListener::bind(address)
+                    .with_context(|| format!("Failed to bind a TcpListener to {address}"))?;
+                match &config.tls {
+                    Some(paths) => {
+                        let certificates = CertificateStore::load(paths)?;
+                        let acceptor = TlsAcceptor::from(certificates.server_config()?);
+                        Socket::Tls(listener, certificates, acceptor)
+                    }
+                    None => Socket::Tcp(listener),
+                }
+            }
+            ListenAddress::Unix(path) => {
+                // A socket file left behind by a previous run would stop the bind, anything
+                // that isn't a socket is left alone
+                if fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+                    fs::remove_file(path)?;
+                }
+                let listener = std::os::unix::net::UnixListener::bind(path).with_context(|| {
+                    format!("Failed to bind a UnixListener to {}", path.display())
+                })?;
+                Socket::Unix(listener, SocketFile(path.clone()))
+            }
+        };
+        // tokio needs the sockets to be non blocking
+        match &socket {
+            Socket::Tcp(listener) | Socket::Tls(listener, ..) => listener.set_nonblocking(true)?,
+            Socket::Unix(listener, _) => listener.set_nonblocking(true)?,
+        }
+        Ok(Listener {
+            socket,
+            kind: config.kind,
+        })
+    }
+
+    /// Where the listener is accepting connections, with any port 0 resolved
+    pub fn address(&self) -> io::Result<ListenAddress> {
+        match &self.socket {
+            Socket::Tcp(listener) | Socket::Tls(listener, ..) => {
+                listener.local_addr().map(ListenAddress::Tcp)
+            }
+            Socket::Unix(_, file) => Ok(ListenAddress::Unix(file.0.clone())),
+        }
+    }
+
+    /// Accepts connections on a task of its own until `stop` is set, each client is handled on
+    /// a task of its own too
+    /// The task returns the clients that are still connected
+    /// Must be called from within the tokio runtime
+    pub fn spawn(
+        self,
+        state: &Arc<RwLock<State>>,
+        mut stop: watch::Receiver<bool>,
+    ) -> Result<JoinHandle<JoinSet<Result<()>>>> {
+        println!(
+            "Listening on {} ({:?}{})",
+            self.address()?,
+            self.kind,
+            match self.socket {
+                Socket::Tls(..) => ", TLS",
+                _ => "",
+            }
+        );
+        let state = Arc::clone(state);
+        let kind = self.kind;
+        let accepting = Accepting::new(self.socket)?;
+
+        Ok(tokio::spawn(async move {
+            let mut clients = JoinSet::new();
+            loop {
+                let accepted = tokio::select! {
+                    accepted = accepting.accept() => accepted,
+                    _ = stop.wait_for(|stop| *stop) => break,
+                };
+                // Collecting finished clients here keeps the set to the live ones
+                while let Some(finished) = clients.try_join_next() {
+                    log_client_result(finished);
+                }
+                match accepted {
+                    Ok(accepted) => {
+                        let state = Arc::clone(&state);
+                        clients.spawn(async move {
+                            let stream = accepted.into_stream().await?;
+                            server::handle_client(state, stream, kind).await
+                        });
+                    }
+                    // Connection failed
+                    Err(e) => {
+                        println!("Error: {e}");
+                        tokio::select! {
+                            _ = tokio::time::sleep(ACCEPT_ERROR_BACKOFF) => (),
+                            _ = stop.wait_for(|stop| *stop) => break,
+                        }
+                    }
+                }
+            }
+            clients
+        }))
+    }
+}
+
+/// A listener's socket, once it has been handed over to tokio
+enum Accepting {
+    Tcp(TcpListener),
+    Tls(TcpListener, Arc<CertificateStore>, TlsAcceptor),
+    Unix {
+        listener: UnixListener,
+        /// Held until the listener stops
+        _file: SocketFile,
+    },
+}
+
+impl Accepting {
+    fn new(socket: Socket) -> io::Result<Self> {
+        Ok(match socket {
+            Socket::Tcp(listener) => Accepting::Tcp(TcpListener::from_std(listener)?),
+            Socket::Tls(listener, certificates, acceptor) => {
+                Accepting::Tls(TcpListener::from_std(listener)?, certificates, acceptor)
+            }
+            Socket::Unix(listener, file) => Accepting::Unix {
+                listener: UnixListener::from_std(listener)?,
+                _file: file,
+            },
+        })
+    }
+
+    async fn accept(&self) -> io::Result<Accepted> {
+        Ok(match self {
+            Accepting::Tcp(listener) => Accepted::Ready(Stream::tcp(listener.accept().await?.0)?),
+            Accepting::Tls(listener, certificates, acceptor) => {
+                let (stream, _) = listener.accept().await?;
+                // Picks up a renewed certificate, connections already made carry on with the
+                // old one
+                if let Err(e) = certificates.reload_if_changed() {
+                    println!("Error: {e:#}");
+                }
+                Accepted::Tls(stream, acceptor.clone())
+            }
+            Accepting::Unix { listener, .. } => {
+                Accepted::Ready(Stream::unix(listener.accept().await?.0))
+            }
+        })
+    }
+}
+
+/// A connection that has been accepted, but may still need its TLS handshake
+/// The handshake happens on the client's own task, so a slow one never holds up the listener
+enum Accepted {
+    Ready(Stream),
+    Tls(TcpStream, TlsAcceptor),
+}
+
+impl Accepted {
+    async fn into_stream(self) -> Result<Stream> {
+        match self {
+            Accepted::Ready(stream) => Ok(stream),
+            Accepted::Tls(stream, acceptor) => {
+                let stream = tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream))
+                    .await
+                    .context("TLS handshake timed out")?
+                    .context("TLS handshake failed")?;
+                Ok(Stream::tls(stream)?)
+            }
+        }
+    }
+}
+
+/// Reports how a client's task ended, if it ended badly
+pub fn log_client_result(result: Result<Result<()>, JoinError>) {
+    match result {
+        Ok(Ok(())) => (),
+        Ok(Err(e)) => println!("Error: {e:#}"),
+        Err(e) => println!("Error: a client task failed: {e}"),
+    }
+}
@@ -1,17 +1,30 @@
 use dotenvy::dotenv;
+use irc::Action;
 use std::process::exit;
 
 fn main() {
     dotenv().ok();
-    let file_path = match irc::parse_env_args(Vec::new()) {
-        Ok(file_path) => file_path,
+    let config = match irc::parse_env_args(std::env::args().skip(1).collect()) {
+        Ok(Action::Run(config)) => config,
+        Ok(Action::CheckConfig(config)) => {
+            println!("Configuration OK: {config:#?}");
+            return;
+        }
+        Ok(Action::PrintVersion) => {
+            println!("{}", irc::VERSION);
+            return;
+        }
+        Ok(Action::PrintHelp) => {
+            println!("{}", irc::USAGE);
+            return;
+        }
         Err(e) => {
-            println!("Error: {e}");
+            println!("Error: {e:#}");
             exit(-1);
         }
     };
 
-    if let Err(e) = irc::run(file_path) {
+    if let Err(e) = irc::run(config) {
         println!("Error: {e}");
         exit(-1);
     }
@@ -0,0 +1,327 @@
+use std::fmt;
+
+use crate::errors::IrcError;
+
+/// A numeric reply that can be sent to a client, either a success reply or an error
+pub trait Numeric: fmt::Display {
+    fn numeric_code(&self) -> i16;
+
+    /// Renders the reply as a complete line, eg:
+    /// `:server 433 * nick :Nickname is already in use\r\n`
+    /// `target` is the nickname of the client the reply is addressed to, "*" if it has none yet
+    fn to_wire(&self, server: &str, target: &str) -> String {
+        format!(":{server} {:03} {target} {self}\r\n", self.numeric_code())
+    }
+}
+
+impl Numeric for IrcError {
+    fn numeric_code(&self) -> i16 {
+        IrcError::numeric_code(self)
+    }
+}
+
+/// Command responses (RPL_*) from RFC 2812 section 5.1
+/// Each variant holds the parameters that follow the target nickname
+// Variants are named after their RPL_* counterparts, eg RPL_NAMREPLY
+#[allow(dead_code, clippy::enum_variant_names)]
+#[derive(Debug, PartialEq, Clone)]
+pub enum Reply {
+    Welcome {
+        nickname: String,
+        user: String,
+        host: String,
+    },
+    YourHost {
+        server: String,
+        version: String,
+    },
+    Created {
+        date: String,
+    },
+    MyInfo {
+        server: String,
+        version: String,
+        user_modes: String,
+        channel_modes: String,
+    },
+    /// RPL_ISUPPORT, the server's features as "NAME=value" tokens
+    ISupport {
+        tokens: Vec<String>,
+    },
+    UModeIs {
+        modes: String,
+    },
+    Away {
+        nickname: String,
+        message: String,
+    },
+    UnAway,
+    NowAway,
+    WhoisUser {
+        nickname: String,
+        user: String,
+        host: String,
+        real_name: String,
+    },
+    WhoisServer {
+        nickname: String,
+        server: String,
+        server_info: String,
+    },
+    WhoisOperator {
+        nickname: String,
+    },
+    EndOfWho {
+        name: String,
+    },
+    WhoisIdle {
+        nickname: String,
+        seconds: u64,
+    },
+    EndOfWhois {
+        nickname: String,
+    },
+    WhoisChannels {
+        nickname: String,
+        channels: Vec<String>,
+    },
+    ChannelModeIs {
+        channel: String,
+        modes: String,
+    },
+    NoTopic {
+        channel: String,
+    },
+    Topic {
+        channel: String,
+        topic: String,
+    },
+    /// RPL_TOPICWHOTIME, who set the topic and when (in seconds since the unix epoch)
+    TopicWhoTime {
+        channel: String,
+        set_by: String,
+        set_at: u64,
+    },
+    Inviting {
+        channel: String,
+        nickname: String,
+    },
+    InviteList {
+        channel: String,
+        mask: String,
+        set_by: String,
+        /// Seconds since the unix epoch
+        set_at: u64,
+    },
+    EndOfInviteList {
+        channel: String,
+    },
+    ExceptList {
+        channel: String,
+        mask: String,
+        set_by: String,
+        /// Seconds since the unix epoch
+        set_at: u64,
+    },
+    EndOfExceptList {
+        channel: String,
+    },
+    WhoReply {
+        channel: String,
+        user: String,
+        host: String,
+        server: String,
+        nickname: String,
+        flags: String,
+        hopcount: u32,
+        real_name: String,
+    },
+    NamReply {
+        symbol: char,
+        channel: String,
+        nicknames: Vec<String>,
+    },
+    EndOfNames {
+        channel: String,
+    },
+    BanList {
+        channel: String,
+        mask: String,
+        set_by: String,
+        /// Seconds since the unix epoch
+        set_at: u64,
+    },
+    EndOfBanList {
+        channel: String,
+    },
+    MotdStart {
+        server: String,
+    },
+    Motd {
+        text: String,
+    },
+    EndOfMotd,
+    YoureOper,
+    Time {
+        server: String,
+        time: String,
+    },
+}
+
+impl Reply {
+    pub fn numeric_code(&self) -> i16 {
+        match *self {
+            Reply::Welcome { .. } => 1,
+            Reply::YourHost { .. } => 2,
+            Reply::Created { .. } => 3,
+            Reply::MyInfo { .. } => 4,
+            Reply::ISupport { .. } => 5,
+            Reply::UModeIs { .. } => 221,
+            Reply::Away { .. } => 301,
+            Reply::UnAway => 305,
+            Reply::NowAway => 306,
+            Reply::WhoisUser { .. } => 311,
+            Reply::WhoisServer { .. } => 312,
+            Reply::WhoisOperator { .. } => 313,
+            Reply::EndOfWho { .. } => 315,
+            Reply::WhoisIdle { .. } => 317,
+            Reply::EndOfWhois { .. } => 318,
+            Reply::WhoisChannels { .. } => 319,
+            Reply::ChannelModeIs { .. } => 324,
+            Reply::NoTopic { .. } => 331,
+            Reply::Topic { .. } => 332,
+            Reply::TopicWhoTime { .. } => 333,
+            Reply::Inviting { .. } => 341,
+            Reply::InviteList { .. } => 346,
+            Reply::EndOfInviteList { .. } => 347,
+            Reply::ExceptList { .. } => 348,
+            Reply::EndOfExceptList { .. } => 349,
+            Reply::WhoReply { .. } => 352,
+            Reply::NamReply { .. } => 353,
+            Reply::EndOfNames { .. } => 366,
+            Reply::BanList { .. } => 367,
+            Reply::EndOfBanList { .. } => 368,
+            Reply::Motd { .. } => 372,
+            Reply::MotdStart { .. } => 375,
+            Reply::EndOfMotd => 376,
+            Reply::YoureOper => 381,
+            Reply::Time { .. } => 391,
+        }
+    }
+}
+
+impl Numeric for Reply {
+    fn numeric_code(&self) -> i16 {
+        Reply::numeric_code(self)
+    }
+}
+
+impl fmt::Display for Reply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reply::Welcome {
+                nickname,
+                user,
+                host,
+            } => write!(
+                f,
+                ":Welcome to the Internet Relay Network {nickname}!{user}@{host}"
+            ),
+            Reply::YourHost { server, version } => {
+                write!(f, ":Your host is {server}, running version {version}")
+            }
+            Reply::Created { date } => write!(f, ":This server was created {date}"),
+            Reply::MyInfo {
+                server,
+                version,
+                user_modes,
+                channel_modes,
+            } => write!(f, "{server} {version} {user_modes} {channel_modes}"),
+            Reply::ISupport { tokens } => {
+                write!(f, "{} :are supported by this server", tokens.join(" "))
+            }
+            Reply::UModeIs { modes } => write!(f, "{modes}"),
+            Reply::Away { nickname, message } => write!(f, "{nickname} :{message}"),
+            Reply::UnAway => write!(f, ":You are no longer marked as being away"),
+            Reply::NowAway => write!(f, ":You have been marked as being away"),
+            Reply::WhoisUser {
+                nickname,
+                user,
+                host,
+                real_name,
+            } => write!(f, "{nickname} {user} {host} * :{real_name}"),
+            Reply::WhoisServer {
+                nickname,
+                server,
+                server_info,
+            } => write!(f, "{nickname} {server} :{server_info}"),
+            Reply::WhoisOperator { nickname } => write!(f, "{nickname} :is an IRC operator"),
+            Reply::EndOfWho { name } => write!(f, "{name} :End of WHO list"),
+            Reply::WhoisIdle { nickname, seconds } => {
+                write!(f, "{nickname} {seconds} :seconds idle")
+            }
+            Reply::EndOfWhois { nickname } => write!(f, "{nickname} :End of WHOIS list"),
+            Reply::WhoisChannels { nickname, channels } => {
+                write!(f, "{nickname} :{}", channels.join(" "))
+            }
+            Reply::ChannelModeIs { channel, modes } => write!(f, "{channel} {modes}"),
+            Reply::NoTopic { channel } => write!(f, "{channel} :No topic is set"),
+            Reply::Topic { channel, topic } => write!(f, "{channel} :{topic}"),
+            Reply::TopicWhoTime {
+                channel,
+                set_by,
+                set_at,
+            } => write!(f, "{channel} {set_by} {set_at}"),
+            Reply::Inviting { channel, nickname } => write!(f, "{channel} {nickname}"),
+            Reply::InviteList {
+                channel,
+                mask,
+                set_by,
+                set_at,
+            } => write!(f, "{channel} {mask} {set_by} {set_at}"),
+            Reply::EndOfInviteList { channel } => {
+                write!(f, "{channel} :End of channel invite list")
+            }
+            Reply::ExceptList {
+                channel,
+                mask,
+                set_by,
+                set_at,
+            } => write!(f, "{channel} {mask} {set_by} {set_at}"),
+            Reply::EndOfExceptList { channel } => {
+                write!(f, "{channel} :End of channel exception list")
+            }
+            Reply::WhoReply {
+                channel,
+                user,
+                host,
+                server,
+                nickname,
+                flags,
+                hopcount,
+                real_name,
+            } => write!(
+                f,
+                "{channel} {user} {host} {server} {nickname} {flags} :{hopcount} {real_name}"
+            ),
+            Reply::NamReply {
+                symbol,
+                channel,
+                nicknames,
+            } => write!(f, "{symbol} {channel} :{}", nicknames.join(" ")),
+            Reply::EndOfNames { channel } => write!(f, "{channel} :End of NAMES list"),
+            Reply::BanList {
+                channel,
+                mask,
+                set_by,
+                set_at,
+            } => write!(f, "{channel} {mask} {set_by} {set_at}"),
+            Reply::EndOfBanList { channel } => write!(f, "{channel} :End of channel ban list"),
+            Reply::MotdStart { server } => write!(f, ":- {server} Message of the day - "),
+            Reply::Motd { text } => write!(f, ":- {text}"),
+            Reply::EndOfMotd => write!(f, ":End of MOTD command"),
+            Reply::YoureOper => write!(f, ":You are now an IRC operator"),
+            Reply::Time { server, time } => write!(f, "{server} :{time}"),
+        }
+    }
+}
@@ -1,106 +1,754 @@
-use anyhow::Result;
+use anyhow::{Result, anyhow, bail};
 use std::{
-    io::{BufRead, BufReader, BufWriter},
-    net::{Shutdown, TcpStream},
-    sync::{Arc, RwLock},
+    collections::{HashSet, VecDeque},
+    ops::ControlFlow,
+    sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, BufReader},
+    time::Instant,
 };
 
 #[cfg(test)]
 mod tests;
 
+mod channels;
+pub mod client;
+mod flood;
+mod line;
+mod modes;
+mod oper;
 mod parser;
+pub mod stream;
 pub use crate::server::parser::try_parse_from_line;
 
-use crate::{Command, CommandKind, state::State};
+use crate::{
+    Command, CommandKind,
+    config::ListenerKind,
+    errors::IrcError,
+    replies::{Numeric, Reply},
+    state::{Channel, ClientEntry, Rank, State, casemap},
+};
+use client::{Client, Outbound, Registration, SendQExceeded};
+use flood::FloodControl;
+use line::{Line, LineReader};
+use parser::ParseError;
+use stream::Stream;
+
+pub const VERSION: &str = concat!("irc-", env!("CARGO_PKG_VERSION"));
+/// The most targets a single PRIVMSG or NOTICE may be sent to
+pub const MAX_TARGETS: usize = 4;
+// Advertised in RPL_MYINFO, along with whichever channel ranks are on offer
+pub const USER_MODES: &str = "iorswZ";
+pub const CHANNEL_MODES: &str = "beIiklmnpst";
+/// How long whatever is still queued for a client that has quit gets to be written
+const GOODBYE_TIMEOUT: Duration = Duration::from_secs(5);
 
-pub fn handle_client(state: Arc<RwLock<State>>, stream: TcpStream) -> Result<()> {
-    let mut stream_writer = BufWriter::new(stream.try_clone()?);
-    let mut stream_reader = BufReader::new(stream);
+/// Serves one connection until it closes
+/// Replies are queued and written by a task of their own, so this only ever waits on the client
+pub async fn handle_client(
+    state: Arc<RwLock<State>>,
+    stream: Stream,
+    listener: ListenerKind,
+) -> Result<()> {
+    let sendq = read_state(&state)?.sendq();
+    let (reader, writer) = tokio::io::split(stream.transport);
+    let (outbound, queue) = Outbound::new(sendq);
+    let mut writer = tokio::spawn(client::write_queued(queue, writer));
+    let mut client = Client::new(outbound.clone(), stream.peer_host, stream.secure, listener);
+    write_state(&state)?.add_connection(outbound.clone());
 
-    let mut buf = String::new();
-    loop {
-        buf.clear();
+    // However the connection ends, the client is torn down the same way
+    let read = tokio::select! {
+        read = read_commands(&state, &mut client, reader) => read,
+        // The connection was closed from this end (eg on shutdown), or can't be written to
+        written = &mut writer => {
+            let written = written.map_err(anyhow::Error::from).and_then(|written| written);
+            quit(
+                &mut *write_state(&state)?,
+                Some("Connection closed".to_owned()),
+                client,
+            );
+            return written;
+        }
+        // Something sent to the client by someone else has overflowed its SendQ
+        _ = outbound.sendq_exceeded() => Err(SendQExceeded.into()),
+    };
+    let (quit_message, result) = match read {
+        Ok(quit_message) => (quit_message, Ok(())),
+        // The client has stopped reading, whatever is still queued for it is thrown away
+        Err(e) if e.is::<SendQExceeded>() => {
+            quit(&mut *write_state(&state)?, Some(e.to_string()), client);
+            writer.abort();
+            return Ok(());
+        }
+        Err(e) => (Some("Connection error".to_owned()), Err(e)),
+    };
+    quit(&mut *write_state(&state)?, quit_message, client);
+    // Lets the goodbye be written before the connection is dropped, but a client that has
+    // stopped reading doesn't get to hold on to the connection
+    match tokio::time::timeout(GOODBYE_TIMEOUT, &mut writer).await {
+        Ok(Ok(Err(e))) if result.is_ok() => Err(e),
+        Ok(_) => result,
+        Err(_elapsed) => {
+            writer.abort();
+            result
+        }
+    }
+}
+
+/// Reads and applies commands until the client quits, returning its quit message
+/// Lines are read ahead of being run, so a client that is being held back for flooding is
+/// still caught once it has sent far too much
+async fn read_commands(
+    state: &Arc<RwLock<State>>,
+    client: &mut Client,
+    reader: impl AsyncRead + Unpin,
+) -> Result<Option<String>> {
+    let (ping_interval, invalid_utf8, mut flood, trusted) = {
+        let state = read_state(state)?;
+        (
+            state.ping_interval(),
+            state.invalid_utf8(),
+            FloodControl::new(state.flood_limits(client.listener)),
+            state.is_flood_exempt(&client.hostname),
+        )
+    };
+    // Anything read before a timeout is kept by the reader, so the line can be finished on
+    // the next read
+    let mut lines = LineReader::new(BufReader::new(reader));
+    let mut awaiting_pong = false;
+    // Each line that has been read, with when it may run
+    let mut pending: VecDeque<(Instant, Result<Command>)> = VecDeque::new();
+    // Set once the client has closed its end, after which only what is pending is left to run
+    let mut eof = false;
 
-        match stream_reader.read_line(&mut buf) {
-            Ok(0) => {
+    loop {
+        // A read timing out means the client has been idle for a whole ping interval
+        let next_line = tokio::time::timeout(ping_interval, lines.next_line());
+        let read = match pending.front() {
+            // Whatever is due runs before anything more is read
+            Some((ready_at, _)) if *ready_at <= Instant::now() => None,
+            Some((ready_at, _)) if eof => {
+                tokio::time::sleep_until(*ready_at).await;
+                None
+            }
+            Some((ready_at, _)) => tokio::select! {
+                _ = tokio::time::sleep_until(*ready_at) => None,
+                read = next_line => Some(read),
+            },
+            None => Some(next_line.await),
+        };
+        let command = match read {
+            None => match pending.pop_front() {
+                Some((_, Ok(command))) => command,
+                Some((_, Err(e))) => {
+                    report_line_error(state, client, e)?;
+                    continue;
+                }
+                None => continue,
+            },
+            Some(Ok(Ok(Line::Eof))) => {
                 // If, for some other reason, a client connection is closed without  the
                 // client  issuing  a  QUIT  command  (e.g.  client  dies and EOF occurs
                 // on socket), the server is required to fill in the quit  message  with
                 // some sort  of  message  reflecting the nature of the event which
                 // caused it to happen.
-                let mut state = match state.write() {
-                    Ok(state) => state,
-                    Err(_e) => todo!(),
+                let quit = Command {
+                    prefix: None,
+                    kind: CommandKind::Quit {
+                        quit_message: Some("Socket disconnected".to_owned()),
+                    },
+                };
+                // Anything still held back (including a QUIT of the client's own) runs first
+                let ready_at = pending
+                    .back()
+                    .map_or_else(Instant::now, |(ready_at, _)| *ready_at);
+                pending.push_back((ready_at, Ok(quit)));
+                eof = true;
+                continue;
+            }
+            Some(Ok(Ok(line))) => {
+                // Any line at all shows the connection is still alive
+                awaiting_pong = false;
+                let parsed = match line {
+                    Line::Complete(line) => {
+                        let Some(mut line) = line::decode(line, invalid_utf8) else {
+                            println!("Ignoring line: invalid UTF-8");
+                            continue;
+                        };
+                        if line.is_empty() {
+                            continue;
+                        }
+                        try_parse_from_line(&mut line)
+                    }
+                    _ => Err(IrcError::InputTooLong.into()),
+                };
+                let cost = parsed
+                    .as_ref()
+                    .map_or(1, |command| flood::cost(&command.kind));
+                let ready_at = match trusted || client.is_oper() {
+                    true => Some(Instant::now()),
+                    false => flood.charge(cost, Instant::now()),
                 };
-                apply_command(
-                    &mut state,
+                match ready_at {
+                    Some(ready_at) => pending.push_back((ready_at, parsed)),
+                    None => return Ok(Some("Excess Flood".to_owned())),
+                }
+                continue;
+            }
+            Some(Ok(Err(e))) => return Err(e.into()),
+            Some(Err(_elapsed)) => {
+                if awaiting_pong {
                     Command {
                         prefix: None,
                         kind: CommandKind::Quit {
-                            quit_message: Some("Socket disconnected".to_owned()),
+                            quit_message: Some("Ping timeout".to_owned()),
                         },
-                    },
-                    stream_writer,
-                );
-                return Ok(());
+                    }
+                } else {
+                    awaiting_pong = true;
+                    send_ping(&*read_state(state)?, client)?;
+                    continue;
+                }
             }
-            Ok(i) if i >= 513 => todo!(), // max len is 512 bytes
-            Ok(_) => (),
-            // Happens if a non UTF-8 byte is read. try to recover by attempting to parse
-            // leftover bytes in buf?
-            Err(_e) => todo!(),
-        }
-        let command = try_parse_from_line(&mut buf)?;
-        let mut state = match state.write() {
-            Ok(state) => state,
-            Err(_e) => todo!(),
         };
-        stream_writer = match apply_command(&mut state, command, stream_writer) {
-            Some(stream) => stream,
-            None => return Ok(()),
+
+        // Commands that only read the state leave other clients free to do the same meanwhile
+        let (applied, unsynced) = if is_query(&command.kind) {
+            let state = read_state(state)?;
+            let applied = apply_query(&state, client, command);
+            (report_command_error(&state, client, applied), None)
+        } else {
+            let mut state = write_state(state)?;
+            let applied = apply_command(&mut state, client, command);
+            let applied = report_command_error(&state, client, applied);
+            (applied, state.take_unsynced_journal())
         };
+        // Whatever the command journaled reaches the disk without holding everyone else up
+        if let Some(journal) = unsynced {
+            tokio::task::spawn_blocking(move || journal.sync()).await??;
+        }
+        if let ControlFlow::Break(quit_message) = applied? {
+            return Ok(quit_message);
+        }
+    }
+}
 
-        if let Err(e) = state.save() {
-            println!("error: {e}");
+/// Sends the numeric matching a line that couldn't be turned into a command back to the
+/// client
+/// Lines with no matching numeric are dropped, the connection is left open either way
+fn report_line_error(
+    state: &Arc<RwLock<State>>,
+    client: &mut Client,
+    error: anyhow::Error,
+) -> Result<()> {
+    let irc_error = match error.downcast::<IrcError>() {
+        Ok(irc_error) => irc_error,
+        Err(error) => match error
+            .downcast_ref::<ParseError>()
+            .and_then(ParseError::to_irc_error)
+        {
+            Some(irc_error) => irc_error,
+            None => {
+                println!("Ignoring line: {error:#}");
+                return Ok(());
+            }
+        },
+    };
+    send_numeric(&*read_state(state)?, client, &irc_error)
+}
+
+/// Locks the state for reading
+/// A poisoned lock means another client panicked part way through changing it, so rather
+/// than carry on with whatever it left behind the connection is closed with an error
+fn read_state(state: &RwLock<State>) -> Result<RwLockReadGuard<'_, State>> {
+    state
+        .read()
+        .map_err(|_| anyhow!("The state lock is poisoned"))
+}
+
+/// Locks the state for writing, closing the connection with an error if the lock is poisoned
+fn write_state(state: &RwLock<State>) -> Result<RwLockWriteGuard<'_, State>> {
+    state
+        .write()
+        .map_err(|_| anyhow!("The state lock is poisoned"))
+}
+
+fn send_numeric(state: &State, client: &mut Client, numeric: &impl Numeric) -> Result<()> {
+    client.send_wire(&numeric.to_wire(state.server_name(), client.target()))
+}
+
+/// Sends the numeric for a command that failed with an IrcError, leaving the connection open
+fn report_command_error(
+    state: &State,
+    client: &mut Client,
+    applied: Result<ControlFlow<Option<String>>>,
+) -> Result<ControlFlow<Option<String>>> {
+    match applied {
+        Err(e) => match e.downcast_ref::<IrcError>() {
+            Some(irc_error) => send_numeric(state, client, irc_error).map(ControlFlow::Continue),
+            None => Err(e),
+        },
+        applied => applied,
+    }
+}
+
+/// Reports the outcome of one part of a multi-target command (eg one channel of a JOIN),
+/// an IrcError is sent to the client so that the remaining targets can still be processed
+fn report_error(state: &State, client: &mut Client, result: Result<()>) -> Result<()> {
+    match result {
+        Ok(()) => Ok(()),
+        Err(e) => match e.downcast_ref::<IrcError>() {
+            Some(irc_error) => send_numeric(state, client, irc_error),
+            None => Err(e),
+        },
+    }
+}
+
+/// Sends a line to everyone who shares a channel with `nickname` (but not to `nickname`
+/// itself), each of them only once
+fn send_to_peers(state: &State, nickname: &str, wire: &str) {
+    let mut peers = HashSet::new();
+    for channel in state.channels_of(nickname) {
+        if let Some(channel) = state.find_channel(&channel) {
+            peers.extend(channel.nicknames().filter(|peer| *peer != nickname));
+        }
+    }
+    for peer in peers {
+        if let Some(entry) = state.find_client(peer)
+            && let Err(e) = entry.outbound.send_wire(wire)
+        {
+            println!("Error: {e}");
+        }
+    }
+}
+
+/// Sends a line to every member of a channel, optionally skipping one of them
+fn send_to_channel(state: &State, channel: &Channel, wire: &str, except: Option<&str>) {
+    for nickname in channel.nicknames() {
+        if Some(nickname) == except {
+            continue;
+        }
+        if let Some(entry) = state.find_client(nickname) {
+            // A broken recipient connection is its own handle_client's problem
+            if let Err(e) = entry.outbound.send_wire(wire) {
+                println!("Error: {e}");
+            }
         }
     }
 }
 
+/// True for commands that only read the state, which `apply_query` can run with the state
+/// locked for reading so that they don't hold up everyone else
+fn is_query(kind: &CommandKind) -> bool {
+    matches!(
+        kind,
+        CommandKind::Ping { .. }
+            | CommandKind::Pong { .. }
+            | CommandKind::PrivMsg { .. }
+            | CommandKind::Notice { .. }
+            | CommandKind::Quit { .. }
+            | CommandKind::Who { .. }
+            | CommandKind::Names { .. }
+            | CommandKind::Flush
+    )
+}
+
+/// Errors if the client isn't yet allowed to send the command
+fn check_registration(client: &Client, kind: &CommandKind) -> Result<()> {
+    // Only the registration commands (and keepalives and QUIT) are allowed before the
+    // welcome burst
+    match (kind, client.registration) {
+        (
+            CommandKind::Pass { .. }
+            | CommandKind::Nick { .. }
+            | CommandKind::User { .. }
+            | CommandKind::Ping { .. }
+            | CommandKind::Pong { .. }
+            | CommandKind::Quit { .. },
+            _,
+        ) => Ok(()),
+        (_, Registration::Pending) => bail!(IrcError::NotRegistered),
+        (_, Registration::Registered) => Ok(()),
+    }
+}
+
+/// Applies a single command on behalf of a client
+/// Breaks with the quit message when the connection should be closed
 fn apply_command(
     state: &mut State,
+    client: &mut Client,
     command: Command,
-    stream: BufWriter<TcpStream>,
-) -> Option<BufWriter<TcpStream>> {
+) -> Result<ControlFlow<Option<String>>> {
+    check_registration(client, &command.kind)?;
     match command.kind {
-        CommandKind::Join {
-            channels: _,
-            keys: _,
-        } => Some(stream),
-        CommandKind::Nick { nickname: _ } => todo!(),
+        CommandKind::Pass { password } => {
+            if client.is_registered() {
+                bail!(IrcError::AlreadyRegistered);
+            }
+            client.password = Some(password);
+        }
+        CommandKind::Join { channels, keys } => channels::join(state, client, channels, keys)?,
+        CommandKind::Part {
+            channels,
+            part_message,
+        } => channels::part(state, client, channels, part_message)?,
+        CommandKind::Nick { nickname } => {
+            if state.is_nickname_in_use(&nickname, client.nickname.as_deref()) {
+                bail!(IrcError::NicknameInUse { nickname });
+            }
+            if client.is_registered() {
+                check_banned_nick_change(state, client, &nickname)?;
+                change_nickname(state, client, nickname);
+            } else {
+                client.nickname = Some(nickname);
+                try_complete_registration(state, client)?;
+            }
+        }
         CommandKind::User {
-            user_name: _,
-            mode: _,
-            real_name: _,
-        } => Some(stream),
+            user_name,
+            mode,
+            real_name,
+        } => {
+            if client.is_registered() {
+                bail!(IrcError::AlreadyRegistered);
+            }
+            // Bit 2 of the mode asks for +w and bit 3 for +i, the rest mean nothing
+            if mode & 0b0100 != 0 {
+                client.modes.insert('w');
+            }
+            if mode & 0b1000 != 0 {
+                client.modes.insert('i');
+            }
+            client.user_name = Some(user_name);
+            client.real_name = Some(real_name);
+            try_complete_registration(state, client)?;
+        }
+        CommandKind::Mode {
+            target,
+            modes,
+            params,
+        } => modes::mode(state, client, target, modes, params)?,
+        CommandKind::Kick {
+            channels,
+            users,
+            comment,
+        } => channels::kick(state, client, channels, users, comment)?,
+        CommandKind::Topic { channel, topic } => channels::topic(state, client, channel, topic)?,
+        CommandKind::Oper { name, password } => oper::oper(state, client, &name, &password)?,
+        CommandKind::RegChan { channel } => oper::regchan(state, client, &channel)?,
+        kind => return run_query(state, client, kind),
+    }
+    Ok(ControlFlow::Continue(()))
+}
+
+/// Applies a command that only reads the state, see `is_query`
+fn apply_query(
+    state: &State,
+    client: &mut Client,
+    command: Command,
+) -> Result<ControlFlow<Option<String>>> {
+    check_registration(client, &command.kind)?;
+    run_query(state, client, command.kind)
+}
+
+fn run_query(
+    state: &State,
+    client: &mut Client,
+    kind: CommandKind,
+) -> Result<ControlFlow<Option<String>>> {
+    match kind {
         CommandKind::Ping {
-            source_server: _,
+            source_server,
             target_server: _,
-        } => Some(stream),
+        } => {
+            let pong = Command {
+                prefix: Some(state.server_name().to_owned()),
+                kind: CommandKind::Pong {
+                    source_server: state.server_name().to_owned(),
+                    target_server: Some(source_server),
+                },
+            };
+            client.send_wire(&pong.to_wire())?;
+        }
+        // Liveness is tracked by handle_client, which treats any line as proof of life
+        CommandKind::Pong { .. } => (),
         CommandKind::PrivMsg {
-            message_target: _,
-            message_text: _,
-        } => Some(stream),
-        CommandKind::Quit { quit_message } => {
-            quit(state, quit_message, stream);
-            None
+            message_targets,
+            message_text,
+        } => deliver_message(state, client, message_targets, message_text, false)?,
+        CommandKind::Notice {
+            message_targets,
+            message_text,
+        } => deliver_message(state, client, message_targets, message_text, true)?,
+        CommandKind::Quit { quit_message } => return Ok(ControlFlow::Break(quit_message)),
+        CommandKind::Who { mask, operators } => channels::who(state, client, mask, operators)?,
+        CommandKind::Names { channels } => channels::names(state, client, channels)?,
+        CommandKind::Flush => oper::flush(state, client)?,
+        kind => bail!("{kind:?} changes the state, so can't be run as a query"),
+    }
+    Ok(ControlFlow::Continue(()))
+}
+
+/// Sends the welcome burst (RPL_WELCOME through RPL_MYINFO) once NICK and USER have both
+/// been received
+fn try_complete_registration(state: &mut State, client: &mut Client) -> Result<()> {
+    if !client.can_complete_registration() {
+        return Ok(());
+    }
+    // Another client may have registered with the same nickname since NICK was accepted
+    if let Some(nickname) = client
+        .nickname
+        .take_if(|n| state.is_nickname_in_use(n, None))
+    {
+        bail!(IrcError::NicknameInUse { nickname });
+    }
+    client.registration = Registration::Registered;
+    state.add_client(ClientEntry {
+        nickname: client.target().to_owned(),
+        user_name: client.user_name.clone().unwrap_or_default(),
+        hostname: client.hostname.clone(),
+        real_name: client.real_name.clone().unwrap_or_default(),
+        modes: client.modes.clone(),
+        outbound: client.outbound().clone(),
+    });
+
+    let server = state.server_name().to_owned();
+    let mut channel_modes: Vec<char> = CHANNEL_MODES.chars().collect();
+    channel_modes.extend(state.ranks().iter().map(|rank| rank.mode()));
+    channel_modes.sort_by_key(|mode| mode.to_ascii_lowercase());
+    for reply in [
+        Reply::Welcome {
+            nickname: client.target().to_owned(),
+            user: client.user_name.clone().unwrap_or_default(),
+            host: client.hostname.clone(),
+        },
+        Reply::YourHost {
+            server: server.clone(),
+            version: VERSION.to_owned(),
+        },
+        Reply::Created {
+            date: humantime::format_rfc3339_seconds(state.created()).to_string(),
+        },
+        Reply::MyInfo {
+            server,
+            version: VERSION.to_owned(),
+            user_modes: USER_MODES.to_owned(),
+            channel_modes: channel_modes.into_iter().collect(),
+        },
+        Reply::ISupport {
+            tokens: isupport(state),
+        },
+    ] {
+        send_numeric(state, client, &reply)?;
+    }
+    Ok(())
+}
+
+/// The RPL_ISUPPORT tokens, describing what the server offers beyond RFC 2812
+fn isupport(state: &State) -> Vec<String> {
+    let ranks = state.ranks();
+    let rank_modes: String = ranks.iter().map(|rank| rank.mode()).collect();
+    let prefixes: String = ranks.iter().map(|rank| rank.prefix()).collect();
+    vec![
+        "CASEMAPPING=rfc1459".to_owned(),
+        format!("CHANMODES={},k,l,{}", modes::LIST_MODES, modes::FLAG_MODES),
+        "CHANTYPES=#&".to_owned(),
+        format!("MAXLIST={}:{}", modes::LIST_MODES, state.max_list_entries()),
+        format!("MAXTARGETS={MAX_TARGETS}"),
+        "NICKLEN=9".to_owned(),
+        format!("PREFIX=({rank_modes}){prefixes}"),
+        format!("TOPICLEN={}", state.topic_len()),
+    ]
+}
+
+/// Errors if the client is banned, as it is now or as it would be, in any channel it is in
+/// Anyone with voice or above is never held back
+fn check_banned_nick_change(state: &State, client: &Client, nickname: &str) -> Result<()> {
+    let new_mask = format!(
+        "{nickname}!{}@{}",
+        client.user_name.as_deref().unwrap_or("*"),
+        client.hostname
+    );
+    for channel in state.channels_of(client.target()) {
+        let Some(channel) = state.find_channel(&channel) else {
+            continue;
+        };
+        let voiced = channel
+            .membership(client.target())
+            .is_some_and(|membership| membership.is_at_least(Rank::Voice));
+        if !voiced && (channel.is_banned(&client.mask()) || channel.is_banned(&new_mask)) {
+            bail!(IrcError::BanNickChange {
+                channel: channel.name.clone(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Changes a registered client's nickname, telling it and everyone it shares a channel with
+fn change_nickname(state: &mut State, client: &mut Client, nickname: String) {
+    let nick_change = Command {
+        prefix: Some(client.mask()),
+        kind: CommandKind::Nick {
+            nickname: nickname.clone(),
+        },
+    }
+    .to_wire();
+    state.rename_client(client.target(), &nickname);
+    client.nickname = Some(nickname);
+
+    if let Err(e) = client.send_wire(&nick_change) {
+        println!("Error: {e}");
+    }
+    send_to_peers(state, client.target(), &nick_change);
+}
+
+/// Delivers a PRIVMSG (or NOTICE) to each of its targets
+/// Failures are reported per target, so one bad target doesn't stop delivery to the rest
+/// NOTICEs never generate automatic replies, so their failures are silently dropped
+fn deliver_message(
+    state: &State,
+    client: &mut Client,
+    targets: Vec<String>,
+    text: String,
+    notice: bool,
+) -> Result<()> {
+    let mut seen = HashSet::new();
+    for (i, target) in targets.into_iter().enumerate() {
+        let delivered = if i >= MAX_TARGETS || !seen.insert(casemap(&target)) {
+            Err(IrcError::TooManyTargets { target }.into())
+        } else {
+            deliver_to_target(state, client, target, &text, notice)
+        };
+
+        match delivered {
+            Err(e) if notice && e.is::<IrcError>() => (),
+            delivered => report_error(state, client, delivered)?,
+        }
+    }
+    Ok(())
+}
+
+fn deliver_to_target(
+    state: &State,
+    client: &Client,
+    target: String,
+    text: &str,
+    notice: bool,
+) -> Result<()> {
+    let message_targets = vec![target.clone()];
+    let message_text = text.to_owned();
+    let message = Command {
+        prefix: Some(client.mask()),
+        kind: if notice {
+            CommandKind::Notice {
+                message_targets,
+                message_text,
+            }
+        } else {
+            CommandKind::PrivMsg {
+                message_targets,
+                message_text,
+            }
+        },
+    };
+
+    if target.starts_with(['#', '&']) {
+        let channel = state
+            .find_channel(&target)
+            .ok_or(IrcError::NoSuchNick { nickname: target })?;
+        let banned = channel.is_banned(&client.mask());
+        let allowed = match channel.membership(client.target()) {
+            // Only members with voice or above may speak in a moderated channel, or while
+            // banned
+            Some(membership) => {
+                membership.is_at_least(Rank::Voice) || !(channel.modes.contains(&'m') || banned)
+            }
+            // Outsiders are kept out by +n, and by +m since they can't hold voice
+            None => !(channel.modes.contains(&'n') || channel.modes.contains(&'m') || banned),
+        };
+        if !allowed {
+            bail!(IrcError::CannotSendToChan {
+                channel: channel.name.clone(),
+            });
+        }
+        send_to_channel(state, channel, &message.to_wire(), Some(client.target()));
+    } else {
+        let entry = state
+            .find_client(&target)
+            .ok_or(IrcError::NoSuchNick { nickname: target })?;
+        // A broken recipient connection is its own handle_client's problem, not the sender's
+        if let Err(e) = entry.outbound.send_wire(&message.to_wire()) {
+            println!("Error: {e}");
         }
     }
+    Ok(())
 }
 
-fn quit(_state: &mut State, _comment: Option<String>, connection: BufWriter<TcpStream>) {
-    let connection = connection.into_inner().unwrap();
-    if let Err(_e) = connection.shutdown(Shutdown::Both) {
-        todo!();
+/// Sends a PING to a client that has gone quiet, it will be disconnected if it doesn't answer
+fn send_ping(state: &State, client: &mut Client) -> Result<()> {
+    let ping = Command {
+        prefix: None,
+        kind: CommandKind::Ping {
+            source_server: state.server_name().to_owned(),
+            target_server: None,
+        },
     };
-    todo!()
+    client.send_wire(&ping.to_wire())
+}
+
+/// Tears down a client's connection, telling everyone it shared a channel with that it quit
+fn quit(state: &mut State, comment: Option<String>, mut client: Client) {
+    state.remove_connection(client.outbound());
+    if state.is_shutting_down() {
+        // Everyone has already been told, and every connection is already closed
+        if client.is_registered() {
+            state.remove_client(client.target());
+        }
+        return;
+    }
+
+    let reason = comment.unwrap_or_else(|| "Client Quit".to_owned());
+    if client.is_registered() {
+        let quit_message = Command {
+            prefix: Some(client.mask()),
+            kind: CommandKind::Quit {
+                quit_message: Some(reason.clone()),
+            },
+        };
+        send_to_peers(state, client.target(), &quit_message.to_wire());
+        state.remove_client(client.target());
+    }
+    // The socket may already be gone (eg after EOF), so failing to say goodbye is fine
+    let _ = client.send_wire(&format!(
+        "ERROR :Closing Link: {} ({reason})\r\n",
+        client.hostname
+    ));
+    if let Err(e) = client.shutdown() {
+        println!("Error: {e}");
+    };
+}
+
+/// Tells every connected client that the server is going away, then closes their connections
+/// Their handle_client tasks see the connection close and clean up after themselves
+pub fn shutdown(state: &mut State) {
+    state.begin_shutdown();
+    for outbound in state.connections() {
+        let notice = Command {
+            prefix: Some(state.server_name().to_owned()),
+            kind: CommandKind::Notice {
+                message_targets: vec![state.nickname_of(outbound).unwrap_or("*").to_owned()],
+                message_text: "Server shutting down".to_owned(),
+            },
+        };
+        // Failing to say goodbye to a connection that is already broken is fine
+        let _ = outbound.send_wire(&notice.to_wire());
+        let _ = outbound.send_wire("ERROR :Server shutting down\r\n");
+        if let Err(e) = outbound.shutdown() {
+            println!("Error: {e}");
+        }
+    }
 }
@@ -0,0 +1,446 @@
+use anyhow::{Result, bail};
+use std::time::SystemTime;
+
+use super::{client::Client, line::MAX_LINE_LEN, report_error, send_numeric, send_to_channel};
+use crate::{
+    Command, CommandKind,
+    errors::IrcError,
+    replies::{Numeric, Reply},
+    state::{Channel, ClientEntry, Rank, State, mask_matches, to_unix_seconds},
+};
+
+/// JOIN, where "JOIN 0" leaves every channel the client is in
+pub fn join(
+    state: &mut State,
+    client: &mut Client,
+    channels: Vec<String>,
+    keys: Option<Vec<String>>,
+) -> Result<()> {
+    if channels == ["0"] {
+        for channel in state.channels_of(client.target()) {
+            let parted = part_one(state, client, &channel, None);
+            report_error(state, client, parted)?;
+        }
+        return Ok(());
+    }
+
+    let keys = keys.unwrap_or_default();
+    for (i, channel) in channels.iter().enumerate() {
+        let joined = join_one(state, client, channel, keys.get(i).map(String::as_str));
+        report_error(state, client, joined)?;
+    }
+    Ok(())
+}
+
+fn join_one(state: &mut State, client: &mut Client, name: &str, key: Option<&str>) -> Result<()> {
+    let nickname = client.target().to_owned();
+    if let Some(channel) = state.find_channel(name) {
+        if channel.is_member(&nickname) {
+            return Ok(());
+        }
+        check_can_join(channel, &client.mask(), key)?;
+    }
+    state.join_channel(name, &nickname);
+
+    let channel = state.find_channel(name).ok_or(IrcError::NoSuchChannel {
+        channel: name.to_owned(),
+    })?;
+    let join = Command {
+        prefix: Some(client.mask()),
+        kind: CommandKind::Join {
+            channels: vec![channel.name.clone()],
+            keys: None,
+        },
+    };
+    send_to_channel(state, channel, &join.to_wire(), None);
+    if channel.topic.is_some() {
+        send_topic(state, client, channel)?;
+    }
+    send_names(state, client, channel)
+}
+
+/// Errors if the channel's modes keep the client (known by its nick!user@host) out
+fn check_can_join(channel: &Channel, mask: &str, key: Option<&str>) -> Result<()> {
+    let channel_name = channel.name.clone();
+    if channel.is_banned(mask) {
+        bail!(IrcError::BannedFromChan {
+            channel: channel_name
+        });
+    }
+    if channel.modes.contains(&'i') && !channel.is_invite_excepted(mask) {
+        bail!(IrcError::InviteOnlyChan {
+            channel: channel_name
+        });
+    }
+    if channel
+        .key
+        .as_deref()
+        .is_some_and(|needed| Some(needed) != key)
+    {
+        bail!(IrcError::BadChannelKey {
+            channel: channel_name
+        });
+    }
+    if channel
+        .limit
+        .is_some_and(|limit| channel.member_count() >= limit)
+    {
+        bail!(IrcError::ChannelIsFull {
+            channel: channel_name
+        });
+    }
+    Ok(())
+}
+
+pub fn part(
+    state: &mut State,
+    client: &mut Client,
+    channels: Vec<String>,
+    part_message: Option<String>,
+) -> Result<()> {
+    for channel in channels {
+        let parted = part_one(state, client, &channel, part_message.clone());
+        report_error(state, client, parted)?;
+    }
+    Ok(())
+}
+
+fn part_one(
+    state: &mut State,
+    client: &mut Client,
+    name: &str,
+    part_message: Option<String>,
+) -> Result<()> {
+    let channel = state.find_channel(name).ok_or(IrcError::NoSuchChannel {
+        channel: name.to_owned(),
+    })?;
+    if !channel.is_member(client.target()) {
+        return Err(IrcError::NotOnChannel {
+            channel: channel.name.clone(),
+        }
+        .into());
+    }
+
+    let part = Command {
+        prefix: Some(client.mask()),
+        kind: CommandKind::Part {
+            channels: vec![channel.name.clone()],
+            part_message,
+        },
+    };
+    send_to_channel(state, channel, &part.to_wire(), None);
+    state.part_channel(name, client.target());
+    Ok(())
+}
+
+/// KICK, either one channel and any number of users, or a channel for each user
+pub fn kick(
+    state: &mut State,
+    client: &mut Client,
+    channels: Vec<String>,
+    users: Vec<String>,
+    comment: Option<String>,
+) -> Result<()> {
+    let channels = match channels.len() {
+        1 => vec![channels[0].clone(); users.len()],
+        _ => channels,
+    };
+    for (channel, user) in channels.iter().zip(users) {
+        let kicked = kick_one(state, client, channel, user, comment.clone());
+        report_error(state, client, kicked)?;
+    }
+    Ok(())
+}
+
+/// Kicking needs halfop or above, and a rank at least as high as the member's
+fn kick_one(
+    state: &mut State,
+    client: &mut Client,
+    name: &str,
+    user: String,
+    comment: Option<String>,
+) -> Result<()> {
+    let channel = state.find_channel(name).ok_or(IrcError::NoSuchChannel {
+        channel: name.to_owned(),
+    })?;
+    let Some(kicker) = channel.membership(client.target()) else {
+        bail!(IrcError::NotOnChannel {
+            channel: channel.name.clone(),
+        });
+    };
+    let Some(nickname) = state
+        .find_client(&user)
+        .map(|entry| entry.nickname.clone())
+        .filter(|nickname| channel.is_member(nickname))
+    else {
+        bail!(IrcError::UserNotInChannel {
+            nickname: user,
+            channel: channel.name.clone(),
+        });
+    };
+    let outranked = channel
+        .membership(&nickname)
+        .and_then(|membership| membership.highest())
+        .is_some_and(|rank| kicker.highest() < Some(rank));
+    if !kicker.is_at_least(Rank::HalfOp) || outranked {
+        bail!(IrcError::ChanOPrivsNeeded {
+            channel: channel.name.clone(),
+        });
+    }
+
+    let kick = Command {
+        prefix: Some(client.mask()),
+        kind: CommandKind::Kick {
+            channels: vec![channel.name.clone()],
+            users: vec![nickname.clone()],
+            comment: Some(comment.unwrap_or_else(|| client.target().to_owned())),
+        },
+    };
+    send_to_channel(state, channel, &kick.to_wire(), None);
+    state.part_channel(name, &nickname);
+    Ok(())
+}
+
+/// TOPIC, which shows the topic, or changes it if one is given
+/// Only members may change the topic, and only halfops and above while the channel is +t
+pub fn topic(
+    state: &mut State,
+    client: &mut Client,
+    name: String,
+    topic: Option<String>,
+) -> Result<()> {
+    let channel = state.find_channel(&name).ok_or(IrcError::NoSuchChannel {
+        channel: name.clone(),
+    })?;
+    let membership = channel.membership(client.target());
+    let Some(mut topic) = topic else {
+        if membership.is_none() && channel.is_hidden() {
+            bail!(IrcError::NotOnChannel {
+                channel: channel.name.clone(),
+            });
+        }
+        return send_topic(state, client, channel);
+    };
+    let Some(membership) = membership else {
+        bail!(IrcError::NotOnChannel {
+            channel: channel.name.clone(),
+        });
+    };
+    if channel.modes.contains(&'t') && !membership.is_at_least(Rank::HalfOp) {
+        bail!(IrcError::ChanOPrivsNeeded {
+            channel: channel.name.clone(),
+        });
+    }
+
+    // Cut short on a character boundary
+    if topic.len() > state.topic_len() {
+        let end = (0..=state.topic_len())
+            .rev()
+            .find(|end| topic.is_char_boundary(*end))
+            .unwrap_or_default();
+        topic.truncate(end);
+    }
+    let set_by = client.mask();
+    state.update_channel(&name, |channel| {
+        channel.topic = Some(topic.clone()).filter(|topic| !topic.is_empty());
+        channel.topic_set_by = set_by.clone();
+        channel.topic_set_at = SystemTime::now();
+    })?;
+
+    let Some(channel) = state.find_channel(&name) else {
+        return Ok(());
+    };
+    let topic_change = Command {
+        prefix: Some(set_by),
+        kind: CommandKind::Topic {
+            channel: channel.name.clone(),
+            topic: Some(topic),
+        },
+    };
+    send_to_channel(state, channel, &topic_change.to_wire(), None);
+    Ok(())
+}
+
+/// RPL_TOPIC and RPL_TOPICWHOTIME, or RPL_NOTOPIC
+fn send_topic(state: &State, client: &mut Client, channel: &Channel) -> Result<()> {
+    let Some(topic) = &channel.topic else {
+        let reply = Reply::NoTopic {
+            channel: channel.name.clone(),
+        };
+        return send_numeric(state, client, &reply);
+    };
+    let reply = Reply::Topic {
+        channel: channel.name.clone(),
+        topic: topic.clone(),
+    };
+    send_numeric(state, client, &reply)?;
+    let reply = Reply::TopicWhoTime {
+        channel: channel.name.clone(),
+        set_by: channel.topic_set_by.clone(),
+        set_at: to_unix_seconds(channel.topic_set_at),
+    };
+    send_numeric(state, client, &reply)
+}
+
+/// WHO, listing a channel's members with their ranks, or every client whose nickname,
+/// hostname or real name matches a mask, only IRC operators if `operators` is set
+/// Members of secret and private channels, and invisible (+i) clients, are only listed for
+/// those who share a channel with them
+pub fn who(
+    state: &State,
+    client: &mut Client,
+    mask: Option<String>,
+    operators: bool,
+) -> Result<()> {
+    // No mask, or "0", lists everyone
+    let mask = mask
+        .filter(|mask| mask != "0")
+        .unwrap_or_else(|| "*".to_owned());
+    let visible = visible_to(state, client);
+
+    // Each client listed, with the channel and rank to show for it
+    let mut listed: Vec<(&ClientEntry, Option<&Channel>)> = Vec::new();
+    match state.find_channel(&mask) {
+        Some(channel) => {
+            let member = channel.is_member(client.target());
+            if member || !channel.is_hidden() {
+                listed.extend(
+                    channel
+                        .nicknames()
+                        .filter_map(|nickname| state.find_client(nickname))
+                        .filter(|entry| member || visible(entry))
+                        .map(|entry| (entry, Some(channel))),
+                );
+            }
+        }
+        None => {
+            listed.extend(
+                state
+                    .clients()
+                    .filter(|entry| {
+                        [&entry.nickname, &entry.hostname, &entry.real_name]
+                            .iter()
+                            .any(|field| mask_matches(&mask, field))
+                    })
+                    .filter(|entry| visible(entry))
+                    .map(|entry| (entry, None)),
+            );
+            listed.sort_by(|(a, _), (b, _)| a.nickname.cmp(&b.nickname));
+        }
+    }
+
+    let server = state.server_name().to_owned();
+    for (entry, channel) in listed {
+        if operators && !entry.modes.contains(&'o') {
+            continue;
+        }
+        // H for here, * for an IRC operator, then the channel rank
+        let mut flags = "H".to_owned();
+        if entry.modes.contains(&'o') {
+            flags.push('*');
+        }
+        if let Some(rank) = channel
+            .and_then(|channel| channel.membership(&entry.nickname))
+            .and_then(|membership| membership.highest())
+        {
+            flags.push(rank.prefix());
+        }
+        let reply = Reply::WhoReply {
+            channel: channel.map_or("*".to_owned(), |channel| channel.name.clone()),
+            user: entry.user_name.clone(),
+            host: entry.hostname.clone(),
+            server: server.clone(),
+            nickname: entry.nickname.clone(),
+            flags,
+            hopcount: 0,
+            real_name: entry.real_name.clone(),
+        };
+        send_numeric(state, client, &reply)?;
+    }
+    send_numeric(state, client, &Reply::EndOfWho { name: mask })
+}
+
+/// Which clients WHO and NAMES may list to `client`: anyone who isn't +i, and anyone it
+/// shares a channel with
+fn visible_to<'a>(state: &'a State, client: &Client) -> impl Fn(&ClientEntry) -> bool + 'a {
+    let nickname = client.target().to_owned();
+    let shared: Vec<&Channel> = state
+        .channels_of(&nickname)
+        .iter()
+        .filter_map(|name| state.find_channel(name))
+        .collect();
+    move |entry| {
+        !entry.modes.contains(&'i')
+            || entry.nickname == nickname
+            || shared
+                .iter()
+                .any(|channel| channel.is_member(&entry.nickname))
+    }
+}
+
+/// NAMES, which lists the members of each channel
+/// Hidden channels are only listed to their members, and anyone else only sees the members
+/// that WHO would show them
+pub fn names(state: &State, client: &mut Client, channels: Vec<String>) -> Result<()> {
+    // Listing every channel at once is more than anyone needs
+    if channels.is_empty() {
+        let end = Reply::EndOfNames {
+            channel: "*".to_owned(),
+        };
+        return send_numeric(state, client, &end);
+    }
+    for name in channels {
+        match state.find_channel(&name) {
+            Some(channel) if channel.is_member(client.target()) || !channel.is_hidden() => {
+                send_names(state, client, channel)?
+            }
+            _ => send_numeric(state, client, &Reply::EndOfNames { channel: name })?,
+        }
+    }
+    Ok(())
+}
+
+/// RPL_NAMREPLY, split over as many lines as needed, followed by RPL_ENDOFNAMES
+/// Members are left out if the client couldn't see them with WHO
+pub fn send_names(state: &State, client: &mut Client, channel: &Channel) -> Result<()> {
+    let member = channel.is_member(client.target());
+    let visible = visible_to(state, client);
+    let names =
+        channel.names(|nickname| member || state.find_client(nickname).is_some_and(&visible));
+    // Each line gets whatever room is left once the rest of the reply is written out
+    let empty = Reply::NamReply {
+        symbol: channel.names_symbol(),
+        channel: channel.name.clone(),
+        nicknames: Vec::new(),
+    };
+    let room =
+        MAX_LINE_LEN.saturating_sub(empty.to_wire(state.server_name(), client.target()).len());
+    let mut lines: Vec<Vec<String>> = vec![Vec::new()];
+    let mut line_len = 0;
+    for name in names {
+        if line_len + name.len() > room {
+            lines.push(Vec::new());
+            line_len = 0;
+        }
+        line_len += name.len() + 1;
+        if let Some(line) = lines.last_mut() {
+            line.push(name);
+        }
+    }
+
+    for nicknames in lines.into_iter().filter(|line| !line.is_empty()) {
+        let reply = Reply::NamReply {
+            symbol: channel.names_symbol(),
+            channel: channel.name.clone(),
+            nicknames,
+        };
+        send_numeric(state, client, &reply)?;
+    }
+    send_numeric(
+        state,
+        client,
+        &Reply::EndOfNames {
+            channel: channel.name.clone(),
+        },
+    )
+}
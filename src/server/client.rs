@@ -0,0 +1,207 @@
+use anyhow::{Result, anyhow, bail};
+use std::{
+    collections::BTreeSet,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt, BufWriter},
+    sync::{
+        Notify,
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+    },
+};
+
+use crate::config::ListenerKind;
+
+/// Something waiting to be written to a client's connection
+pub enum Outgoing {
+    /// An already CRLF terminated line
+    Line(String),
+    /// Close the connection once everything before this has been written
+    Close,
+}
+
+/// How much a client has queued for it but not yet written, and how much it is allowed
+struct SendQ {
+    queued: AtomicUsize,
+    limit: usize,
+    exceeded: Notify,
+}
+
+/// A client has fallen more than its SendQ limit behind, and needs to be disconnected
+#[derive(Error, Debug)]
+#[error("Max SendQ exceeded")]
+pub struct SendQExceeded;
+
+/// A shareable handle for writing to a client's connection, so that other clients can deliver
+/// messages to it
+/// Lines are queued rather than written straight away, so a client that is slow to read never
+/// holds up whoever is sending to it
+/// A client that falls more than its SendQ limit behind is cut off, rather than the queue
+/// being allowed to grow without bound
+#[derive(Clone)]
+pub struct Outbound {
+    sender: UnboundedSender<Outgoing>,
+    sendq: Arc<SendQ>,
+}
+
+/// The receiving end of an Outbound, for `write_queued` to drain
+pub struct Queue {
+    receiver: UnboundedReceiver<Outgoing>,
+    sendq: Arc<SendQ>,
+}
+
+impl Outbound {
+    /// `sendq` is the most bytes that may be waiting to be written at once
+    pub fn new(sendq: usize) -> (Self, Queue) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let sendq = Arc::new(SendQ {
+            queued: AtomicUsize::new(0),
+            limit: sendq,
+            exceeded: Notify::new(),
+        });
+        let queue = Queue {
+            receiver,
+            sendq: Arc::clone(&sendq),
+        };
+        (Outbound { sender, sendq }, queue)
+    }
+
+    /// Queues an already CRLF terminated line for the client
+    pub fn send_wire(&self, wire: &str) -> Result<()> {
+        let queued = self.sendq.queued.fetch_add(wire.len(), Ordering::Relaxed) + wire.len();
+        if queued > self.sendq.limit {
+            self.sendq.queued.fetch_sub(wire.len(), Ordering::Relaxed);
+            self.sendq.exceeded.notify_one();
+            bail!(SendQExceeded);
+        }
+        self.sender
+            .send(Outgoing::Line(wire.to_owned()))
+            .map_err(|_e| anyhow!("Connection already closed"))
+    }
+
+    /// Completes once the client has fallen too far behind, and needs to be disconnected
+    pub async fn sendq_exceeded(&self) {
+        self.sendq.exceeded.notified().await
+    }
+
+    /// True if both handles write to the same connection
+    pub fn is_same(&self, other: &Outbound) -> bool {
+        self.sender.same_channel(&other.sender)
+    }
+
+    /// Closes the connection, after anything already queued has been written
+    pub fn shutdown(&self) -> Result<()> {
+        self.sender
+            .send(Outgoing::Close)
+            .map_err(|_e| anyhow!("Connection already closed"))
+    }
+}
+
+/// Writes a connection's queue out to it until the connection is closed
+pub async fn write_queued(mut queue: Queue, writer: impl AsyncWrite + Unpin) -> Result<()> {
+    let mut writer = BufWriter::new(writer);
+    while let Some(Outgoing::Line(line)) = queue.receiver.recv().await {
+        writer.write_all(line.as_bytes()).await?;
+        queue.sendq.queued.fetch_sub(line.len(), Ordering::Relaxed);
+        // Flushing once the queue is empty sends a burst of lines in as few writes as possible
+        if queue.receiver.is_empty() {
+            writer.flush().await?;
+        }
+    }
+    writer.flush().await?;
+    writer.shutdown().await?;
+    Ok(())
+}
+
+/// Where a connection is in the RFC 2812 registration handshake
+/// PASS, NICK and USER may arrive in any order, registration completes once NICK and USER
+/// have both been seen
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Registration {
+    Pending,
+    Registered,
+}
+
+/// Per-connection state for a single client
+pub struct Client {
+    pub registration: Registration,
+    pub password: Option<String>,
+    pub nickname: Option<String>,
+    pub user_name: Option<String>,
+    pub real_name: Option<String>,
+    pub hostname: String,
+    /// The kind of listener the client connected to
+    pub listener: ListenerKind,
+    /// User modes, eg 'i' for invisible
+    pub modes: BTreeSet<char>,
+    outbound: Outbound,
+}
+
+impl Client {
+    pub fn new(outbound: Outbound, hostname: String, secure: bool, listener: ListenerKind) -> Self {
+        Client {
+            registration: Registration::Pending,
+            password: None,
+            nickname: None,
+            user_name: None,
+            real_name: None,
+            hostname,
+            listener,
+            // +Z, for a TLS connection, lasts as long as the connection
+            modes: match secure {
+                true => BTreeSet::from(['Z']),
+                false => BTreeSet::new(),
+            },
+            outbound,
+        }
+    }
+
+    /// True once the client has become an IRC operator
+    pub fn is_oper(&self) -> bool {
+        self.modes.contains(&'o')
+    }
+
+    pub fn is_registered(&self) -> bool {
+        self.registration == Registration::Registered
+    }
+
+    /// True once both NICK and USER have been received but the welcome burst hasn't been sent
+    pub fn can_complete_registration(&self) -> bool {
+        self.registration == Registration::Pending
+            && self.nickname.is_some()
+            && self.user_name.is_some()
+    }
+
+    /// The nickname to address replies to, "*" if one hasn't been chosen yet
+    pub fn target(&self) -> &str {
+        self.nickname.as_deref().unwrap_or("*")
+    }
+
+    /// nick!user@host
+    pub fn mask(&self) -> String {
+        format!(
+            "{}!{}@{}",
+            self.target(),
+            self.user_name.as_deref().unwrap_or("*"),
+            self.hostname
+        )
+    }
+
+    pub fn outbound(&self) -> &Outbound {
+        &self.outbound
+    }
+
+    /// Writes an already CRLF terminated line to the client
+    pub fn send_wire(&mut self, wire: &str) -> Result<()> {
+        self.outbound.send_wire(wire)
+    }
+
+    pub fn shutdown(self) -> Result<()> {
+        self.outbound.shutdown()
+    }
+}
@@ -0,0 +1,66 @@
+use tokio::time::Instant;
+
+use crate::{CommandKind, config::FloodLimits};
+
+/// Classic ircd "fake lag": every command moves a client's penalty clock forward by its cost,
+/// and a command only runs once the clock is less than a burst ahead of real time
+pub struct FloodControl {
+    limits: FloodLimits,
+    clock: Instant,
+}
+
+impl FloodControl {
+    pub fn new(limits: FloodLimits) -> Self {
+        FloodControl {
+            limits,
+            clock: Instant::now(),
+        }
+    }
+
+    /// Charges for a command costing `cost` units, and returns when it may run
+    /// None if the client has fallen so far behind that it should be disconnected
+    pub fn charge(&mut self, cost: u32, now: Instant) -> Option<Instant> {
+        self.clock = self.clock.max(now) + self.limits.interval * cost;
+        let ahead = self.clock - now;
+        if ahead > self.limits.interval * (self.limits.burst + self.limits.excess) {
+            return None;
+        }
+        let burst = self.limits.interval * self.limits.burst;
+        Some(now + ahead.saturating_sub(burst))
+    }
+}
+
+/// How many units a command costs, commands that make the server do more work cost more
+pub fn cost(command: &CommandKind) -> u32 {
+    let count = |targets: &Vec<String>| (targets.len() as u32).max(1);
+    match command {
+        CommandKind::PrivMsg {
+            message_targets, ..
+        }
+        | CommandKind::Notice {
+            message_targets, ..
+        } => count(message_targets),
+        CommandKind::Join { channels, .. } => 2 * count(channels),
+        CommandKind::Part { channels, .. } => count(channels),
+        // Seen by everyone in every channel the client is in
+        CommandKind::Nick { .. } => 3,
+        // Changes are seen by the whole channel, asking only bothers the client
+        CommandKind::Mode { modes: Some(_), .. } => 3,
+        CommandKind::Mode { modes: None, .. } => 1,
+        CommandKind::Kick { users, .. } => count(users),
+        CommandKind::Topic { topic: Some(_), .. } => 3,
+        CommandKind::Topic { topic: None, .. } => 1,
+        // Can send back a line for every client on the server
+        CommandKind::Who { .. } => 2,
+        CommandKind::Names { channels } => 2 * count(channels),
+        // Makes guessing operator passwords slow
+        CommandKind::Oper { .. } => 5,
+        CommandKind::Pass { .. }
+        | CommandKind::User { .. }
+        | CommandKind::Ping { .. }
+        | CommandKind::Pong { .. }
+        | CommandKind::Quit { .. }
+        | CommandKind::Flush
+        | CommandKind::RegChan { .. } => 1,
+    }
+}
@@ -0,0 +1,98 @@
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+
+use crate::config::InvalidUtf8;
+
+/// The longest line a client may send, including the CRLF
+pub const MAX_LINE_LEN: usize = 512;
+
+/// What the next line read from a client turned out to be
+#[derive(Debug, PartialEq)]
+pub enum Line {
+    /// A line without its line ending
+    Complete(Vec<u8>),
+    /// A line that was longer than MAX_LINE_LEN, which has been thrown away
+    TooLong,
+    Eof,
+}
+
+/// Splits a connection into lines, which may end in CRLF or just LF
+/// Never holds more than one line's worth of bytes, however long the line the client sends
+pub struct LineReader<R> {
+    reader: R,
+    buf: Vec<u8>,
+    /// True while skipping to the end of a line that is already too long
+    overlong: bool,
+}
+
+impl<R: AsyncBufRead + Unpin> LineReader<R> {
+    pub fn new(reader: R) -> Self {
+        LineReader {
+            reader,
+            buf: Vec::new(),
+            overlong: false,
+        }
+    }
+
+    /// Reads the next line
+    /// Cancel safe, anything read before being cancelled is kept for the next call
+    pub async fn next_line(&mut self) -> std::io::Result<Line> {
+        loop {
+            let available = self.reader.fill_buf().await?;
+            if available.is_empty() {
+                // A last line without a line ending still counts
+                return Ok(match (self.overlong, self.buf.is_empty()) {
+                    (false, false) => Line::Complete(std::mem::take(&mut self.buf)),
+                    (true, _) => {
+                        self.overlong = false;
+                        Line::TooLong
+                    }
+                    (false, true) => Line::Eof,
+                });
+            }
+            let (taken, end_of_line) = match available.iter().position(|&b| b == b'\n') {
+                Some(i) => (i + 1, true),
+                None => (available.len(), false),
+            };
+            if !self.overlong {
+                self.buf.extend_from_slice(&available[..taken]);
+            }
+            self.reader.consume(taken);
+
+            if !end_of_line {
+                // Too long already, so there's no point keeping any of it
+                if self.buf.len() > MAX_LINE_LEN {
+                    self.buf.clear();
+                    self.overlong = true;
+                }
+                continue;
+            }
+            if std::mem::take(&mut self.overlong) {
+                return Ok(Line::TooLong);
+            }
+            let mut line = std::mem::take(&mut self.buf);
+            line.pop();
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            // Leaves room for the CRLF, even if the line only ended with LF
+            return Ok(match line.len() > MAX_LINE_LEN - 2 {
+                true => Line::TooLong,
+                false => Line::Complete(line),
+            });
+        }
+    }
+}
+
+/// Turns a line into a String, using `policy` if it isn't valid UTF-8
+/// None if the line should be dropped
+pub fn decode(line: Vec<u8>, policy: InvalidUtf8) -> Option<String> {
+    match String::from_utf8(line) {
+        Ok(line) => Some(line),
+        Err(e) => match policy {
+            InvalidUtf8::Reject => None,
+            InvalidUtf8::Lossy => Some(String::from_utf8_lossy(e.as_bytes()).into_owned()),
+            // Every byte is the latin-1 character with the same code point
+            InvalidUtf8::Latin1 => Some(e.into_bytes().into_iter().map(char::from).collect()),
+        },
+    }
+}
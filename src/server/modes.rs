@@ -0,0 +1,350 @@
+use anyhow::{Result, bail};
+
+use super::{USER_MODES, client::Client, send_numeric, send_to_channel};
+use crate::{
+    Command, CommandKind,
+    errors::IrcError,
+    replies::Reply,
+    state::{ModeChange, Rank, State, casemap, normalize_mask, to_unix_seconds},
+};
+
+/// Channel modes that are simply on or off, +k and +l take a parameter
+pub const FLAG_MODES: &str = "imnpst";
+/// Channel modes that add a mask to a list, or remove one
+pub const LIST_MODES: &str = "beI";
+
+/// MODE, for both channels and nicknames
+pub fn mode(
+    state: &mut State,
+    client: &mut Client,
+    target: String,
+    modes: Option<String>,
+    params: Vec<String>,
+) -> Result<()> {
+    match target.starts_with(['#', '&']) {
+        true => channel_mode(state, client, &target, modes, params),
+        false => user_mode(state, client, &target, modes),
+    }
+}
+
+fn channel_mode(
+    state: &mut State,
+    client: &mut Client,
+    name: &str,
+    modes: Option<String>,
+    params: Vec<String>,
+) -> Result<()> {
+    let channel = state.find_channel(name).ok_or(IrcError::NoSuchChannel {
+        channel: name.to_owned(),
+    })?;
+    let member = channel.is_member(client.target());
+    let Some(modes) = modes else {
+        // As with TOPIC, only members get to see the modes of a hidden channel
+        if !member && channel.is_hidden() {
+            bail!(IrcError::NotOnChannel {
+                channel: channel.name.clone(),
+            });
+        }
+        let reply = Reply::ChannelModeIs {
+            channel: channel.name.clone(),
+            modes: channel.mode_string(member),
+        };
+        return send_numeric(state, client, &reply);
+    };
+    let rank = channel
+        .membership(client.target())
+        .and_then(|membership| membership.highest());
+    let ranks = state.ranks();
+
+    // Everything is checked before anything is changed, each bad mode is reported and the
+    // rest still go ahead
+    let mut changes = Vec::new();
+    let mut lists = Vec::new();
+    let mut errors = Vec::new();
+    let mut params = params.into_iter();
+    let mut set = true;
+    for mode in modes.chars() {
+        let param = match (mode, set) {
+            ('+', _) | ('-', _) => {
+                set = mode == '+';
+                continue;
+            }
+            (mode, _) if LIST_MODES.contains(mode) => match params.next() {
+                Some(mask) => {
+                    let mask = normalize_mask(&mask);
+                    let adding = |change: &&ModeChange| change.set && change.mode == mode;
+                    let listed = channel.list(mode).map_or(0, Vec::len)
+                        + changes.iter().filter(adding).count();
+                    if set && listed >= state.max_list_entries() {
+                        errors.push(IrcError::BanListFull {
+                            channel: channel.name.clone(),
+                            char: mode,
+                        });
+                        continue;
+                    }
+                    Some(mask)
+                }
+                // Without a mask the list is shown instead, which anyone may ask for
+                None => {
+                    if !lists.contains(&mode) {
+                        lists.push(mode);
+                    }
+                    continue;
+                }
+            },
+            // Ranks are given to members by nickname
+            (mode, _) if ranks.iter().any(|rank| rank.mode() == mode) => match params.next() {
+                Some(nickname) => match state.find_client(&nickname) {
+                    Some(entry) if channel.is_member(&entry.nickname) => {
+                        Some(entry.nickname.clone())
+                    }
+                    Some(entry) => {
+                        errors.push(IrcError::UserNotInChannel {
+                            nickname: entry.nickname.clone(),
+                            channel: channel.name.clone(),
+                        });
+                        continue;
+                    }
+                    None => {
+                        errors.push(IrcError::NoSuchNick { nickname });
+                        continue;
+                    }
+                },
+                None => {
+                    errors.push(IrcError::NeedMoreParams {
+                        command: "MODE".to_owned(),
+                    });
+                    continue;
+                }
+            },
+            ('k', true) => match params.next().filter(|key| !key.is_empty()) {
+                Some(_) if channel.key.is_some() => {
+                    errors.push(IrcError::KeySet {
+                        channel: channel.name.clone(),
+                    });
+                    continue;
+                }
+                Some(key) => Some(key),
+                None => {
+                    errors.push(IrcError::NeedMoreParams {
+                        command: "MODE".to_owned(),
+                    });
+                    continue;
+                }
+            },
+            // The key has to be given to remove it, but it doesn't have to be right
+            ('k', false) => {
+                params.next();
+                None
+            }
+            ('l', true) => match params.next().filter(|limit| limit.parse::<usize>().is_ok()) {
+                Some(limit) => Some(limit),
+                None => {
+                    errors.push(IrcError::NeedMoreParams {
+                        command: "MODE".to_owned(),
+                    });
+                    continue;
+                }
+            },
+            ('l', false) => None,
+            (mode, _) if FLAG_MODES.contains(mode) => None,
+            (mode, _) => {
+                errors.push(IrcError::UnknownMode { char: mode });
+                continue;
+            }
+        };
+        changes.push(ModeChange { set, mode, param });
+    }
+    let requested = changes.len();
+    changes.retain(|change| rank.is_some_and(|rank| rank >= required_rank(change.mode)));
+    if changes.len() < requested {
+        errors.push(IrcError::ChanOPrivsNeeded {
+            channel: channel.name.clone(),
+        });
+    }
+    let name = channel.name.clone();
+    for error in errors {
+        send_numeric(state, client, &error)?;
+    }
+    for mode in lists {
+        send_list(state, client, &name, mode)?;
+    }
+
+    let mut applied = Vec::new();
+    let set_by = client.mask();
+    state.update_channel(&name, |channel| {
+        applied = changes
+            .into_iter()
+            .filter(|change| channel.apply_mode(change, &set_by))
+            .collect();
+    })?;
+    if applied.is_empty() {
+        return Ok(());
+    }
+
+    let (modes, params) = describe_changes(&applied);
+    let mode_change = Command {
+        prefix: Some(client.mask()),
+        kind: CommandKind::Mode {
+            target: name.clone(),
+            modes: Some(modes),
+            params,
+        },
+    };
+    if let Some(channel) = state.find_channel(&name) {
+        send_to_channel(state, channel, &mode_change.to_wire(), None);
+    }
+    Ok(())
+}
+
+/// The rank needed to make a change
+/// Giving or taking away a rank needs that rank, except that operators hand out halfop and
+/// halfops hand out voice, anything else needs halfop
+fn required_rank(mode: char) -> Rank {
+    match Rank::from_mode(mode) {
+        Some(Rank::Voice) | None => Rank::HalfOp,
+        Some(Rank::HalfOp) => Rank::Operator,
+        Some(rank) => rank,
+    }
+}
+
+/// RPL_BANLIST, RPL_EXCEPTLIST or RPL_INVITELIST for each entry, then the matching end
+fn send_list(state: &State, client: &mut Client, name: &str, mode: char) -> Result<()> {
+    let Some(channel) = state.find_channel(name) else {
+        return Ok(());
+    };
+    let channel_name = channel.name.clone();
+    for entry in channel.list(mode).into_iter().flatten() {
+        let (channel, mask, set_by) = (
+            channel_name.clone(),
+            entry.mask.clone(),
+            entry.set_by.clone(),
+        );
+        let set_at = to_unix_seconds(entry.set_at);
+        let reply = match mode {
+            'b' => Reply::BanList {
+                channel,
+                mask,
+                set_by,
+                set_at,
+            },
+            'e' => Reply::ExceptList {
+                channel,
+                mask,
+                set_by,
+                set_at,
+            },
+            _ => Reply::InviteList {
+                channel,
+                mask,
+                set_by,
+                set_at,
+            },
+        };
+        send_numeric(state, client, &reply)?;
+    }
+    let channel = channel_name;
+    let end = match mode {
+        'b' => Reply::EndOfBanList { channel },
+        'e' => Reply::EndOfExceptList { channel },
+        _ => Reply::EndOfInviteList { channel },
+    };
+    send_numeric(state, client, &end)
+}
+
+/// Renders changes the way MODE is broadcast, eg ("+kl-n", ["key", "10"])
+/// A removed key is never repeated back
+fn describe_changes(changes: &[ModeChange]) -> (String, Vec<String>) {
+    let mut modes = String::new();
+    let mut params = Vec::new();
+    let mut sign = None;
+    for change in changes {
+        if sign != Some(change.set) {
+            modes.push(if change.set { '+' } else { '-' });
+            sign = Some(change.set);
+        }
+        modes.push(change.mode);
+        match (&change.param, change.mode) {
+            (Some(param), _) => params.push(param.clone()),
+            (None, 'k') => params.push("*".to_owned()),
+            (None, _) => (),
+        }
+    }
+    (modes, params)
+}
+
+/// User modes a client may set and unset on itself
+/// +o can only be given up, and +r and +Z are only ever set by the server
+const USER_SETTABLE_MODES: &str = "isw";
+
+/// MODE for a nickname, which only ever works on the client's own modes
+fn user_mode(
+    state: &mut State,
+    client: &mut Client,
+    nickname: &str,
+    modes: Option<String>,
+) -> Result<()> {
+    if state.find_client(nickname).is_none() {
+        bail!(IrcError::NoSuchNick {
+            nickname: nickname.to_owned(),
+        });
+    }
+    if casemap(nickname) != casemap(client.target()) {
+        bail!(IrcError::UsersDontMatch);
+    }
+    let Some(modes) = modes else {
+        let reply = Reply::UModeIs {
+            modes: format!("+{}", client.modes.iter().collect::<String>()),
+        };
+        return send_numeric(state, client, &reply);
+    };
+
+    let mut applied = Vec::new();
+    let mut unknown = false;
+    let mut set = true;
+    for mode in modes.chars() {
+        let allowed = match mode {
+            '+' | '-' => {
+                set = mode == '+';
+                continue;
+            }
+            'o' => !set,
+            mode if USER_SETTABLE_MODES.contains(mode) => true,
+            // Asking for a mode only the server sets is quietly ignored
+            mode if USER_MODES.contains(mode) => false,
+            _ => {
+                unknown = true;
+                continue;
+            }
+        };
+        let changed = match set {
+            true => allowed && client.modes.insert(mode),
+            false => allowed && client.modes.remove(&mode),
+        };
+        if changed {
+            applied.push(ModeChange {
+                set,
+                mode,
+                param: None,
+            });
+        }
+    }
+
+    if !applied.is_empty() {
+        state.set_user_modes(client.target(), &client.modes);
+        let (modes, params) = describe_changes(&applied);
+        let mode_change = Command {
+            prefix: Some(client.mask()),
+            kind: CommandKind::Mode {
+                target: client.target().to_owned(),
+                modes: Some(modes),
+                params,
+            },
+        };
+        client.send_wire(&mode_change.to_wire())?;
+    }
+    if unknown {
+        bail!(IrcError::UModeUnkownFlag);
+    }
+    Ok(())
+}
@@ -0,0 +1,87 @@
+use anyhow::{Result, bail};
+
+use super::{
+    client::{Client, Outbound},
+    send_numeric,
+};
+use crate::{Command, CommandKind, errors::IrcError, replies::Reply, state::State};
+
+/// OPER, which makes a client an IRC operator if the name and password match one of the
+/// configured operators
+pub fn oper(state: &mut State, client: &mut Client, name: &str, password: &str) -> Result<()> {
+    if !state.is_oper_login(name, password) {
+        bail!(IrcError::PasswdMismatch);
+    }
+    if client.modes.insert('o') {
+        state.set_user_modes(client.target(), &client.modes);
+        let mode_change = Command {
+            prefix: Some(client.mask()),
+            kind: CommandKind::Mode {
+                target: client.target().to_owned(),
+                modes: Some("+o".to_owned()),
+                params: Vec::new(),
+            },
+        };
+        client.send_wire(&mode_change.to_wire())?;
+    }
+    send_numeric(state, client, &Reply::YoureOper)
+}
+
+/// FLUSH, which has the state file written straight away rather than on the next interval
+/// The operator is sent a NOTICE saying how it went once the write has finished
+pub fn flush(state: &State, client: &mut Client) -> Result<()> {
+    if !client.is_oper() {
+        bail!(IrcError::NoPrivileges);
+    }
+    let server_name = state.server_name().to_owned();
+    let nickname = client.target().to_owned();
+    let outbound = client.outbound().clone();
+    let requested = state.request_flush({
+        let (server_name, nickname) = (server_name.clone(), nickname.clone());
+        move |flushed| report_flush(&outbound, server_name, nickname, flushed)
+    });
+    if let Err(e) = requested {
+        report_flush(client.outbound(), server_name, nickname, Err(e));
+    }
+    Ok(())
+}
+
+/// REGCHAN, which registers a channel so that it is kept, along with its topic, modes and
+/// lists, once everyone has left and when the server restarts
+pub fn regchan(state: &mut State, client: &mut Client, name: &str) -> Result<()> {
+    if !client.is_oper() {
+        bail!(IrcError::NoPrivileges);
+    }
+    let Some(channel) = state.find_channel(name) else {
+        bail!(IrcError::NoSuchChannel {
+            channel: name.to_owned(),
+        });
+    };
+    let name = channel.name.clone();
+    if !channel.registered {
+        state.update_channel(&name, |channel| channel.registered = true)?;
+    }
+    let notice = Command {
+        prefix: Some(state.server_name().to_owned()),
+        kind: CommandKind::Notice {
+            message_targets: vec![client.target().to_owned()],
+            message_text: format!("Channel {name} is registered"),
+        },
+    };
+    client.send_wire(&notice.to_wire())
+}
+
+fn report_flush(outbound: &Outbound, server_name: String, nickname: String, flushed: Result<()>) {
+    let notice = Command {
+        prefix: Some(server_name),
+        kind: CommandKind::Notice {
+            message_targets: vec![nickname],
+            message_text: match flushed {
+                Ok(()) => "State file saved".to_owned(),
+                Err(e) => format!("Failed to save the state file: {e:#}"),
+            },
+        },
+    };
+    // The operator may have gone by the time the write finishes
+    let _ = outbound.send_wire(&notice.to_wire());
+}
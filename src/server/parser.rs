@@ -1,11 +1,14 @@
 use std::cell::LazyCell;
-use std::str::Split;
 
 use anyhow::{Context, Result, anyhow, bail};
 use regex::{Captures, Regex};
 use thiserror::Error;
 
-use crate::{Command, CommandKind};
+use crate::{Command, CommandKind, errors::IrcError};
+
+/// The maximum number of parameters a message may have, the 15th parameter swallows the
+/// rest of the line as if it were a trailing parameter
+pub const MAX_PARAMS: usize = 15;
 
 #[derive(Error, Debug)]
 pub enum ParseError {
@@ -13,35 +16,137 @@ pub enum ParseError {
     UnrecognisedCommand(String),
     #[error("Malformed/Empty Command: [{0}]")]
     MalformedCommand(String),
+    #[error("Not enough parameters for command: [{0}]")]
+    NeedMoreParams(String),
+    #[error("No nickname given")]
+    NoNicknameGiven,
+    #[error("Erroneous nickname: [{0}]")]
+    ErroneusNickname(String),
+    #[error("Invalid channel name: [{0}]")]
+    NoSuchChannel(String),
+    #[error("No origin specified")]
+    NoOrigin,
+    #[error("No recipient given for command: [{0}]")]
+    NoRecipient(String),
+    #[error("No text to send")]
+    NoTextToSend,
 }
 
-fn join_str_iter<'a>(iter: impl Iterator<Item = &'a str>) -> String {
-    iter.fold(String::new(), |a, b| a + b + " ")
-        .trim_end()
-        .to_owned()
+impl ParseError {
+    /// The numeric error that should be sent back to the client, if any
+    /// Malformed lines have no numeric and are silently ignored, as per RFC 2812
+    pub fn to_irc_error(&self) -> Option<IrcError> {
+        match self {
+            ParseError::UnrecognisedCommand(command) => Some(IrcError::UnknownCommand {
+                command: command.clone(),
+            }),
+            ParseError::MalformedCommand(_) => None,
+            ParseError::NeedMoreParams(command) => Some(IrcError::NeedMoreParams {
+                command: command.clone(),
+            }),
+            ParseError::NoNicknameGiven => Some(IrcError::NoNickNameGiven),
+            ParseError::ErroneusNickname(nickname) => Some(IrcError::ErroneusNickname {
+                nickname: nickname.clone(),
+            }),
+            ParseError::NoSuchChannel(channel) => Some(IrcError::NoSuchChannel {
+                channel: channel.clone(),
+            }),
+            ParseError::NoOrigin => Some(IrcError::NoOrigin),
+            ParseError::NoRecipient(command) => Some(IrcError::NoRecipient {
+                command: command.clone(),
+            }),
+            ParseError::NoTextToSend => Some(IrcError::NoTextToSend),
+        }
+    }
+}
+
+/// A message that has been split according to the RFC 2812 message grammar, but whose
+/// command and parameters haven't been interpreted yet
+#[derive(Debug, PartialEq, Clone)]
+pub struct RawMessage {
+    pub prefix: Option<String>,
+    /// Letters are uppercased, so "privmsg" and "PRIVMSG" are the same command
+    pub command: String,
+    /// The trailing parameter (if any) is the last element, without its leading ':'
+    pub params: Vec<String>,
 }
 
-// [ ":" prefix SPACE ] command [ params ] crlf
 /// Parses an IRC command according to RFC 2812
 /// Errors when the command is malformed or unrecognised
 /// You can assume that any text-based limitations (allowed chars, length, etc) are assured by this function
 pub fn try_parse_from_line(line: &mut str) -> Result<Command> {
-    match line.chars().next() {
-        Some(':') => {
-            let mut iter = line.split(" ");
-            Ok(Command {
-                prefix: Some(
-                    parse_prefix(iter.next().ok_or(anyhow!("Impossibly bad prefix"))?)
-                        .context("Bad Command: {line}")?,
-                ),
-                kind: parse_command(iter).context(format!("\nWhole Line: {line}"))?,
-            })
+    let raw = parse_raw_message(line).context(format!("\nWhole Line: {line}"))?;
+    raw_to_command(raw).context(format!("\nWhole Line: {line}"))
+}
+
+// message    =  [ ":" prefix SPACE ] command [ params ] crlf
+// params     =  *14( SPACE middle ) [ SPACE ":" trailing ]
+//            =/ 14( SPACE middle ) [ SPACE [ ":" ] trailing ]
+// nospcrlfcl =  %x01-09 / %x0B-0C / %x0E-1F / %x21-39 / %x3B-FF
+//                 ; any octet except NUL, CR, LF, " " and ":"
+// middle     =  nospcrlfcl *( ":" / nospcrlfcl )
+// trailing   =  *( ":" / " " / nospcrlfcl )
+/// Splits a line into prefix, command and parameters, a single trailing CRLF is ignored
+/// Runs of spaces between parameters are treated as a single separator, as in RFC 1459
+pub fn parse_raw_message(line: &str) -> Result<RawMessage> {
+    let line = line
+        .strip_suffix("\r\n")
+        .or(line.strip_suffix('\n'))
+        .unwrap_or(line);
+    if line.contains(['\0', '\r', '\n']) {
+        bail!(ParseError::MalformedCommand(line.to_owned()));
+    }
+
+    let mut rest = line;
+    let prefix = match rest.strip_prefix(':') {
+        Some(after_colon) => {
+            let (prefix, after_prefix) = after_colon
+                .split_once(' ')
+                .ok_or(ParseError::MalformedCommand(line.to_owned()))?;
+            rest = after_prefix;
+            Some(parse_prefix(prefix).context(format!("Bad Command: {line}"))?)
         }
-        Some(_c) => Ok(Command {
-            prefix: None,
-            kind: parse_command(line.split(" ")).context(format!("\nWhole Line: {line}"))?,
-        }),
-        None => bail!(ParseError::MalformedCommand(line.to_owned())),
+        None => None,
+    };
+
+    rest = rest.trim_start_matches(' ');
+    let (command, mut rest) = rest.split_once(' ').unwrap_or((rest, ""));
+    let command = parse_command_name(command)?;
+
+    let mut params = Vec::new();
+    loop {
+        rest = rest.trim_start_matches(' ');
+        if rest.is_empty() {
+            break;
+        }
+        if let Some(trailing) = rest.strip_prefix(':') {
+            params.push(trailing.to_owned());
+            break;
+        }
+        if params.len() == MAX_PARAMS - 1 {
+            params.push(rest.to_owned());
+            break;
+        }
+        let (middle, after_middle) = rest.split_once(' ').unwrap_or((rest, ""));
+        params.push(middle.to_owned());
+        rest = after_middle;
+    }
+
+    Ok(RawMessage {
+        prefix,
+        command,
+        params,
+    })
+}
+
+// command    =  1*letter / 3digit
+fn parse_command_name(command: &str) -> Result<String> {
+    let is_word = !command.is_empty() && command.chars().all(|c| c.is_ascii_alphabetic());
+    let is_numeric = command.len() == 3 && command.chars().all(|c| c.is_ascii_digit());
+    if is_word || is_numeric {
+        Ok(command.to_ascii_uppercase())
+    } else {
+        bail!(ParseError::MalformedCommand(command.to_owned()))
     }
 }
 
@@ -82,9 +187,8 @@ pub fn regex_capture(
 }
 
 // servername / ( nickname [ [ "!" user ] "@" host ] )
-// (preceded by a ':' that we know is present from try_parse_from_line)
+// (the leading ':' has already been stripped by parse_raw_message)
 fn parse_prefix(prefix: &str) -> Result<String> {
-    let prefix = &prefix[1..];
     if let Ok(pre) = parse_servername(prefix) {
         Ok(pre)
     } else if let Ok(pre) = parse_nickname_etc_for_prefix(prefix) {
@@ -125,28 +229,40 @@ fn parse_servername(word: &str) -> Result<String> {
     })
 }
 
-// 1*letter / 3digit
-// 1 or more letters / 3 digits
-fn parse_command(mut line: Split<'_, &str>) -> Result<CommandKind> {
-    let word = match line.next() {
-        Some(word) => word,
-        None => bail!(ParseError::MalformedCommand(join_str_iter(&mut line))),
-    }
-    .to_lowercase();
-
-    match word.as_str() {
-        "join" => parse_join(line),
-        "nick" => parse_nick(line),
-        "user" => parse_user(&join_str_iter(line)),
-        "ping" => parse_ping(&join_str_iter(line)),
-        "privmsg" => parse_privmsg(&join_str_iter(line)),
-        "quit" => parse_quit(&join_str_iter(line)),
-        x => bail!(ParseError::UnrecognisedCommand(format!(
-            "{} {}",
-            x,
-            join_str_iter(&mut line)
-        ))),
-    }
+/// Interprets the parameters of a raw message as a specific command
+pub fn raw_to_command(raw: RawMessage) -> Result<Command> {
+    let kind = match raw.command.as_str() {
+        "PASS" => parse_pass(raw.params),
+        "JOIN" => parse_join(raw.params),
+        "PART" => parse_part(raw.params),
+        "NICK" => parse_nick(raw.params),
+        "USER" => parse_user(raw.params),
+        "PING" => parse_ping(raw.params),
+        "PONG" => parse_pong(raw.params),
+        "PRIVMSG" => parse_privmsg(raw.params),
+        "NOTICE" => parse_notice(raw.params),
+        "QUIT" => parse_quit(raw.params),
+        "MODE" => parse_mode(raw.params),
+        "KICK" => parse_kick(raw.params),
+        "TOPIC" => parse_topic(raw.params),
+        "WHO" => parse_who(raw.params),
+        "NAMES" => parse_names(raw.params),
+        "OPER" => parse_oper(raw.params),
+        "FLUSH" => parse_flush(raw.params),
+        "REGCHAN" => parse_regchan(raw.params),
+        _ => bail!(ParseError::UnrecognisedCommand(raw.command)),
+    }?;
+    Ok(Command {
+        prefix: raw.prefix,
+        kind,
+    })
+}
+
+/// Drops any parameters past the first `max`, which other servers ignore too (so that eg
+/// "QUIT leaving now" quits with "leaving")
+fn drop_surplus_params(mut params: Vec<String>, max: usize) -> Vec<String> {
+    params.truncate(max);
+    params
 }
 
 fn parse_channel(channel: &str) -> Result<String> {
@@ -157,102 +273,310 @@ fn parse_channel(channel: &str) -> Result<String> {
     })
 }
 
+// Parameters: <password>
+fn parse_pass(params: Vec<String>) -> Result<CommandKind> {
+    let params = drop_surplus_params(params, 1);
+    let password = params
+        .into_iter()
+        .next()
+        .filter(|p| !p.is_empty())
+        .ok_or(ParseError::NeedMoreParams("PASS".to_owned()))?;
+
+    Ok(CommandKind::Pass { password })
+}
+
 // <channel>{,<channel>} [<key>{,<key>}]
 // channel = ('#' | '&') <chstring>
 // chstring = <any 8bit code except SPACE, BELL, NUL, CR, LF and comma (',')>
-fn parse_join(mut line: Split<'_, &str>) -> Result<CommandKind> {
-    let channels = match line.next() {
-        Some("0") => match line.next() {
-            Some(_p) => return Err(ParseError::MalformedCommand(join_str_iter(&mut line)).into()),
-            None => {
-                return Ok(CommandKind::Join {
-                    channels: vec!["0".to_owned()],
-                    keys: None,
-                });
-            }
-        },
+fn parse_join(params: Vec<String>) -> Result<CommandKind> {
+    let mut params = drop_surplus_params(params, 2).into_iter();
+    let channels = match params.next() {
+        Some(zero) if zero == "0" => {
+            return Ok(CommandKind::Join {
+                channels: vec!["0".to_owned()],
+                keys: None,
+            });
+        }
         Some(channels) => channels
             .split(",")
-            .map(parse_channel)
-            .collect::<Result<Vec<String>>>()
-            .context("Bad command: {line}")?,
-        None => bail!(ParseError::UnrecognisedCommand(join_str_iter(&mut line))),
+            .map(|channel| {
+                parse_channel(channel)
+                    .map_err(|_e| ParseError::NoSuchChannel(channel.to_owned()).into())
+            })
+            .collect::<Result<Vec<String>>>()?,
+        None => bail!(ParseError::NeedMoreParams("JOIN".to_owned())),
     };
 
-    let keys = line
+    let keys = params
         .next()
         .map(|keys| keys.split(",").map(|s| s.to_owned()).collect());
 
-    match line.next() {
-        Some(_p) => Err(ParseError::MalformedCommand(join_str_iter(&mut line)).into()),
-        None => Ok(CommandKind::Join { channels, keys }),
-    }
+    Ok(CommandKind::Join { channels, keys })
+}
+
+// Parameters: <channel> *( "," <channel> ) [ <Part Message> ]
+fn parse_part(params: Vec<String>) -> Result<CommandKind> {
+    let params = drop_surplus_params(params, 2);
+    let mut params = params.into_iter();
+    let channels = params
+        .next()
+        .ok_or(ParseError::NeedMoreParams("PART".to_owned()))?
+        .split(",")
+        .map(|channel| {
+            parse_channel(channel)
+                .map_err(|_e| ParseError::NoSuchChannel(channel.to_owned()).into())
+        })
+        .collect::<Result<Vec<String>>>()?;
+
+    Ok(CommandKind::Part {
+        channels,
+        part_message: params.next(),
+    })
 }
 
 // Parameters: <nickname>
-fn parse_nick(mut line: Split<'_, &str>) -> Result<CommandKind> {
-    let caps = regex_capture(
-        line.next()
-            .ok_or(ParseError::UnrecognisedCommand(join_str_iter(&mut line)))?,
-        || {
-            Regex::new(
-                r"^(?<nickname>[A-Za-z\x5B-\x60\x7B-\x7D][\-A-Za-z0-9\x5B-\x60\x7B-\x7D]{0,8})$",
-            )
-        },
-    )?;
+fn parse_nick(params: Vec<String>) -> Result<CommandKind> {
+    let params = drop_surplus_params(params, 1);
+    let nickname = params
+        .into_iter()
+        .next()
+        .filter(|n| !n.is_empty())
+        .ok_or(ParseError::NoNicknameGiven)?;
+    let caps = regex_capture(&nickname, || {
+        Regex::new(r"^(?<nickname>[A-Za-z\x5B-\x60\x7B-\x7D][\-A-Za-z0-9\x5B-\x60\x7B-\x7D]{0,8})$")
+    })
+    .map_err(|_e| ParseError::ErroneusNickname(nickname.clone()))?;
 
     let nickname = caps
         .name("nickname")
-        .ok_or(ParseError::MalformedCommand(join_str_iter(&mut line)))?
+        .ok_or(ParseError::ErroneusNickname(nickname.clone()))?
         .as_str();
 
-    match line.next() {
-        Some(_p) => Err(ParseError::MalformedCommand(join_str_iter(&mut line)).into()),
-        None => Ok(CommandKind::Nick {
-            nickname: nickname.to_owned(),
-        }),
-    }
+    Ok(CommandKind::Nick {
+        nickname: nickname.to_owned(),
+    })
 }
 
 // Parameters: <user> <mode> <unused> <realname>
-fn parse_user(line: &str) -> Result<CommandKind> {
-    let caps = regex_capture(line, || {
-        Regex::new(
-            r"^(?x)
-        (?<user>[\x01-\x07\x08-\x09\x0B-\x0C\x0E-\x1F\x21-\x2B\x2D-\x39\x3B-\xFF]+)
-        \s(?<mode>[0-9])\s\*\s:
-        (?<realname>[\s\x01-\x07\x08-\x09\x0B-\x0C\x0E-\x1F\x21-\x2B\x2D-\x39\x3B-\xFF]+)$",
-        )
-    })?;
-    let user_name = caps
-        .name("user")
-        .ok_or(ParseError::MalformedCommand(line.to_owned()))?
-        .as_str();
-    let mode = caps
-        .name("mode")
-        .ok_or(ParseError::MalformedCommand(line.to_owned()))?
-        .as_str()
-        .parse::<u8>()?;
-    let real_name = caps
-        .name("realname")
-        .ok_or(ParseError::MalformedCommand(line.to_owned()))?
-        .as_str();
+fn parse_user(params: Vec<String>) -> Result<CommandKind> {
+    let params = drop_surplus_params(params, 4);
+    let [user_name, mode, _unused, real_name]: [String; 4] = params
+        .try_into()
+        .map_err(|_p| ParseError::NeedMoreParams("USER".to_owned()))?;
+
+    regex_match(&user_name, || {
+        Regex::new(r"^[\x01-\x07\x08-\x09\x0B-\x0C\x0E-\x1F\x21-\x2B\x2D-\x39\x3B-\xFF]+$")
+    })
+    .map_err(|_e| ParseError::NeedMoreParams("USER".to_owned()))?;
+    // A bitmask, of which only the lowest four bits are defined
+    let mode = regex_match(&mode, || Regex::new(r"^[0-9]{1,2}$"))
+        .ok()
+        .and_then(|mode| mode.parse::<u8>().ok())
+        .filter(|mode| *mode < 16)
+        .ok_or(ParseError::NeedMoreParams("USER".to_owned()))?;
+    if real_name.is_empty() {
+        bail!(ParseError::NeedMoreParams("USER".to_owned()));
+    }
 
     Ok(CommandKind::User {
-        user_name: user_name.to_owned(),
+        user_name,
         mode,
-        real_name: real_name.to_owned(),
+        real_name,
     })
 }
 
-fn parse_ping(line: &str) -> Result<CommandKind> {
-    todo!()
+// Parameters: <server1> [ <server2> ]
+fn parse_ping(params: Vec<String>) -> Result<CommandKind> {
+    let params = drop_surplus_params(params, 2);
+    let mut params = params.into_iter();
+    let source_server = params
+        .next()
+        .filter(|s| !s.is_empty())
+        .ok_or(ParseError::NoOrigin)?;
+
+    Ok(CommandKind::Ping {
+        source_server,
+        target_server: params.next(),
+    })
+}
+
+// Parameters: <server> [ <server2> ]
+fn parse_pong(params: Vec<String>) -> Result<CommandKind> {
+    let params = drop_surplus_params(params, 2);
+    let mut params = params.into_iter();
+    let source_server = params
+        .next()
+        .filter(|s| !s.is_empty())
+        .ok_or(ParseError::NoOrigin)?;
+
+    Ok(CommandKind::Pong {
+        source_server,
+        target_server: params.next(),
+    })
+}
+
+// Parameters: <msgtarget> <text to be sent>
+// msgtarget = msgto *( "," msgto )
+fn parse_message_params(params: Vec<String>, command: &str) -> Result<(Vec<String>, String)> {
+    let params = drop_surplus_params(params, 2);
+    let mut params = params.into_iter();
+    let message_targets: Vec<String> = params
+        .next()
+        .map(|targets| {
+            targets
+                .split(",")
+                .filter(|t| !t.is_empty())
+                .map(|t| t.to_owned())
+                .collect()
+        })
+        .unwrap_or_default();
+    if message_targets.is_empty() {
+        bail!(ParseError::NoRecipient(command.to_owned()));
+    }
+    let message_text = params
+        .next()
+        .filter(|t| !t.is_empty())
+        .ok_or(ParseError::NoTextToSend)?;
+
+    Ok((message_targets, message_text))
+}
+
+fn parse_privmsg(params: Vec<String>) -> Result<CommandKind> {
+    let (message_targets, message_text) = parse_message_params(params, "PRIVMSG")?;
+    Ok(CommandKind::PrivMsg {
+        message_targets,
+        message_text,
+    })
+}
+
+// A NOTICE must never generate an automatic reply, so any problem with it is just a
+// malformed line to be ignored
+fn parse_notice(params: Vec<String>) -> Result<CommandKind> {
+    let (message_targets, message_text) = parse_message_params(params, "NOTICE")
+        .map_err(|e| ParseError::MalformedCommand(e.to_string()))?;
+    Ok(CommandKind::Notice {
+        message_targets,
+        message_text,
+    })
+}
+
+// Parameters: [ <Quit Message> ]
+fn parse_quit(params: Vec<String>) -> Result<CommandKind> {
+    let params = drop_surplus_params(params, 1);
+    Ok(CommandKind::Quit {
+        quit_message: params.into_iter().next(),
+    })
+}
+
+// Parameters: <channel> *( ( "-" / "+" ) *<modes> *<modeparams> )
+//             <nickname> *( ( "+" / "-" ) *( "i" / "w" / "o" / "O" / "r" ) )
+// Which modes take a parameter is up to the server, so the changes are left for it to read
+fn parse_mode(params: Vec<String>) -> Result<CommandKind> {
+    let mut params = params.into_iter();
+    let target = params
+        .next()
+        .filter(|t| !t.is_empty())
+        .ok_or(ParseError::NeedMoreParams("MODE".to_owned()))?;
+    Ok(CommandKind::Mode {
+        target,
+        modes: params.next(),
+        params: params.collect(),
+    })
+}
+
+// Parameters: <channel> *( "," <channel> ) <user> *( "," <user> ) [<comment>]
+// There must be either one channel, or as many channels as users
+fn parse_kick(params: Vec<String>) -> Result<CommandKind> {
+    let params = drop_surplus_params(params, 3);
+    let mut params = params.into_iter();
+    let (Some(channels), Some(users)) = (params.next(), params.next()) else {
+        bail!(ParseError::NeedMoreParams("KICK".to_owned()));
+    };
+    let channels = channels
+        .split(",")
+        .map(|channel| {
+            parse_channel(channel)
+                .map_err(|_e| ParseError::NoSuchChannel(channel.to_owned()).into())
+        })
+        .collect::<Result<Vec<String>>>()?;
+    let users: Vec<String> = users
+        .split(",")
+        .filter(|user| !user.is_empty())
+        .map(|user| user.to_owned())
+        .collect();
+    if users.is_empty() || (channels.len() != 1 && channels.len() != users.len()) {
+        bail!(ParseError::NeedMoreParams("KICK".to_owned()));
+    }
+
+    Ok(CommandKind::Kick {
+        channels,
+        users,
+        comment: params.next(),
+    })
+}
+
+// Parameters: <channel> [ <topic> ]
+fn parse_topic(params: Vec<String>) -> Result<CommandKind> {
+    let params = drop_surplus_params(params, 2);
+    let mut params = params.into_iter();
+    let channel = params
+        .next()
+        .ok_or(ParseError::NeedMoreParams("TOPIC".to_owned()))?;
+    let channel =
+        parse_channel(&channel).map_err(|_e| ParseError::NoSuchChannel(channel.clone()))?;
+
+    Ok(CommandKind::Topic {
+        channel,
+        topic: params.next(),
+    })
+}
+
+// Parameters: [ <mask> [ "o" ] ]
+fn parse_who(params: Vec<String>) -> Result<CommandKind> {
+    let params = drop_surplus_params(params, 2);
+    let mut params = params.into_iter();
+    let mask = params.next();
+    // Any flag other than "o" is one this server doesn't know, and is ignored
+    let operators = params.next().is_some_and(|o| o == "o");
+    Ok(CommandKind::Who { mask, operators })
+}
+
+// Parameters: [ <channel> *( "," <channel> ) [ <target> ] ]
+// Asking another server isn't supported, so any target is ignored
+fn parse_names(params: Vec<String>) -> Result<CommandKind> {
+    let channels = params
+        .into_iter()
+        .next()
+        .map(|channels| {
+            channels
+                .split(",")
+                .filter(|channel| !channel.is_empty())
+                .map(|channel| channel.to_owned())
+                .collect()
+        })
+        .unwrap_or_default();
+    Ok(CommandKind::Names { channels })
+}
+
+// Parameters: <name> <password>
+fn parse_oper(params: Vec<String>) -> Result<CommandKind> {
+    let params = drop_surplus_params(params, 2);
+    let [name, password]: [String; 2] = params
+        .try_into()
+        .map_err(|_p| ParseError::NeedMoreParams("OPER".to_owned()))?;
+    Ok(CommandKind::Oper { name, password })
 }
 
-fn parse_privmsg(line: &str) -> Result<CommandKind> {
-    todo!()
+// Parameters: none
+fn parse_flush(_params: Vec<String>) -> Result<CommandKind> {
+    Ok(CommandKind::Flush)
 }
 
-fn parse_quit(line: &str) -> Result<CommandKind> {
-    todo!()
+// Parameters: <channel>
+fn parse_regchan(params: Vec<String>) -> Result<CommandKind> {
+    let params = drop_surplus_params(params, 1);
+    let [channel]: [String; 1] = params
+        .try_into()
+        .map_err(|_p| ParseError::NeedMoreParams("REGCHAN".to_owned()))?;
+    Ok(CommandKind::RegChan { channel })
 }
@@ -0,0 +1,54 @@
+use std::net::IpAddr;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpStream, UnixStream};
+use tokio_rustls::server::TlsStream;
+
+/// Anything a client can be connected over
+pub trait Transport: AsyncRead + AsyncWrite + Send + Unpin {}
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Transport for T {}
+
+/// A client connection, from any kind of listener
+pub struct Stream {
+    pub transport: Box<dyn Transport>,
+    /// The host the client is connecting from, Unix socket clients are always local
+    pub peer_host: String,
+    /// True if the connection is encrypted
+    pub secure: bool,
+}
+
+impl Stream {
+    pub fn tcp(stream: TcpStream) -> std::io::Result<Self> {
+        Ok(Stream {
+            peer_host: peer_host(stream.peer_addr()?.ip()),
+            transport: Box::new(stream),
+            secure: false,
+        })
+    }
+
+    pub fn tls(stream: TlsStream<TcpStream>) -> std::io::Result<Self> {
+        Ok(Stream {
+            peer_host: peer_host(stream.get_ref().0.peer_addr()?.ip()),
+            transport: Box::new(stream),
+            secure: true,
+        })
+    }
+
+    pub fn unix(stream: UnixStream) -> Self {
+        Stream {
+            transport: Box::new(stream),
+            peer_host: "localhost".to_owned(),
+            secure: false,
+        }
+    }
+}
+
+/// The host a client connecting from `ip` is shown as
+/// IPv6 addresses like ::1 get a 0 in front, as a host starting with a colon would be read as
+/// the trailing parameter of any line it appears in the middle of
+pub fn peer_host(ip: IpAddr) -> String {
+    let host = ip.to_string();
+    match host.starts_with(':') {
+        true => format!("0{host}"),
+        false => host,
+    }
+}
@@ -1,4 +1,8 @@
 use crate::server::*;
+use crate::state::{self, casemap};
+use parser::{MAX_PARAMS, RawMessage};
+use std::io::{BufRead, BufReader};
+use std::net::TcpStream;
 
 #[test]
 fn check_case_insensitivity() {
@@ -113,17 +117,53 @@ fn parse_join_no_params() {
 }
 
 #[test]
-#[should_panic]
 fn parse_join_too_many_params() {
     let mut line = "JOIN #foo,#bar fubar,foobar foooobar".to_owned();
-    try_parse_from_line(&mut line).unwrap();
+    assert_eq!(
+        try_parse_from_line(&mut line).unwrap().kind,
+        CommandKind::Join {
+            channels: vec!["#foo".to_string(), "#bar".to_string()],
+            keys: Some(vec!["fubar".to_string(), "foobar".to_string()]),
+        }
+    );
 }
 
 #[test]
-#[should_panic]
 fn parse_join_0_more_args() {
     let mut line = "JOIN 0 fubar,foobar".to_owned();
-    try_parse_from_line(&mut line).unwrap();
+    assert_eq!(
+        try_parse_from_line(&mut line).unwrap().kind,
+        CommandKind::Join {
+            channels: vec!["0".to_string()],
+            keys: None,
+        }
+    );
+}
+
+#[test]
+fn surplus_params_are_ignored() {
+    let parse = |line: &str| try_parse_from_line(&mut line.to_owned()).unwrap().kind;
+    assert_eq!(
+        parse("QUIT leaving now"),
+        CommandKind::Quit {
+            quit_message: Some("leaving".to_owned()),
+        }
+    );
+    assert_eq!(
+        parse("PRIVMSG bob hello world"),
+        CommandKind::PrivMsg {
+            message_targets: vec!["bob".to_owned()],
+            message_text: "hello".to_owned(),
+        }
+    );
+    assert_eq!(
+        parse("PART #test bye now"),
+        CommandKind::Part {
+            channels: vec!["#test".to_owned()],
+            part_message: Some("bye".to_owned()),
+        }
+    );
+    assert_eq!(parse("FLUSH now"), CommandKind::Flush);
 }
 
 #[test]
@@ -198,7 +238,7 @@ fn parse_user() {
 #[test]
 #[should_panic]
 fn parse_user_bad_mode() {
-    let mut line = "USER guest 11 * :Amity Blight".to_owned();
+    let mut line = "USER guest 16 * :Amity Blight".to_owned();
     try_parse_from_line(&mut line).unwrap();
 }
 
@@ -208,3 +248,3076 @@ fn parse_user_no_unused() {
     let mut line = "USER guest 0 :Amity Blight".to_owned();
     try_parse_from_line(&mut line).unwrap();
 }
+
+/// A state with nothing saved, along with its file to be deleted once the test is done
+fn test_state() -> (State, TestPath) {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let path = state_path(&format!(
+        "state-{}",
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    std::fs::File::create(&*path).unwrap();
+    (build_state(&path).unwrap(), path)
+}
+
+/// Shared by every test, so anything spawned outlives the test that spawned it
+static RUNTIME: std::sync::LazyLock<tokio::runtime::Runtime> =
+    std::sync::LazyLock::new(|| tokio::runtime::Runtime::new().unwrap());
+
+/// Returns a server-side client along with the reading half of its peer
+fn test_client() -> (Client, BufReader<TcpStream>) {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    // fail rather than hang if a test expects a reply that never comes
+    peer.set_read_timeout(Some(std::time::Duration::from_secs(5)))
+        .unwrap();
+    let (stream, _) = listener.accept().unwrap();
+    stream.set_nonblocking(true).unwrap();
+
+    let (outbound, queue) = client::Outbound::new(state::DEFAULT_SENDQ);
+    let _guard = RUNTIME.enter();
+    let stream = tokio::net::TcpStream::from_std(stream).unwrap();
+    RUNTIME.spawn(client::write_queued(queue, stream));
+    (
+        Client::new(
+            outbound,
+            "127.0.0.1".to_owned(),
+            false,
+            ListenerKind::Client,
+        ),
+        BufReader::new(peer),
+    )
+}
+
+fn run(state: &mut State, client: &mut Client, line: &str) -> Result<()> {
+    apply_command(
+        state,
+        client,
+        try_parse_from_line(&mut line.to_owned()).unwrap(),
+    )
+    .map(|_flow| ())
+}
+
+fn read_reply(reader: &mut impl BufRead) -> String {
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    line
+}
+
+#[test]
+fn registration_sends_welcome_burst() {
+    let (mut state, _path) = test_state();
+    let (mut client, mut reader) = test_client();
+
+    run(&mut state, &mut client, "PASS secret").unwrap();
+    run(&mut state, &mut client, "USER guest 0 * :Amity Blight").unwrap();
+    assert!(!client.is_registered());
+    run(&mut state, &mut client, "NICK amity").unwrap();
+    assert!(client.is_registered());
+
+    for numeric in ["001", "002", "003", "004", "005"] {
+        let reply = read_reply(&mut reader);
+        assert!(reply.starts_with(&format!(":irc.localhost {numeric} amity ")));
+        assert!(reply.ends_with("\r\n"));
+        match numeric {
+            "004" => assert!(reply.ends_with(" beIiklmnopstv\r\n"), "{reply}"),
+            "005" => assert!(reply.contains(" PREFIX=(ov)@+ "), "{reply}"),
+            _ => (),
+        }
+    }
+}
+
+#[test]
+fn commands_before_registration_are_rejected() {
+    let (mut state, _path) = test_state();
+    let (mut client, _reader) = test_client();
+
+    run(&mut state, &mut client, "NICK amity").unwrap();
+    let err = run(&mut state, &mut client, "JOIN #foo").unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<IrcError>().unwrap().numeric_code(),
+        IrcError::NotRegistered.numeric_code()
+    );
+}
+
+#[test]
+fn reregistration_is_rejected() {
+    let (mut state, _path) = test_state();
+    let (mut client, _reader) = test_client();
+
+    run(&mut state, &mut client, "NICK amity").unwrap();
+    run(&mut state, &mut client, "USER guest 0 * :Amity Blight").unwrap();
+    for line in ["USER guest 0 * :Amity Blight", "PASS secret"] {
+        let err = run(&mut state, &mut client, line).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<IrcError>().unwrap().numeric_code(),
+            IrcError::AlreadyRegistered.numeric_code()
+        );
+    }
+}
+
+#[test]
+fn numerics_to_wire() {
+    let error = IrcError::NicknameInUse {
+        nickname: "nick".to_owned(),
+    };
+    assert_eq!(
+        error.to_wire("server", "*"),
+        ":server 433 * nick :Nickname is already in use\r\n"
+    );
+
+    let reply = Reply::Welcome {
+        nickname: "amity".to_owned(),
+        user: "guest".to_owned(),
+        host: "127.0.0.1".to_owned(),
+    };
+    assert_eq!(
+        reply.to_wire("server", "amity"),
+        ":server 001 amity :Welcome to the Internet Relay Network amity!guest@127.0.0.1\r\n"
+    );
+
+    let reply = Reply::NamReply {
+        symbol: '=',
+        channel: "#foo".to_owned(),
+        nicknames: vec!["@amity".to_owned(), "luz".to_owned()],
+    };
+    assert_eq!(
+        reply.to_wire("server", "amity"),
+        ":server 353 amity = #foo :@amity luz\r\n"
+    );
+}
+
+fn parse_error_code(line: &str) -> Option<i16> {
+    try_parse_from_line(&mut line.to_owned())
+        .unwrap_err()
+        .downcast_ref::<ParseError>()
+        .unwrap()
+        .to_irc_error()
+        .map(|e| e.numeric_code())
+}
+
+#[test]
+fn parse_errors_map_to_numerics() {
+    assert_eq!(parse_error_code("FOO bar"), Some(421));
+    assert_eq!(parse_error_code("JOIN"), Some(461));
+    assert_eq!(parse_error_code("JOIN foo"), Some(403));
+    assert_eq!(parse_error_code("NICK"), Some(431));
+    assert_eq!(parse_error_code("NICK x.23"), Some(432));
+    assert_eq!(parse_error_code("USER guest 0 :Amity Blight"), Some(461));
+}
+
+fn raw(line: &str) -> RawMessage {
+    parser::parse_raw_message(line).unwrap()
+}
+
+#[test]
+fn raw_message_trailing() {
+    assert_eq!(
+        raw("PRIVMSG #c :hello world"),
+        RawMessage {
+            prefix: None,
+            command: "PRIVMSG".to_owned(),
+            params: vec!["#c".to_owned(), "hello world".to_owned()],
+        }
+    );
+    // colons are only special at the start of a parameter
+    assert_eq!(raw("PRIVMSG #c a:b").params, vec!["#c", "a:b"]);
+    assert_eq!(raw("PRIVMSG #c ::)").params, vec!["#c", ":)"]);
+    // an empty trailing parameter is still a parameter
+    assert_eq!(raw("TOPIC #c :").params, vec!["#c", ""]);
+    assert_eq!(raw("QUIT").params, Vec::<String>::new());
+}
+
+#[test]
+fn raw_message_spacing() {
+    assert_eq!(
+        raw(":nvx-23!nvx@ecs.vuw.ac.nz   privmsg   #c   :  spaced  out  "),
+        RawMessage {
+            prefix: Some("nvx-23!nvx@ecs.vuw.ac.nz".to_owned()),
+            command: "PRIVMSG".to_owned(),
+            params: vec!["#c".to_owned(), "  spaced  out  ".to_owned()],
+        }
+    );
+    assert_eq!(raw("JOIN #foo ").params, vec!["#foo"]);
+}
+
+#[test]
+fn raw_message_param_limit() {
+    let line = format!("FOO {} fifteen :and more", "p ".repeat(14));
+    let params = raw(&line).params;
+    assert_eq!(params.len(), MAX_PARAMS);
+    assert_eq!(params[MAX_PARAMS - 1], "fifteen :and more");
+
+    let line = format!("FOO {}:trailing", "p ".repeat(14));
+    assert_eq!(raw(&line).params[MAX_PARAMS - 1], "trailing");
+}
+
+#[test]
+fn raw_message_command_name() {
+    assert_eq!(raw(":irc.localhost 001 amity :Welcome").command, "001");
+    assert!(parser::parse_raw_message("01 amity").is_err());
+    assert!(parser::parse_raw_message("PRIV2MSG amity").is_err());
+    assert!(parser::parse_raw_message("").is_err());
+    assert!(parser::parse_raw_message(":irc.localhost").is_err());
+    assert!(parser::parse_raw_message("PRIVMSG #c :nul\0byte").is_err());
+}
+
+#[test]
+fn parse_privmsg() {
+    let mut line = "PRIVMSG #c :hello world".to_owned();
+    assert_eq!(
+        try_parse_from_line(&mut line).unwrap(),
+        Command {
+            prefix: None,
+            kind: CommandKind::PrivMsg {
+                message_targets: vec!["#c".to_owned()],
+                message_text: "hello world".to_owned(),
+            }
+        }
+    );
+    assert_eq!(parse_error_code("PRIVMSG"), Some(411));
+    assert_eq!(parse_error_code("PRIVMSG #c"), Some(412));
+}
+
+#[test]
+fn commands_round_trip() {
+    let commands = vec![
+        CommandKind::Pass {
+            password: "hunter2".to_owned(),
+        },
+        CommandKind::Pass {
+            password: "two words".to_owned(),
+        },
+        CommandKind::Join {
+            channels: vec!["#foo".to_owned(), "&bar".to_owned()],
+            keys: Some(vec!["fubar".to_owned(), "foobar".to_owned()]),
+        },
+        CommandKind::Join {
+            channels: vec!["#foo".to_owned()],
+            keys: None,
+        },
+        CommandKind::Join {
+            channels: vec!["0".to_owned()],
+            keys: None,
+        },
+        CommandKind::Part {
+            channels: vec!["#foo".to_owned(), "&bar".to_owned()],
+            part_message: Some("see you".to_owned()),
+        },
+        CommandKind::Part {
+            channels: vec!["#foo".to_owned()],
+            part_message: None,
+        },
+        CommandKind::Nick {
+            nickname: "[{|21lu}]".to_owned(),
+        },
+        CommandKind::User {
+            user_name: "guest".to_owned(),
+            mode: 8,
+            real_name: "Amity Blight".to_owned(),
+        },
+        CommandKind::Ping {
+            source_server: "irc.localhost".to_owned(),
+            target_server: None,
+        },
+        CommandKind::Ping {
+            source_server: "amity".to_owned(),
+            target_server: Some("irc.localhost".to_owned()),
+        },
+        CommandKind::Pong {
+            source_server: "irc.localhost".to_owned(),
+            target_server: Some(":token".to_owned()),
+        },
+        CommandKind::PrivMsg {
+            message_targets: vec!["#c".to_owned()],
+            message_text: ":) hello  world ".to_owned(),
+        },
+        CommandKind::Notice {
+            message_targets: vec!["amity".to_owned(), "#c".to_owned()],
+            message_text: "hi".to_owned(),
+        },
+        CommandKind::Quit { quit_message: None },
+        CommandKind::Quit {
+            quit_message: Some(String::new()),
+        },
+        CommandKind::Quit {
+            quit_message: Some("Gone to lunch".to_owned()),
+        },
+    ];
+
+    for kind in commands {
+        for prefix in [None, Some("WiZ!jto@tolsun.oulu.fi".to_owned())] {
+            let command = Command {
+                prefix,
+                kind: kind.clone(),
+            };
+            let mut wire = command.to_wire();
+            assert!(wire.ends_with("\r\n"));
+            assert_eq!(try_parse_from_line(&mut wire).unwrap(), command);
+        }
+    }
+}
+
+#[test]
+fn command_wire_format() {
+    let command = Command {
+        prefix: Some("amity!guest@127.0.0.1".to_owned()),
+        kind: CommandKind::PrivMsg {
+            message_targets: vec!["#c".to_owned()],
+            message_text: "hi".to_owned(),
+        },
+    };
+    assert_eq!(
+        command.to_wire(),
+        ":amity!guest@127.0.0.1 PRIVMSG #c :hi\r\n"
+    );
+
+    let command = Command {
+        prefix: None,
+        kind: CommandKind::Join {
+            channels: vec!["#foo".to_owned(), "#bar".to_owned()],
+            keys: None,
+        },
+    };
+    assert_eq!(command.to_string(), "JOIN #foo,#bar");
+}
+
+#[test]
+fn ping_is_answered_with_pong() {
+    let (mut state, _path) = test_state();
+    let (mut client, mut reader) = test_client();
+
+    // keepalives are allowed before registration
+    run(&mut state, &mut client, "PING :LAG1234").unwrap();
+    assert_eq!(
+        read_reply(&mut reader),
+        ":irc.localhost PONG irc.localhost LAG1234\r\n"
+    );
+    run(&mut state, &mut client, "PONG irc.localhost").unwrap();
+    assert_eq!(parse_error_code("PING"), Some(409));
+}
+
+#[test]
+fn silent_clients_time_out() {
+    use crate::server::stream::Stream;
+    use std::sync::{Arc, RwLock};
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+    let path = state_path("ping-timeout");
+    let _ = std::fs::remove_file(&path);
+    let state = State::build(&crate::Config {
+        state_path: path.to_path_buf(),
+        ping_interval: std::time::Duration::from_millis(200),
+        ..Default::default()
+    })
+    .unwrap();
+    let (client_end, server_end) = tokio::io::duplex(4096);
+    let stream = Stream {
+        transport: Box::new(server_end),
+        peer_host: "127.0.0.1".to_owned(),
+        secure: false,
+    };
+    let state = Arc::new(RwLock::new(state));
+    RUNTIME.spawn(handle_client(state, stream, ListenerKind::Client));
+
+    let (reader, mut writer) = tokio::io::split(client_end);
+    let mut lines = tokio::io::BufReader::new(reader).lines();
+    let mut read_reply = || RUNTIME.block_on(lines.next_line()).unwrap();
+    // Answering the PING keeps the connection open for another interval
+    assert_eq!(read_reply().unwrap(), "PING irc.localhost");
+    RUNTIME
+        .block_on(writer.write_all(b"PONG irc.localhost\r\n"))
+        .unwrap();
+    assert_eq!(read_reply().unwrap(), "PING irc.localhost");
+    assert_eq!(
+        read_reply().unwrap(),
+        "ERROR :Closing Link: 127.0.0.1 (Ping timeout)"
+    );
+    assert_eq!(read_reply(), None);
+}
+
+/// A client that has completed registration as `nickname`, with its welcome burst consumed
+fn registered_client(state: &mut State, nickname: &str) -> (Client, BufReader<TcpStream>) {
+    let (mut client, mut reader) = test_client();
+    run(state, &mut client, &format!("NICK {nickname}")).unwrap();
+    run(state, &mut client, "USER guest 0 * :Test User").unwrap();
+    for _ in 0..5 {
+        read_reply(&mut reader);
+    }
+    (client, reader)
+}
+
+#[test]
+fn parse_message_targets() {
+    let mut line = "PRIVMSG amity,luz,,#c :hello world".to_owned();
+    assert_eq!(
+        try_parse_from_line(&mut line).unwrap().kind,
+        CommandKind::PrivMsg {
+            message_targets: vec!["amity".to_owned(), "luz".to_owned(), "#c".to_owned()],
+            message_text: "hello world".to_owned(),
+        }
+    );
+    // NOTICE never generates replies, even for errors
+    assert_eq!(parse_error_code("NOTICE"), None);
+    assert_eq!(parse_error_code("NOTICE amity"), None);
+}
+
+#[test]
+fn privmsg_delivery() {
+    let (mut state, _path) = test_state();
+    let (mut amity, mut amity_reader) = registered_client(&mut state, "amity");
+    let (_luz, mut luz_reader) = registered_client(&mut state, "luz");
+    let (_eda, mut eda_reader) = registered_client(&mut state, "eda");
+
+    run(&mut state, &mut amity, "PRIVMSG luz,eda :hi there").unwrap();
+    assert_eq!(
+        read_reply(&mut luz_reader),
+        ":amity!guest@127.0.0.1 PRIVMSG luz :hi there\r\n"
+    );
+    assert_eq!(
+        read_reply(&mut eda_reader),
+        ":amity!guest@127.0.0.1 PRIVMSG eda :hi there\r\n"
+    );
+
+    run(&mut state, &mut amity, "NOTICE luz :psst").unwrap();
+    assert_eq!(
+        read_reply(&mut luz_reader),
+        ":amity!guest@127.0.0.1 NOTICE luz :psst\r\n"
+    );
+
+    // errors are per target, the valid target still gets the message
+    // duplicates are spotted regardless of case
+    run(&mut state, &mut amity, "PRIVMSG hooty,luz,LUZ :hoot").unwrap();
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        ":irc.localhost 401 amity hooty :No such nick/channel\r\n"
+    );
+    assert_eq!(
+        read_reply(&mut luz_reader),
+        ":amity!guest@127.0.0.1 PRIVMSG luz :hoot\r\n"
+    );
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        ":irc.localhost 407 amity LUZ :Duplicate recipients. No message delivered\r\n"
+    );
+}
+
+#[test]
+fn notice_never_replies() {
+    let (mut state, _path) = test_state();
+    let (mut amity, mut amity_reader) = registered_client(&mut state, "amity");
+    let (_luz, mut luz_reader) = registered_client(&mut state, "luz");
+
+    run(&mut state, &mut amity, "NOTICE hooty,luz :hoot").unwrap();
+    assert_eq!(
+        read_reply(&mut luz_reader),
+        ":amity!guest@127.0.0.1 NOTICE luz :hoot\r\n"
+    );
+    // the next thing amity hears is the PONG, not an error about hooty
+    run(&mut state, &mut amity, "PING token").unwrap();
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        ":irc.localhost PONG irc.localhost token\r\n"
+    );
+}
+
+#[test]
+fn join_creates_channel_with_operator() {
+    let (mut state, _path) = test_state();
+    let (mut amity, mut amity_reader) = registered_client(&mut state, "amity");
+    let (mut luz, mut luz_reader) = registered_client(&mut state, "luz");
+
+    run(&mut state, &mut amity, "JOIN #Hexside").unwrap();
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        ":amity!guest@127.0.0.1 JOIN #Hexside\r\n"
+    );
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        ":irc.localhost 353 amity = #Hexside :@amity\r\n"
+    );
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        ":irc.localhost 366 amity #Hexside :End of NAMES list\r\n"
+    );
+
+    // channel names are case insensitive, but keep the creator's spelling
+    run(&mut state, &mut luz, "JOIN #hexside").unwrap();
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        ":luz!guest@127.0.0.1 JOIN #Hexside\r\n"
+    );
+    assert_eq!(
+        read_reply(&mut luz_reader),
+        ":luz!guest@127.0.0.1 JOIN #Hexside\r\n"
+    );
+    assert_eq!(
+        read_reply(&mut luz_reader),
+        ":irc.localhost 353 luz = #Hexside :@amity luz\r\n"
+    );
+}
+
+#[test]
+fn part_and_join_zero() {
+    let (mut state, _path) = test_state();
+    let (mut amity, mut amity_reader) = registered_client(&mut state, "amity");
+    let (mut luz, mut luz_reader) = registered_client(&mut state, "luz");
+
+    run(&mut state, &mut amity, "JOIN #a,#b").unwrap();
+    run(&mut state, &mut luz, "JOIN #a").unwrap();
+    for _ in 0..7 {
+        read_reply(&mut amity_reader);
+    }
+    for _ in 0..3 {
+        read_reply(&mut luz_reader);
+    }
+
+    run(&mut state, &mut luz, "PART #a :bye for now").unwrap();
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        ":luz!guest@127.0.0.1 PART #a :bye for now\r\n"
+    );
+    assert_eq!(
+        read_reply(&mut luz_reader),
+        ":luz!guest@127.0.0.1 PART #a :bye for now\r\n"
+    );
+
+    run(&mut state, &mut luz, "PART #a,#nowhere").unwrap();
+    assert_eq!(
+        read_reply(&mut luz_reader),
+        ":irc.localhost 442 luz #a :You're not on that channel\r\n"
+    );
+    assert_eq!(
+        read_reply(&mut luz_reader),
+        ":irc.localhost 403 luz #nowhere :No such channel\r\n"
+    );
+
+    run(&mut state, &mut amity, "JOIN 0").unwrap();
+    let mut parts = vec![read_reply(&mut amity_reader), read_reply(&mut amity_reader)];
+    parts.sort();
+    assert_eq!(
+        parts,
+        vec![
+            ":amity!guest@127.0.0.1 PART #a\r\n",
+            ":amity!guest@127.0.0.1 PART #b\r\n"
+        ]
+    );
+    assert!(state.find_channel("#a").is_none());
+    assert!(state.channels_of("amity").is_empty());
+}
+
+#[test]
+fn privmsg_to_channel() {
+    let (mut state, _path) = test_state();
+    let (mut amity, mut amity_reader) = registered_client(&mut state, "amity");
+    let (mut luz, mut luz_reader) = registered_client(&mut state, "luz");
+    let (mut eda, mut eda_reader) = registered_client(&mut state, "eda");
+
+    run(&mut state, &mut amity, "JOIN #c").unwrap();
+    run(&mut state, &mut luz, "JOIN #c").unwrap();
+    for _ in 0..4 {
+        read_reply(&mut amity_reader);
+    }
+    for _ in 0..3 {
+        read_reply(&mut luz_reader);
+    }
+
+    run(&mut state, &mut amity, "PRIVMSG #c :hello everyone").unwrap();
+    assert_eq!(
+        read_reply(&mut luz_reader),
+        ":amity!guest@127.0.0.1 PRIVMSG #c :hello everyone\r\n"
+    );
+
+    run(&mut state, &mut eda, "PRIVMSG #c,#nowhere :let me in").unwrap();
+    assert_eq!(
+        read_reply(&mut eda_reader),
+        ":irc.localhost 404 eda #c :Cannot send to channel\r\n"
+    );
+    assert_eq!(
+        read_reply(&mut eda_reader),
+        ":irc.localhost 401 eda #nowhere :No such nick/channel\r\n"
+    );
+
+    // the sender doesn't get their own message back
+    run(&mut state, &mut amity, "PING token").unwrap();
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        ":irc.localhost PONG irc.localhost token\r\n"
+    );
+
+    // a channel named twice in different case is only delivered to once
+    run(&mut state, &mut amity, "PRIVMSG #c,#C :just once").unwrap();
+    assert_eq!(
+        read_reply(&mut luz_reader),
+        ":amity!guest@127.0.0.1 PRIVMSG #c :just once\r\n"
+    );
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        ":irc.localhost 407 amity #C :Duplicate recipients. No message delivered\r\n"
+    );
+    run(&mut state, &mut amity, "PRIVMSG #c :and again").unwrap();
+    assert_eq!(
+        read_reply(&mut luz_reader),
+        ":amity!guest@127.0.0.1 PRIVMSG #c :and again\r\n"
+    );
+}
+
+#[test]
+fn casemapping() {
+    assert_eq!(casemap("Amity[]\\~"), "amity{}|^");
+    assert_eq!(casemap("#Hex{side}"), "#hex{side}");
+}
+
+#[test]
+fn nicknames_are_unique() {
+    let (mut state, _path) = test_state();
+    let (mut amity, _amity_reader) = registered_client(&mut state, "am[ty]");
+    let (mut luz, mut luz_reader) = registered_client(&mut state, "luz");
+
+    let err = run(&mut state, &mut luz, "NICK AM{TY}").unwrap_err();
+    assert_eq!(err.downcast_ref::<IrcError>().unwrap().numeric_code(), 433);
+
+    // a client can change the case of its own nickname
+    run(&mut state, &mut amity, "NICK Am[ty]").unwrap();
+    assert!(state.find_client("AM{TY}").is_some());
+
+    // and messages find nicknames case insensitively
+    run(&mut state, &mut amity, "PRIVMSG LUZ :hi").unwrap();
+    assert_eq!(
+        read_reply(&mut luz_reader),
+        ":Am[ty]!guest@127.0.0.1 PRIVMSG LUZ :hi\r\n"
+    );
+
+    // unregistered clients can't take a registered nickname either
+    let (mut hooty, _hooty_reader) = test_client();
+    let err = run(&mut state, &mut hooty, "NICK Luz").unwrap_err();
+    assert_eq!(err.downcast_ref::<IrcError>().unwrap().numeric_code(), 433);
+}
+
+#[test]
+fn nickname_taken_during_registration() {
+    let (mut state, _path) = test_state();
+    let (mut hooty, _hooty_reader) = test_client();
+    run(&mut state, &mut hooty, "NICK luz").unwrap();
+    let (_luz, _luz_reader) = registered_client(&mut state, "luz");
+
+    let err = run(&mut state, &mut hooty, "USER guest 0 * :Hooty").unwrap_err();
+    assert_eq!(err.downcast_ref::<IrcError>().unwrap().numeric_code(), 433);
+    assert!(!hooty.is_registered());
+
+    run(&mut state, &mut hooty, "NICK hooty").unwrap();
+    assert!(hooty.is_registered());
+}
+
+#[test]
+fn nick_change_is_broadcast_to_channels() {
+    let (mut state, _path) = test_state();
+    let (mut amity, mut amity_reader) = registered_client(&mut state, "amity");
+    let (mut luz, mut luz_reader) = registered_client(&mut state, "luz");
+    let (mut eda, mut eda_reader) = registered_client(&mut state, "eda");
+
+    run(&mut state, &mut amity, "JOIN #a,#b").unwrap();
+    run(&mut state, &mut luz, "JOIN #a,#b").unwrap();
+    for _ in 0..8 {
+        read_reply(&mut amity_reader);
+    }
+    for _ in 0..6 {
+        read_reply(&mut luz_reader);
+    }
+
+    run(&mut state, &mut amity, "NICK blight").unwrap();
+    let nick_change = ":amity!guest@127.0.0.1 NICK blight\r\n";
+    assert_eq!(read_reply(&mut amity_reader), nick_change);
+    // luz shares two channels with amity, but only hears about it once
+    assert_eq!(read_reply(&mut luz_reader), nick_change);
+    run(&mut state, &mut luz, "PRIVMSG #a :hi blight").unwrap();
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        ":luz!guest@127.0.0.1 PRIVMSG #a :hi blight\r\n"
+    );
+
+    // eda shares no channels so isn't told
+    run(&mut state, &mut eda, "PING token").unwrap();
+    assert_eq!(
+        read_reply(&mut eda_reader),
+        ":irc.localhost PONG irc.localhost token\r\n"
+    );
+    assert!(state.find_client("amity").is_none());
+    assert!(state.find_channel("#b").unwrap().is_member("blight"));
+}
+
+#[test]
+fn parse_quit() {
+    let mut line = "QUIT :Gone to have lunch".to_owned();
+    assert_eq!(
+        try_parse_from_line(&mut line).unwrap().kind,
+        CommandKind::Quit {
+            quit_message: Some("Gone to have lunch".to_owned())
+        }
+    );
+    let mut line = "QUIT".to_owned();
+    assert_eq!(
+        try_parse_from_line(&mut line).unwrap().kind,
+        CommandKind::Quit { quit_message: None }
+    );
+}
+
+#[test]
+fn quit_is_broadcast_and_cleaned_up() {
+    let (mut state, _path) = test_state();
+    let (mut amity, mut amity_reader) = registered_client(&mut state, "amity");
+    let (mut luz, mut luz_reader) = registered_client(&mut state, "luz");
+
+    run(&mut state, &mut amity, "JOIN #a,#b").unwrap();
+    run(&mut state, &mut luz, "JOIN #a,#b").unwrap();
+    for _ in 0..8 {
+        read_reply(&mut amity_reader);
+    }
+    for _ in 0..6 {
+        read_reply(&mut luz_reader);
+    }
+
+    let command = try_parse_from_line(&mut "QUIT :Gone to lunch".to_owned()).unwrap();
+    let Ok(ControlFlow::Break(quit_message)) = apply_command(&mut state, &mut amity, command)
+    else {
+        panic!("QUIT should end the connection");
+    };
+    quit(&mut state, quit_message, amity);
+
+    assert_eq!(
+        read_reply(&mut luz_reader),
+        ":amity!guest@127.0.0.1 QUIT :Gone to lunch\r\n"
+    );
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        "ERROR :Closing Link: 127.0.0.1 (Gone to lunch)\r\n"
+    );
+    assert_eq!(read_reply(&mut amity_reader), "");
+
+    assert!(state.find_client("amity").is_none());
+    assert!(!state.find_channel("#a").unwrap().is_member("amity"));
+    assert_eq!(state.channels_of("luz").len(), 2);
+}
+
+fn build_state(state_path: &std::path::Path) -> Result<State> {
+    State::build(&crate::Config {
+        state_path: state_path.to_path_buf(),
+        ..Default::default()
+    })
+}
+
+fn state_path(name: &str) -> TestPath {
+    TestPath(std::env::temp_dir().join(format!("irc-test-{name}-{}.json", std::process::id())))
+}
+
+/// Where a test keeps its state file
+/// Once dropped, the file is deleted along with everything else named after it, eg its
+/// journal or a config written alongside it with `with_extension`
+struct TestPath(std::path::PathBuf);
+
+impl std::ops::Deref for TestPath {
+    type Target = std::path::PathBuf;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl AsRef<std::path::Path> for TestPath {
+    fn as_ref(&self) -> &std::path::Path {
+        &self.0
+    }
+}
+
+impl Drop for TestPath {
+    fn drop(&mut self) {
+        let (Some(dir), Some(stem)) = (self.0.parent(), self.0.file_stem()) else {
+            return;
+        };
+        let prefix = format!("{}.", stem.to_string_lossy());
+        for entry in std::fs::read_dir(dir).into_iter().flatten().flatten() {
+            if entry.file_name().to_string_lossy().starts_with(&prefix) {
+                let _ = std::fs::remove_file(entry.path());
+            }
+        }
+    }
+}
+
+const SAVED_STATE: &str = r##"{
+  "version": 2,
+  "channels": [
+    {
+      "name": "#Hexside",
+      "created": 1700000000,
+      "topic": "Welcome to Hexside",
+      "topic_set_by": "eda!eda@owl.house",
+      "topic_set_at": 1700000050,
+      "modes": "nt",
+      "bans": [
+        { "mask": "*!*@boiling.isles", "set_by": "eda!eda@owl.house", "set_at": 1700000100 }
+      ]
+    }
+  ],
+  "accounts": [{ "name": "eda", "registered": 1700000000 }]
+}"##;
+
+/// Writes the state file the way the persistence thread does
+fn save(state: &mut State) {
+    let snapshot = state.snapshot();
+    snapshot.save().unwrap();
+    state.saved(&snapshot).unwrap();
+}
+
+#[test]
+fn build_creates_missing_state_file() {
+    let path = state_path("missing");
+    let _ = std::fs::remove_file(&path);
+
+    build_state(&path).unwrap();
+    let saved: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+    assert_eq!(saved["version"], 2);
+    assert_eq!(saved["channels"], serde_json::json!([]));
+}
+
+#[test]
+fn state_file_round_trip() {
+    let path = state_path("round-trip");
+    std::fs::write(&path, SAVED_STATE).unwrap();
+
+    let mut state = build_state(&path).unwrap();
+    let channel = state.find_channel("#hexside").unwrap();
+    assert_eq!(channel.name, "#Hexside");
+    assert_eq!(channel.topic.as_deref(), Some("Welcome to Hexside"));
+    assert_eq!(channel.topic_set_by, "eda!eda@owl.house");
+    assert_eq!(state::to_unix_seconds(channel.topic_set_at), 1700000050);
+    assert_eq!(channel.names_symbol(), '=');
+    assert_eq!(channel.bans[0].mask, "*!*@boiling.isles");
+    assert_eq!(channel.bans[0].set_by, "eda!eda@owl.house");
+    assert_eq!(state::to_unix_seconds(channel.bans[0].set_at), 1700000100);
+
+    save(&mut state);
+    let saved: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+    let expected: serde_json::Value = serde_json::from_str(SAVED_STATE).unwrap();
+    assert_eq!(saved, expected);
+}
+
+#[test]
+fn registered_channels_outlive_their_members() {
+    let path = state_path("registered");
+    std::fs::write(&path, SAVED_STATE).unwrap();
+    let mut state = build_state(&path).unwrap();
+    let (mut amity, _amity_reader) = registered_client(&mut state, "amity");
+
+    run(&mut state, &mut amity, "JOIN #hexside,#other").unwrap();
+    run(&mut state, &mut amity, "PART #hexside,#other").unwrap();
+    assert!(state.find_channel("#hexside").is_some());
+    assert!(state.find_channel("#other").is_none());
+}
+
+#[test]
+fn reload_keeps_members() {
+    let path = state_path("reload");
+    std::fs::write(&path, SAVED_STATE).unwrap();
+    let mut state = build_state(&path).unwrap();
+    let (mut amity, _amity_reader) = registered_client(&mut state, "amity");
+    run(&mut state, &mut amity, "JOIN #hexside,#other").unwrap();
+
+    std::fs::write(&path, SAVED_STATE.replace("Welcome to Hexside", "Closed")).unwrap();
+    state.reload_from_file().unwrap();
+
+    let channel = state.find_channel("#hexside").unwrap();
+    assert_eq!(channel.topic.as_deref(), Some("Closed"));
+    assert!(channel.is_member("amity"));
+    assert!(state.find_channel("#other").unwrap().is_member("amity"));
+}
+
+#[test]
+fn failed_reload_changes_nothing() {
+    let path = state_path("bad-reload");
+    std::fs::write(&path, SAVED_STATE).unwrap();
+    let mut state = build_state(&path).unwrap();
+
+    let edited = SAVED_STATE.replace("Welcome to Hexside", "Closed");
+    let misnamed = edited.replace(
+        "\"channels\": [",
+        "\"channels\": [{ \"name\": \"hexside\", \"created\": 1700000000 },",
+    );
+    for broken in [&edited[..edited.len() - 1], &misnamed] {
+        std::fs::write(&path, broken).unwrap();
+        assert!(state.reload_from_file().is_err());
+        let channel = state.find_channel("#hexside").unwrap();
+        assert_eq!(channel.topic.as_deref(), Some("Welcome to Hexside"));
+    }
+}
+
+#[test]
+fn newer_state_file_version_is_rejected() {
+    let path = state_path("newer");
+    std::fs::write(
+        &path,
+        SAVED_STATE.replace("\"version\": 2", "\"version\": 99"),
+    )
+    .unwrap();
+    assert!(build_state(&path).is_err());
+}
+
+#[test]
+fn version_1_bans_are_migrated() {
+    let path = state_path("version-1");
+    std::fs::write(
+        &path,
+        r##"{
+          "version": 1,
+          "channels": [{ "name": "#Hexside", "created": 1700000000, "bans": ["*!*@boiling.isles"] }]
+        }"##,
+    )
+    .unwrap();
+
+    let mut state = build_state(&path).unwrap();
+    let ban = &state.find_channel("#hexside").unwrap().bans[0];
+    assert_eq!(ban.mask, "*!*@boiling.isles");
+    assert_eq!(ban.set_by, "*");
+    assert_eq!(state::to_unix_seconds(ban.set_at), 0);
+
+    save(&mut state);
+    let saved: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+    assert_eq!(saved["version"], 2);
+    assert_eq!(
+        saved["channels"][0]["bans"],
+        serde_json::json!([{ "mask": "*!*@boiling.isles", "set_by": "*", "set_at": 0 }])
+    );
+}
+
+#[test]
+fn journal_survives_a_crash() {
+    let path = state_path("journal");
+    let _ = std::fs::remove_file(state::Journal::path_for(&path));
+    std::fs::write(&path, SAVED_STATE).unwrap();
+
+    let mut state = build_state(&path).unwrap();
+    state
+        .update_channel("#hexside", |channel| {
+            channel.topic = Some("Closed".to_owned())
+        })
+        .unwrap();
+    // dropped without saving, as if the server had crashed
+    drop(state);
+
+    let mut state = build_state(&path).unwrap();
+    let channel = state.find_channel("#hexside").unwrap();
+    assert_eq!(channel.topic.as_deref(), Some("Closed"));
+
+    save(&mut state);
+    assert!(std::fs::read_to_string(&path).unwrap().contains("Closed"));
+    assert_eq!(
+        std::fs::read_to_string(state::Journal::path_for(&path)).unwrap(),
+        ""
+    );
+}
+
+#[test]
+fn changes_during_a_save_stay_journaled() {
+    let path = state_path("save-race");
+    let _ = std::fs::remove_file(state::Journal::path_for(&path));
+    std::fs::write(&path, SAVED_STATE).unwrap();
+    let mut state = build_state(&path).unwrap();
+    let set_topic = |state: &mut State, topic: &str| {
+        let topic = topic.to_owned();
+        state
+            .update_channel("#hexside", |channel| channel.topic = Some(topic))
+            .unwrap();
+    };
+
+    set_topic(&mut state, "Closed");
+    let snapshot = state.snapshot_if_dirty().unwrap();
+    // Made while the snapshot is being written, with the lock released
+    set_topic(&mut state, "Reopened");
+    snapshot.save().unwrap();
+    state.saved(&snapshot).unwrap();
+    assert!(std::fs::read_to_string(&path).unwrap().contains("Closed"));
+    assert!(
+        std::fs::read_to_string(state::Journal::path_for(&path))
+            .unwrap()
+            .contains("Reopened")
+    );
+    drop(state);
+
+    // Replaying the whole journal over the newer state file still ends up at the latest topic
+    let mut state = build_state(&path).unwrap();
+    let channel = state.find_channel("#hexside").unwrap();
+    assert_eq!(channel.topic.as_deref(), Some("Reopened"));
+    let snapshot = state.snapshot_if_dirty().unwrap();
+    snapshot.save().unwrap();
+    state.saved(&snapshot).unwrap();
+    assert!(state.snapshot_if_dirty().is_none());
+    assert_eq!(
+        std::fs::read_to_string(state::Journal::path_for(&path)).unwrap(),
+        ""
+    );
+}
+
+#[test]
+fn torn_journal_entry_is_ignored() {
+    let path = state_path("torn");
+    std::fs::write(&path, SAVED_STATE).unwrap();
+    std::fs::write(
+        state::Journal::path_for(&path),
+        "{\"kind\":\"Channel\",\"name\":\"#Hexs",
+    )
+    .unwrap();
+
+    let state = build_state(&path).unwrap();
+    let channel = state.find_channel("#hexside").unwrap();
+    assert_eq!(channel.topic.as_deref(), Some("Welcome to Hexside"));
+}
+
+#[test]
+fn persistence_flushes_on_request_and_shutdown() {
+    use std::sync::{Arc, RwLock};
+
+    let path = state_path("persistence");
+    let config = oper_config(&path);
+    let path = config.state_path.clone();
+    let _ = std::fs::remove_file(state::Journal::path_for(&path));
+    std::fs::write(&path, SAVED_STATE).unwrap();
+    let state = Arc::new(RwLock::new(State::build(&config).unwrap()));
+    let (mut amity, mut amity_reader) = registered_client(&mut state.write().unwrap(), "amity");
+    let mut run = |line: &str| run(&mut state.write().unwrap(), &mut amity, line);
+
+    // Only operators may ask for a flush
+    let err = run("FLUSH").unwrap_err();
+    assert_eq!(err.downcast_ref::<IrcError>().unwrap().numeric_code(), 481);
+    run("OPER eda owlbeast").unwrap();
+    for _ in 0..2 {
+        read_reply(&mut amity_reader);
+    }
+    run("FLUSH").unwrap();
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        ":irc.localhost NOTICE amity :Failed to save the state file: The state file isn't being saved in the background\r\n"
+    );
+
+    let persistence =
+        state::Persistence::start(&state, std::time::Duration::from_secs(3600)).unwrap();
+    let set_topic = |topic: &str| {
+        let topic = topic.to_owned();
+        state
+            .write()
+            .unwrap()
+            .update_channel("#hexside", |channel| channel.topic = Some(topic))
+            .unwrap();
+    };
+
+    set_topic("Closed");
+    assert!(!std::fs::read_to_string(&path).unwrap().contains("Closed"));
+    run("FLUSH").unwrap();
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        ":irc.localhost NOTICE amity :State file saved\r\n"
+    );
+    assert!(std::fs::read_to_string(&path).unwrap().contains("Closed"));
+
+    set_topic("Reopened");
+    persistence.shutdown().unwrap();
+    assert!(std::fs::read_to_string(&path).unwrap().contains("Reopened"));
+}
+
+#[test]
+fn registered_channel_survives_restart() {
+    let path = state_path("regchan");
+    let config = oper_config(&path);
+    let _ = std::fs::remove_file(&config.state_path);
+    let _ = std::fs::remove_file(state::Journal::path_for(&config.state_path));
+    let mut state = State::build(&config).unwrap();
+    let (mut amity, mut amity_reader) = registered_client(&mut state, "amity");
+    run(&mut state, &mut amity, "JOIN #Owls").unwrap();
+    run(&mut state, &mut amity, "TOPIC #owls :Hoots only").unwrap();
+    for _ in 0..4 {
+        read_reply(&mut amity_reader);
+    }
+
+    // Only operators may register channels, and only ones that exist
+    let err = run(&mut state, &mut amity, "REGCHAN #owls").unwrap_err();
+    assert_eq!(err.downcast_ref::<IrcError>().unwrap().numeric_code(), 481);
+    run(&mut state, &mut amity, "OPER eda owlbeast").unwrap();
+    for _ in 0..2 {
+        read_reply(&mut amity_reader);
+    }
+    let err = run(&mut state, &mut amity, "REGCHAN #bats").unwrap_err();
+    assert_eq!(err.downcast_ref::<IrcError>().unwrap().numeric_code(), 403);
+    run(&mut state, &mut amity, "REGCHAN #OWLS").unwrap();
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        ":irc.localhost NOTICE amity :Channel #Owls is registered\r\n"
+    );
+
+    // Kept once empty, and after a restart whether or not the state file was written since
+    run(&mut state, &mut amity, "PART #owls").unwrap();
+    assert!(state.find_channel("#owls").is_some());
+    drop(state);
+    let mut state = State::build(&config).unwrap();
+    let channel = state.find_channel("#owls").unwrap();
+    assert!(channel.registered);
+    assert_eq!(channel.topic.as_deref(), Some("Hoots only"));
+    save(&mut state);
+    drop(state);
+    let state = State::build(&config).unwrap();
+    assert!(state.find_channel("#owls").unwrap().registered);
+}
+
+fn args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string()).collect()
+}
+
+#[test]
+fn cli_actions() {
+    use crate::{Action, Config};
+    let no_env = |_: &str| None;
+
+    assert_eq!(
+        Config::from_args(args(&["--help"]), no_env).unwrap(),
+        Action::PrintHelp
+    );
+    assert_eq!(
+        Config::from_args(args(&["-V"]), no_env).unwrap(),
+        Action::PrintVersion
+    );
+    assert_eq!(
+        Config::from_args(args(&[]), no_env).unwrap(),
+        Action::Run(Config::default())
+    );
+    let Action::CheckConfig(config) =
+        Config::from_args(args(&["--check-config", "owl.json"]), no_env).unwrap()
+    else {
+        panic!("--check-config should only check the config");
+    };
+    assert_eq!(config.state_path, std::path::PathBuf::from("owl.json"));
+
+    assert!(Config::from_args(args(&["--bogus"]), no_env).is_err());
+    assert!(Config::from_args(args(&["--config"]), no_env).is_err());
+    assert!(Config::from_args(args(&["a.json", "b.json"]), no_env).is_err());
+}
+
+#[test]
+fn config_sources_are_layered() {
+    use crate::{Action, Config};
+
+    let files = state_path("config");
+    let path = files.with_extension("toml");
+    std::fs::write(
+        &path,
+        "server_name = \"irc.bonesborough\"\nstate_file = \"file.json\"\nping_interval = \"30s\"\nsendq = 1024\nmax_list_entries = 20\ntopic_len = 80\n",
+    )
+    .unwrap();
+    let env = |name: &str| match name {
+        "IRC_CONFIG" => Some(path.display().to_string()),
+        "IRC_STATE_FILE" => Some("env.json".to_owned()),
+        "IRC_LISTEN" => Some("[::1]:6667".to_owned()),
+        "IRC_SENDQ" => Some("2048".to_owned()),
+        "IRC_INVALID_UTF8" => Some("reject".to_owned()),
+        "IRC_EXTENDED_RANKS" => Some("true".to_owned()),
+        _ => None,
+    };
+
+    let Action::Run(config) = Config::from_args(args(&[]), env).unwrap() else {
+        panic!("expected to run");
+    };
+    assert_eq!(config.server_name, "irc.bonesborough");
+    assert_eq!(config.ping_interval, std::time::Duration::from_secs(30));
+    assert_eq!(config.sendq, 2048);
+    assert_eq!(config.invalid_utf8, crate::config::InvalidUtf8::Reject);
+    assert_eq!(config.max_list_entries, 20);
+    assert!(config.extended_ranks);
+    assert_eq!(config.topic_len, 80);
+    assert_eq!(config.state_path, std::path::PathBuf::from("env.json"));
+    assert_eq!(
+        config.listeners,
+        [crate::config::ListenerConfig {
+            address: "[::1]:6667".parse().unwrap(),
+            kind: ListenerKind::Client,
+            tls: None,
+        }]
+    );
+
+    let Action::Run(config) = Config::from_args(args(&["-s", "cli.json"]), env).unwrap() else {
+        panic!("expected to run");
+    };
+    assert_eq!(config.state_path, std::path::PathBuf::from("cli.json"));
+
+    std::fs::write(&path, "ping_interval = \"soon\"\n").unwrap();
+    assert!(Config::from_args(args(&[]), env).is_err());
+    std::fs::write(&path, "colour = \"green\"\n").unwrap();
+    assert!(Config::from_args(args(&[]), env).is_err());
+}
+
+#[test]
+fn listeners_from_config_file() {
+    use crate::config::{ListenAddress, ListenerConfig};
+    use crate::{Action, Config};
+
+    let files = state_path("listeners");
+    let path = files.with_extension("toml");
+    std::fs::write(
+        &path,
+        r#"
+[[listeners]]
+address = "0.0.0.0:6667"
+
+[[listeners]]
+address = "[::]:6667"
+
+[[listeners]]
+address = "unix:/run/irc/admin.sock"
+kind = "admin"
+"#,
+    )
+    .unwrap();
+    let Action::Run(config) =
+        Config::from_args(args(&["-c", path.to_str().unwrap()]), |_| None).unwrap()
+    else {
+        panic!("expected to run");
+    };
+    assert_eq!(
+        config.listeners,
+        [
+            ListenerConfig {
+                address: ListenAddress::Tcp("0.0.0.0:6667".parse().unwrap()),
+                kind: ListenerKind::Client,
+                tls: None,
+            },
+            ListenerConfig {
+                address: ListenAddress::Tcp("[::]:6667".parse().unwrap()),
+                kind: ListenerKind::Client,
+                tls: None,
+            },
+            ListenerConfig {
+                address: ListenAddress::Unix("/run/irc/admin.sock".into()),
+                kind: ListenerKind::Admin,
+                tls: None,
+            },
+        ]
+    );
+
+    std::fs::write(&path, "listeners = []\n").unwrap();
+    assert!(Config::from_args(args(&["-c", path.to_str().unwrap()]), |_| None).is_err());
+    std::fs::write(&path, "[[listeners]]\naddress = \"localhost\"\n").unwrap();
+    assert!(Config::from_args(args(&["-c", path.to_str().unwrap()]), |_| None).is_err());
+    std::fs::write(
+        &path,
+        "[[listeners]]\naddress = \"[::]:6697\"\ntls_cert = \"irc.crt\"\n",
+    )
+    .unwrap();
+    assert!(Config::from_args(args(&["-c", path.to_str().unwrap()]), |_| None).is_err());
+}
+
+#[test]
+fn listeners_accept_into_one_server() {
+    use crate::config::{ListenAddress, ListenerConfig};
+    use crate::listener::Listener;
+    use std::io::Write;
+    use std::sync::{Arc, RwLock};
+
+    let (state, _path) = test_state();
+    let state = Arc::new(RwLock::new(state));
+    let files = state_path("listener");
+    let socket_path = files.with_extension("sock");
+    let tcp = Listener::bind(&ListenerConfig {
+        address: ListenAddress::Tcp("127.0.0.1:0".parse().unwrap()),
+        kind: ListenerKind::Client,
+        tls: None,
+    })
+    .unwrap();
+    let unix = Listener::bind(&ListenerConfig {
+        address: ListenAddress::Unix(socket_path.clone()),
+        kind: ListenerKind::Admin,
+        tls: None,
+    })
+    .unwrap();
+    let ListenAddress::Tcp(tcp_address) = tcp.address().unwrap() else {
+        panic!("expected a TCP listener");
+    };
+    let (_stop, stopped) = tokio::sync::watch::channel(false);
+    let _guard = RUNTIME.enter();
+    tcp.spawn(&state, stopped.clone()).unwrap();
+    unix.spawn(&state, stopped).unwrap();
+
+    let mut amity = TcpStream::connect(tcp_address).unwrap();
+    let mut luz = std::os::unix::net::UnixStream::connect(&socket_path).unwrap();
+    luz.set_read_timeout(Some(std::time::Duration::from_secs(5)))
+        .unwrap();
+    let mut luz_reader = BufReader::new(luz.try_clone().unwrap());
+
+    luz.write_all(b"NICK luz\r\nUSER luz 0 * :Luz Noceda\r\n")
+        .unwrap();
+    for _ in 0..5 {
+        read_reply(&mut luz_reader);
+    }
+    amity
+        .write_all(b"NICK amity\r\nUSER guest 0 * :Amity Blight\r\nPRIVMSG luz :hi\r\n")
+        .unwrap();
+    assert_eq!(
+        read_reply(&mut luz_reader),
+        ":amity!guest@127.0.0.1 PRIVMSG luz :hi\r\n"
+    );
+}
+
+/// Writes a fresh self-signed certificate for "localhost", returning its DER encoding
+fn write_certificate(paths: &crate::config::TlsPaths) -> Vec<u8> {
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+    std::fs::write(&paths.cert, certified.cert.pem()).unwrap();
+    std::fs::write(&paths.key, certified.key_pair.serialize_pem()).unwrap();
+    certified.cert.der().to_vec()
+}
+
+type TlsClient = rustls::StreamOwned<rustls::ClientConnection, TcpStream>;
+
+fn connect_tls(address: std::net::SocketAddr, trusted: &[u8]) -> BufReader<TlsClient> {
+    use std::sync::Arc;
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(trusted.to_vec().into()).unwrap();
+    let config = rustls::ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .unwrap()
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+    let connection =
+        rustls::ClientConnection::new(Arc::new(config), "localhost".try_into().unwrap()).unwrap();
+
+    let socket = TcpStream::connect(address).unwrap();
+    socket
+        .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+        .unwrap();
+    BufReader::new(rustls::StreamOwned::new(connection, socket))
+}
+
+#[test]
+fn tls_listener_with_certificate_reload() {
+    use crate::config::{ListenAddress, ListenerConfig, TlsPaths};
+    use crate::listener::Listener;
+    use std::io::Write;
+    use std::sync::{Arc, RwLock};
+
+    let files = state_path("tls");
+    let paths = TlsPaths {
+        cert: files.with_extension("crt"),
+        key: files.with_extension("key"),
+    };
+    let first = write_certificate(&paths);
+
+    let (state, _path) = test_state();
+    let state = Arc::new(RwLock::new(state));
+    let listener = Listener::bind(&ListenerConfig {
+        address: ListenAddress::Tcp("127.0.0.1:0".parse().unwrap()),
+        kind: ListenerKind::Client,
+        tls: Some(paths.clone()),
+    })
+    .unwrap();
+    let ListenAddress::Tcp(address) = listener.address().unwrap() else {
+        panic!("expected a TCP listener");
+    };
+    let (_stop, stopped) = tokio::sync::watch::channel(false);
+    let _guard = RUNTIME.enter();
+    listener.spawn(&state, stopped).unwrap();
+
+    let mut amity = connect_tls(address, &first);
+    amity
+        .get_mut()
+        .write_all(b"NICK amity\r\nUSER guest 0 * :Amity Blight\r\n")
+        .unwrap();
+    assert!(read_reply(&mut amity).starts_with(":irc.localhost 001 amity "));
+    // TLS clients are +Z from the start
+    let modes = state
+        .read()
+        .unwrap()
+        .find_client("amity")
+        .unwrap()
+        .modes
+        .clone();
+    assert_eq!(modes, std::collections::BTreeSet::from(['Z']));
+
+    // A renewed certificate is presented to new clients, without dropping existing ones
+    let second = write_certificate(&paths);
+    std::fs::File::options()
+        .write(true)
+        .open(&paths.cert)
+        .unwrap()
+        .set_modified(std::time::SystemTime::now() + std::time::Duration::from_secs(60))
+        .unwrap();
+    let mut luz = connect_tls(address, &second);
+    luz.get_mut()
+        .write_all(b"NICK luz\r\nUSER luz 0 * :Luz Noceda\r\nPRIVMSG amity :hi\r\n")
+        .unwrap();
+    assert!(read_reply(&mut luz).starts_with(":irc.localhost 001 luz "));
+
+    for _ in 0..4 {
+        read_reply(&mut amity);
+    }
+    assert_eq!(
+        read_reply(&mut amity),
+        ":luz!luz@127.0.0.1 PRIVMSG amity :hi\r\n"
+    );
+}
+
+#[test]
+fn graceful_shutdown() {
+    use crate::config::{ListenAddress, ListenerConfig};
+    use crate::listener::Listener;
+    use std::io::Write;
+    use std::sync::{Arc, RwLock};
+
+    let (state, _path) = test_state();
+    let state = Arc::new(RwLock::new(state));
+    let listener = Listener::bind(&ListenerConfig {
+        address: ListenAddress::Tcp("127.0.0.1:0".parse().unwrap()),
+        kind: ListenerKind::Client,
+        tls: None,
+    })
+    .unwrap();
+    let ListenAddress::Tcp(address) = listener.address().unwrap() else {
+        panic!("expected a TCP listener");
+    };
+    let (stop, stopped) = tokio::sync::watch::channel(false);
+    let _guard = RUNTIME.enter();
+    let listener = listener.spawn(&state, stopped).unwrap();
+
+    let connect = || {
+        let stream = TcpStream::connect(address).unwrap();
+        stream
+            .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+            .unwrap();
+        stream
+    };
+    let mut amity = connect();
+    let mut amity_reader = BufReader::new(amity.try_clone().unwrap());
+    amity
+        .write_all(b"NICK amity\r\nUSER guest 0 * :Amity Blight\r\nJOIN #a\r\n")
+        .unwrap();
+    for _ in 0..8 {
+        read_reply(&mut amity_reader);
+    }
+    let mut hunter = BufReader::new(connect());
+    while state.read().unwrap().connections().len() < 2 {
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+
+    stop.send_replace(true);
+    let clients = RUNTIME.block_on(listener).unwrap();
+    assert!(TcpStream::connect(address).is_err());
+
+    shutdown(&mut state.write().unwrap());
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        ":irc.localhost NOTICE amity :Server shutting down\r\n"
+    );
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        "ERROR :Server shutting down\r\n"
+    );
+    assert_eq!(read_reply(&mut amity_reader), "");
+    assert_eq!(
+        read_reply(&mut hunter),
+        ":irc.localhost NOTICE * :Server shutting down\r\n"
+    );
+
+    RUNTIME.block_on(crate::join_clients(
+        vec![clients],
+        std::time::Duration::from_secs(5),
+    ));
+    let state = state.read().unwrap();
+    assert!(state.connections().is_empty());
+    assert!(state.find_client("amity").is_none());
+}
+
+#[test]
+fn sendq_limit_is_enforced() {
+    let (outbound, _queue) = client::Outbound::new(12);
+    outbound.send_wire("PING a\r\n").unwrap();
+    assert!(outbound.send_wire("PING b\r\n").is_err());
+    // Rejected lines don't count against the limit
+    outbound.send_wire("P\r\n").unwrap();
+    // Closing is never refused, however full the queue is
+    outbound.shutdown().unwrap();
+}
+
+#[test]
+fn slow_readers_are_disconnected() {
+    use crate::server::stream::Stream;
+    use std::sync::{Arc, RwLock};
+    use tokio::io::AsyncWriteExt;
+
+    let path = state_path("sendq");
+    let state = Arc::new(RwLock::new(
+        State::build(&crate::Config {
+            state_path: path.to_path_buf(),
+            sendq: 4096,
+            ..Default::default()
+        })
+        .unwrap(),
+    ));
+    let (mut amity, mut amity_reader) = registered_client(&mut state.write().unwrap(), "amity");
+    run(&mut state.write().unwrap(), &mut amity, "JOIN #a").unwrap();
+    for _ in 0..3 {
+        read_reply(&mut amity_reader);
+    }
+
+    // willow never reads, so only a little of what is sent to her gets anywhere
+    let (mut willow, server_end) = tokio::io::duplex(64);
+    let stream = Stream {
+        transport: Box::new(server_end),
+        peer_host: "127.0.0.1".to_owned(),
+        secure: false,
+    };
+    let handle = RUNTIME.spawn(handle_client(
+        Arc::clone(&state),
+        stream,
+        ListenerKind::Client,
+    ));
+    RUNTIME
+        .block_on(willow.write_all(b"NICK willow\r\nUSER guest 0 * :Willow Park\r\nJOIN #a\r\n"))
+        .unwrap();
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        ":willow!guest@127.0.0.1 JOIN #a\r\n"
+    );
+
+    let message = format!("PRIVMSG #a :{}", "a".repeat(400));
+    for _ in 0..20 {
+        run(&mut state.write().unwrap(), &mut amity, &message).unwrap();
+    }
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        ":willow!guest@127.0.0.1 QUIT :Max SendQ exceeded\r\n"
+    );
+    RUNTIME.block_on(handle).unwrap().unwrap();
+    let state = state.read().unwrap();
+    assert!(state.find_client("willow").is_none());
+    assert!(state.connections().is_empty());
+}
+
+#[test]
+fn overflowing_own_sendq_disconnects() {
+    use crate::server::stream::Stream;
+    use std::sync::{Arc, RwLock};
+    use tokio::io::AsyncWriteExt;
+
+    let path = state_path("own-sendq");
+    let state = Arc::new(RwLock::new(
+        State::build(&crate::Config {
+            state_path: path.to_path_buf(),
+            sendq: 2048,
+            flood_exempt: vec!["127.0.0.1".to_owned()],
+            ..Default::default()
+        })
+        .unwrap(),
+    ));
+    let (mut amity, mut amity_reader) = registered_client(&mut state.write().unwrap(), "amity");
+    run(&mut state.write().unwrap(), &mut amity, "JOIN #a").unwrap();
+    for _ in 0..3 {
+        read_reply(&mut amity_reader);
+    }
+
+    // willow never reads, so her own replies are what fill up her SendQ
+    let (mut willow, server_end) = tokio::io::duplex(64);
+    let stream = Stream {
+        transport: Box::new(server_end),
+        peer_host: "127.0.0.1".to_owned(),
+        secure: false,
+    };
+    let handle = RUNTIME.spawn(handle_client(
+        Arc::clone(&state),
+        stream,
+        ListenerKind::Client,
+    ));
+    RUNTIME
+        .block_on(willow.write_all(b"NICK willow\r\nUSER guest 0 * :Willow Park\r\nJOIN #a\r\n"))
+        .unwrap();
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        ":willow!guest@127.0.0.1 JOIN #a\r\n"
+    );
+
+    // willow is cut off part way through, so not all of this gets written
+    let pings = format!("PING {}\r\n", "a".repeat(400)).repeat(10);
+    RUNTIME.spawn(async move { willow.write_all(pings.as_bytes()).await });
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        ":willow!guest@127.0.0.1 QUIT :Max SendQ exceeded\r\n"
+    );
+    RUNTIME.block_on(handle).unwrap().unwrap();
+    assert!(state.read().unwrap().find_client("willow").is_none());
+}
+
+#[test]
+fn quitting_clients_that_never_read_are_let_go() {
+    use crate::server::stream::Stream;
+    use std::sync::{Arc, RwLock};
+    use tokio::io::AsyncWriteExt;
+
+    let (state, _path) = test_state();
+    let state = Arc::new(RwLock::new(state));
+    // willow never reads, so her welcome burst can never all be written
+    let (mut willow, server_end) = tokio::io::duplex(64);
+    let stream = Stream {
+        transport: Box::new(server_end),
+        peer_host: "127.0.0.1".to_owned(),
+        secure: false,
+    };
+    let handle = RUNTIME.spawn(handle_client(
+        Arc::clone(&state),
+        stream,
+        ListenerKind::Client,
+    ));
+    RUNTIME
+        .block_on(willow.write_all(b"NICK willow\r\nUSER guest 0 * :Willow Park\r\nQUIT\r\n"))
+        .unwrap();
+
+    let wait = GOODBYE_TIMEOUT + std::time::Duration::from_secs(5);
+    let finished = RUNTIME.block_on(async { tokio::time::timeout(wait, handle).await });
+    finished.unwrap().unwrap().unwrap();
+    assert!(state.read().unwrap().connections().is_empty());
+}
+
+#[test]
+fn queries_only_need_a_read_lock() {
+    use crate::server::stream::Stream;
+    use std::sync::{Arc, RwLock};
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+    let (state, _path) = test_state();
+    let state = Arc::new(RwLock::new(state));
+    let (client_end, server_end) = tokio::io::duplex(4096);
+    let stream = Stream {
+        transport: Box::new(server_end),
+        peer_host: "127.0.0.1".to_owned(),
+        secure: false,
+    };
+    RUNTIME.spawn(handle_client(
+        Arc::clone(&state),
+        stream,
+        ListenerKind::Client,
+    ));
+    let (reader, mut writer) = tokio::io::split(client_end);
+    let mut lines = tokio::io::BufReader::new(reader).lines();
+    RUNTIME
+        .block_on(writer.write_all(b"NICK willow\r\nUSER guest 0 * :Willow Park\r\n"))
+        .unwrap();
+    for _ in 0..5 {
+        RUNTIME.block_on(lines.next_line()).unwrap();
+    }
+
+    // Answered while someone else is reading the state
+    let reading = state.read().unwrap();
+    RUNTIME
+        .block_on(writer.write_all(b"PING token\r\nWHO willow\r\n"))
+        .unwrap();
+    let replies = RUNTIME.block_on(async {
+        let mut replies = Vec::new();
+        for _ in 0..3 {
+            let next = tokio::time::timeout(std::time::Duration::from_secs(5), lines.next_line());
+            replies.push(next.await.unwrap().unwrap().unwrap());
+        }
+        replies
+    });
+    drop(reading);
+    assert_eq!(
+        replies,
+        [
+            ":irc.localhost PONG irc.localhost token",
+            ":irc.localhost 352 willow * guest 127.0.0.1 irc.localhost willow H :0 Willow Park",
+            ":irc.localhost 315 willow willow :End of WHO list",
+        ]
+    );
+}
+
+#[test]
+fn lines_are_split_and_bounded() {
+    use crate::server::line::{Line, LineReader, MAX_LINE_LEN};
+
+    let long = "a".repeat(MAX_LINE_LEN);
+    let input = format!("PING a\r\nPING b\n{long}\r\n\r\n{}\nPING c", &long[2..]);
+    // A small buffer makes the long line arrive in pieces
+    let mut lines = LineReader::new(tokio::io::BufReader::with_capacity(16, input.as_bytes()));
+    let mut next = || RUNTIME.block_on(lines.next_line()).unwrap();
+    assert_eq!(next(), Line::Complete(b"PING a".to_vec()));
+    assert_eq!(next(), Line::Complete(b"PING b".to_vec()));
+    assert_eq!(next(), Line::TooLong);
+    assert_eq!(next(), Line::Complete(Vec::new()));
+    // 510 bytes, which just fits once the CRLF is added back
+    assert_eq!(next(), Line::Complete(long.as_bytes()[2..].to_vec()));
+    assert_eq!(next(), Line::Complete(b"PING c".to_vec()));
+    assert_eq!(next(), Line::Eof);
+}
+
+#[test]
+fn invalid_utf8_policies() {
+    use crate::config::InvalidUtf8;
+    use crate::server::line::decode;
+
+    let line = b"PRIVMSG #a :caf\xe9".to_vec();
+    assert_eq!(decode(line.clone(), InvalidUtf8::Reject), None);
+    assert_eq!(
+        decode(line.clone(), InvalidUtf8::Lossy).unwrap(),
+        "PRIVMSG #a :caf\u{fffd}"
+    );
+    assert_eq!(
+        decode(line, InvalidUtf8::Latin1).unwrap(),
+        "PRIVMSG #a :café"
+    );
+    assert_eq!(
+        decode("café".as_bytes().to_vec(), InvalidUtf8::Reject).unwrap(),
+        "café"
+    );
+    assert_eq!("lossy".parse::<InvalidUtf8>().unwrap(), InvalidUtf8::Lossy);
+    assert!("ascii".parse::<InvalidUtf8>().is_err());
+}
+
+#[test]
+fn overlong_lines_are_rejected() {
+    use crate::server::stream::Stream;
+    use std::sync::{Arc, RwLock};
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+    let (state, _path) = test_state();
+    let state = Arc::new(RwLock::new(state));
+    let (client_end, server_end) = tokio::io::duplex(4096);
+    let stream = Stream {
+        transport: Box::new(server_end),
+        peer_host: "127.0.0.1".to_owned(),
+        secure: false,
+    };
+    RUNTIME.spawn(handle_client(state, stream, ListenerKind::Client));
+
+    let (reader, mut writer) = tokio::io::split(client_end);
+    let mut reader = tokio::io::BufReader::new(reader);
+    let mut read_reply = || {
+        let mut line = String::new();
+        RUNTIME.block_on(reader.read_line(&mut line)).unwrap();
+        line
+    };
+    let line = format!(
+        "NICK {}\r\nNICK amity\nUSER guest 0 * :Amity\r\n",
+        "a".repeat(600)
+    );
+    RUNTIME.block_on(writer.write_all(line.as_bytes())).unwrap();
+    assert_eq!(
+        read_reply(),
+        ":irc.localhost 417 * :Input line was too long\r\n"
+    );
+    assert!(read_reply().starts_with(":irc.localhost 001 amity "));
+}
+
+#[test]
+fn poisoned_state_closes_connections() {
+    use crate::server::stream::Stream;
+    use std::sync::{Arc, RwLock};
+
+    let (state, _path) = test_state();
+    let state = Arc::new(RwLock::new(state));
+    let poisoner = Arc::clone(&state);
+    std::thread::spawn(move || {
+        let _state = poisoner.write().unwrap();
+        panic!("poisoning the state lock");
+    })
+    .join()
+    .unwrap_err();
+
+    let (_client_end, server_end) = tokio::io::duplex(4096);
+    let stream = Stream {
+        transport: Box::new(server_end),
+        peer_host: "127.0.0.1".to_owned(),
+        secure: false,
+    };
+    let closed = RUNTIME.block_on(handle_client(state, stream, ListenerKind::Client));
+    assert_eq!(
+        closed.unwrap_err().to_string(),
+        "The state lock is poisoned"
+    );
+}
+
+#[test]
+fn flood_penalty_clock() {
+    use crate::config::FloodLimits;
+    use crate::server::flood::FloodControl;
+    use std::time::Duration;
+    use tokio::time::Instant;
+
+    let mut flood = FloodControl::new(FloodLimits {
+        burst: 3,
+        interval: Duration::from_secs(1),
+        excess: 2,
+    });
+    let now = Instant::now();
+    for _ in 0..3 {
+        assert_eq!(flood.charge(1, now), Some(now));
+    }
+    assert_eq!(flood.charge(1, now), Some(now + Duration::from_secs(1)));
+    assert_eq!(flood.charge(1, now), Some(now + Duration::from_secs(2)));
+    assert_eq!(flood.charge(1, now), None);
+    // Waiting pays the penalty off
+    let later = now + Duration::from_secs(10);
+    assert_eq!(flood.charge(3, later), Some(later));
+}
+
+/// Sends `count` PINGs in one go from a client with a burst of 5 and an excess of 5, and
+/// returns the replies up to the connection closing
+fn ping_flood(flood_exempt: Vec<String>, count: usize) -> Vec<String> {
+    use crate::config::{FloodClasses, FloodLimits};
+    use crate::server::stream::Stream;
+    use std::sync::{Arc, RwLock};
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+    let limits = FloodLimits {
+        burst: 5,
+        interval: std::time::Duration::from_secs(60),
+        excess: 5,
+    };
+    let path = state_path(&format!("flood-{}", flood_exempt.len()));
+    let _ = std::fs::remove_file(&path);
+    let state = State::build(&crate::Config {
+        state_path: path.to_path_buf(),
+        flood: FloodClasses {
+            client: limits,
+            admin: limits,
+        },
+        flood_exempt,
+        ..Default::default()
+    })
+    .unwrap();
+    let (client_end, server_end) = tokio::io::duplex(64 * 1024);
+    let stream = Stream {
+        transport: Box::new(server_end),
+        peer_host: "127.0.0.1".to_owned(),
+        secure: false,
+    };
+    let state = Arc::new(RwLock::new(state));
+    RUNTIME.spawn(handle_client(state, stream, ListenerKind::Client));
+
+    let (reader, mut writer) = tokio::io::split(client_end);
+    RUNTIME
+        .block_on(writer.write_all("PING token\r\n".repeat(count).as_bytes()))
+        .unwrap();
+    RUNTIME.block_on(async {
+        let mut replies = Vec::new();
+        let mut lines = tokio::io::BufReader::new(reader).lines();
+        while let Ok(Ok(Some(line))) =
+            tokio::time::timeout(std::time::Duration::from_secs(1), lines.next_line()).await
+        {
+            replies.push(line);
+        }
+        replies
+    })
+}
+
+#[test]
+fn flooding_clients_are_held_back_then_disconnected() {
+    let replies = ping_flood(Vec::new(), 20);
+    let pong = ":irc.localhost PONG irc.localhost token";
+    assert_eq!(replies[..5], [pong; 5]);
+    assert_eq!(replies[5], "ERROR :Closing Link: 127.0.0.1 (Excess Flood)");
+    assert_eq!(replies.len(), 6);
+
+    // Trusted hosts are never held back
+    let replies = ping_flood(vec!["127.0.0.1".to_owned()], 20);
+    assert_eq!(replies, [pong; 20]);
+}
+
+#[test]
+fn ipv6_hosts_never_start_with_a_colon() {
+    use crate::server::stream::peer_host;
+
+    let host = |ip: &str| peer_host(ip.parse().unwrap());
+    assert_eq!(host("127.0.0.1"), "127.0.0.1");
+    assert_eq!(host("::1"), "0::1");
+    assert_eq!(host("::ffff:10.0.0.1"), "0::ffff:10.0.0.1");
+    assert_eq!(host("2001:db8::1"), "2001:db8::1");
+
+    // Exempt addresses are still matched however they are written in the config
+    let path = state_path("ipv6-exempt");
+    let state = State::build(&crate::Config {
+        state_path: path.to_path_buf(),
+        flood_exempt: vec!["::1".to_owned(), "localhost".to_owned()],
+        ..Default::default()
+    })
+    .unwrap();
+    assert!(state.is_flood_exempt("0::1"));
+    assert!(state.is_flood_exempt("localhost"));
+    assert!(!state.is_flood_exempt("0::2"));
+}
+
+#[test]
+fn flood_limits_from_config_file() {
+    use crate::{Action, Config};
+
+    let files = state_path("flood-config");
+    let path = files.with_extension("toml");
+    std::fs::write(
+        &path,
+        "flood_exempt = [\"10.0.0.1\"]\n[flood.client]\nburst = 4\ninterval = \"2s\"\n",
+    )
+    .unwrap();
+    let Action::Run(config) =
+        Config::from_args(args(&["-c", path.to_str().unwrap()]), |_| None).unwrap()
+    else {
+        panic!("expected to run");
+    };
+    assert_eq!(config.flood.client.burst, 4);
+    assert_eq!(
+        config.flood.client.interval,
+        std::time::Duration::from_secs(2)
+    );
+    assert_eq!(
+        config.flood.client.excess,
+        state::DEFAULT_CLIENT_FLOOD.excess
+    );
+    assert_eq!(config.flood.admin, state::DEFAULT_ADMIN_FLOOD);
+    assert_eq!(config.flood_exempt, ["10.0.0.1"]);
+
+    std::fs::write(&path, "[flood.client]\nburst = 0\n").unwrap();
+    assert!(Config::from_args(args(&["-c", path.to_str().unwrap()]), |_| None).is_err());
+}
+
+#[test]
+fn parse_mode() {
+    let mut line = "MODE #a +kl-n key 10".to_owned();
+    let command = try_parse_from_line(&mut line).unwrap();
+    assert_eq!(
+        command.kind,
+        CommandKind::Mode {
+            target: "#a".to_owned(),
+            modes: Some("+kl-n".to_owned()),
+            params: vec!["key".to_owned(), "10".to_owned()],
+        }
+    );
+    assert_eq!(command.to_wire(), "MODE #a +kl-n key 10\r\n");
+    assert_eq!(parse_error_code("MODE"), Some(461));
+}
+
+#[test]
+fn channel_modes_are_set_and_queried() {
+    let (mut state, _path) = test_state();
+    let (mut amity, mut amity_reader) = registered_client(&mut state, "amity");
+    let (mut luz, mut luz_reader) = registered_client(&mut state, "luz");
+    run(&mut state, &mut amity, "JOIN #a").unwrap();
+    run(&mut state, &mut luz, "JOIN #a").unwrap();
+    for _ in 0..4 {
+        read_reply(&mut amity_reader);
+    }
+    for _ in 0..3 {
+        read_reply(&mut luz_reader);
+    }
+
+    run(&mut state, &mut amity, "MODE #a").unwrap();
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        ":irc.localhost 324 amity #a +nt\r\n"
+    );
+
+    // Modes that change nothing aren't announced
+    run(&mut state, &mut amity, "MODE #a +kln-t+x secret 2").unwrap();
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        ":irc.localhost 472 amity x :is unknown mode char to me\r\n"
+    );
+    let change = ":amity!guest@127.0.0.1 MODE #a +kl-t secret 2\r\n";
+    assert_eq!(read_reply(&mut amity_reader), change);
+    assert_eq!(read_reply(&mut luz_reader), change);
+
+    run(&mut state, &mut amity, "MODE #a +k other").unwrap();
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        ":irc.localhost 467 amity #a :Channel key already set\r\n"
+    );
+    run(&mut state, &mut luz, "MODE #a").unwrap();
+    assert_eq!(
+        read_reply(&mut luz_reader),
+        ":irc.localhost 324 luz #a +kln secret 2\r\n"
+    );
+
+    run(&mut state, &mut luz, "MODE #a -k secret").unwrap();
+    assert_eq!(
+        read_reply(&mut luz_reader),
+        ":irc.localhost 482 luz #a :You're not channel operator\r\n"
+    );
+    run(&mut state, &mut amity, "MODE #a -k wrong").unwrap();
+    assert_eq!(
+        read_reply(&mut luz_reader),
+        ":amity!guest@127.0.0.1 MODE #a -k *\r\n"
+    );
+}
+
+#[test]
+fn hidden_channel_modes_are_kept_from_outsiders() {
+    let (mut state, _path) = test_state();
+    let (mut amity, mut amity_reader) = registered_client(&mut state, "amity");
+    let (mut luz, mut luz_reader) = registered_client(&mut state, "luz");
+    run(&mut state, &mut amity, "JOIN #a,#secret").unwrap();
+    run(&mut state, &mut amity, "MODE #a +k key").unwrap();
+    run(&mut state, &mut amity, "MODE #secret +sk key").unwrap();
+    for _ in 0..8 {
+        read_reply(&mut amity_reader);
+    }
+
+    let err = run(&mut state, &mut luz, "MODE #secret").unwrap_err();
+    assert_eq!(err.downcast_ref::<IrcError>().unwrap().numeric_code(), 442);
+    // Other channels' modes are still on show, apart from the key
+    run(&mut state, &mut luz, "MODE #a").unwrap();
+    assert_eq!(
+        read_reply(&mut luz_reader),
+        ":irc.localhost 324 luz #a +knt *\r\n"
+    );
+    run(&mut state, &mut amity, "MODE #secret").unwrap();
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        ":irc.localhost 324 amity #secret +knst key\r\n"
+    );
+}
+
+#[test]
+fn channel_modes_are_enforced() {
+    let (mut state, _path) = test_state();
+    let (mut amity, mut amity_reader) = registered_client(&mut state, "amity");
+    let (mut luz, mut luz_reader) = registered_client(&mut state, "luz");
+    let (mut eda, mut eda_reader) = registered_client(&mut state, "eda");
+    run(&mut state, &mut amity, "JOIN #a").unwrap();
+    run(&mut state, &mut amity, "MODE #a +kl secret 2").unwrap();
+    for _ in 0..4 {
+        read_reply(&mut amity_reader);
+    }
+
+    run(&mut state, &mut luz, "JOIN #a").unwrap();
+    assert_eq!(
+        read_reply(&mut luz_reader),
+        ":irc.localhost 475 luz #a :Cannot join channel (+k)\r\n"
+    );
+    run(&mut state, &mut luz, "JOIN #a secret").unwrap();
+    assert_eq!(
+        read_reply(&mut luz_reader),
+        ":luz!guest@127.0.0.1 JOIN #a\r\n"
+    );
+    run(&mut state, &mut eda, "JOIN #a secret").unwrap();
+    assert_eq!(
+        read_reply(&mut eda_reader),
+        ":irc.localhost 471 eda #a :Cannot join channel (+l)\r\n"
+    );
+    run(&mut state, &mut amity, "MODE #a -l+i").unwrap();
+    run(&mut state, &mut eda, "JOIN #a secret").unwrap();
+    assert_eq!(
+        read_reply(&mut eda_reader),
+        ":irc.localhost 473 eda #a :Cannot join channel (+i)\r\n"
+    );
+
+    // +n keeps eda's messages out, until it is taken off
+    run(&mut state, &mut eda, "PRIVMSG #a :hi").unwrap();
+    assert_eq!(
+        read_reply(&mut eda_reader),
+        ":irc.localhost 404 eda #a :Cannot send to channel\r\n"
+    );
+    run(&mut state, &mut amity, "MODE #a -n").unwrap();
+    for _ in 0..3 {
+        read_reply(&mut amity_reader);
+    }
+    run(&mut state, &mut eda, "PRIVMSG #a :hi").unwrap();
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        ":eda!guest@127.0.0.1 PRIVMSG #a :hi\r\n"
+    );
+    // +m keeps eda out even without +n
+    run(&mut state, &mut amity, "MODE #a +m").unwrap();
+    read_reply(&mut amity_reader);
+    run(&mut state, &mut eda, "PRIVMSG #a :hi again").unwrap();
+    assert_eq!(
+        read_reply(&mut eda_reader),
+        ":irc.localhost 404 eda #a :Cannot send to channel\r\n"
+    );
+    // Only operators may speak while the channel is moderated
+    // The rest of luz's join, three mode changes and eda's message
+    for _ in 0..6 {
+        read_reply(&mut luz_reader);
+    }
+    run(&mut state, &mut luz, "PRIVMSG #a :hi").unwrap();
+    assert_eq!(
+        read_reply(&mut luz_reader),
+        ":irc.localhost 404 luz #a :Cannot send to channel\r\n"
+    );
+    run(&mut state, &mut amity, "PRIVMSG #a :quiet please").unwrap();
+    assert_eq!(
+        read_reply(&mut luz_reader),
+        ":amity!guest@127.0.0.1 PRIVMSG #a :quiet please\r\n"
+    );
+}
+
+#[test]
+fn channel_key_and_limit_are_saved() {
+    let path = state_path("modes");
+    std::fs::write(&path, SAVED_STATE).unwrap();
+    let mut state = build_state(&path).unwrap();
+    let (mut amity, _amity_reader) = registered_client(&mut state, "amity");
+    run(&mut state, &mut amity, "JOIN #hexside").unwrap();
+    run(&mut state, &mut amity, "MODE #hexside +sl 5").unwrap();
+    drop(state);
+
+    // Only journaled so far, as if the server had crashed
+    let state = build_state(&path).unwrap();
+    let channel = state.find_channel("#hexside").unwrap();
+    assert_eq!(channel.limit, Some(5));
+    assert!(channel.modes.contains(&'s'));
+}
+
+#[test]
+fn masks_are_normalized() {
+    assert_eq!(state::normalize_mask("luz"), "luz!*@*");
+    assert_eq!(
+        state::normalize_mask("*@boiling.isles"),
+        "*!*@boiling.isles"
+    );
+    assert_eq!(state::normalize_mask("luz!*"), "luz!*@*");
+    assert_eq!(state::normalize_mask("*!*@*.isles"), "*!*@*.isles");
+}
+
+#[test]
+fn channel_lists_are_set_and_listed() {
+    let (mut state, _path) = test_state();
+    let (mut amity, mut amity_reader) = registered_client(&mut state, "amity");
+    let (mut luz, mut luz_reader) = registered_client(&mut state, "luz");
+    run(&mut state, &mut amity, "JOIN #a").unwrap();
+    for _ in 0..3 {
+        read_reply(&mut amity_reader);
+    }
+
+    run(&mut state, &mut amity, "MODE #a +be luz *@boiling.isles").unwrap();
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        ":amity!guest@127.0.0.1 MODE #a +be luz!*@* *!*@boiling.isles\r\n"
+    );
+    // Anyone may see the lists, but only operators may change them
+    run(&mut state, &mut luz, "MODE #a b").unwrap();
+    let entry = read_reply(&mut luz_reader);
+    assert!(
+        entry.starts_with(":irc.localhost 367 luz #a luz!*@* amity!guest@127.0.0.1 "),
+        "{entry}"
+    );
+    assert_eq!(
+        read_reply(&mut luz_reader),
+        ":irc.localhost 368 luz #a :End of channel ban list\r\n"
+    );
+    run(&mut state, &mut luz, "MODE #a +I luz").unwrap();
+    assert_eq!(
+        read_reply(&mut luz_reader),
+        ":irc.localhost 482 luz #a :You're not channel operator\r\n"
+    );
+    run(&mut state, &mut luz, "MODE #a I").unwrap();
+    assert_eq!(
+        read_reply(&mut luz_reader),
+        ":irc.localhost 347 luz #a :End of channel invite list\r\n"
+    );
+
+    // Masks are removed however they are written
+    run(&mut state, &mut amity, "MODE #a -b LUZ!*@*").unwrap();
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        ":amity!guest@127.0.0.1 MODE #a -b LUZ!*@*\r\n"
+    );
+    let channel = state.find_channel("#a").unwrap();
+    assert!(channel.bans.is_empty());
+    assert_eq!(channel.exceptions[0].mask, "*!*@boiling.isles");
+}
+
+#[test]
+fn channel_lists_are_limited() {
+    let path = state_path("list-limit");
+    let mut state = State::build(&crate::Config {
+        state_path: path.to_path_buf(),
+        max_list_entries: 1,
+        ..Default::default()
+    })
+    .unwrap();
+    let (mut amity, mut amity_reader) = registered_client(&mut state, "amity");
+    run(&mut state, &mut amity, "JOIN #a").unwrap();
+    for _ in 0..3 {
+        read_reply(&mut amity_reader);
+    }
+
+    run(&mut state, &mut amity, "MODE #a +bb luz eda").unwrap();
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        ":irc.localhost 478 amity #a b :Channel list is full\r\n"
+    );
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        ":amity!guest@127.0.0.1 MODE #a +b luz!*@*\r\n"
+    );
+    // Each list has its own limit
+    run(&mut state, &mut amity, "MODE #a +e eda").unwrap();
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        ":amity!guest@127.0.0.1 MODE #a +e eda!*@*\r\n"
+    );
+}
+
+#[test]
+fn channel_lists_are_enforced() {
+    let (mut state, _path) = test_state();
+    let (mut amity, mut amity_reader) = registered_client(&mut state, "amity");
+    let (mut luz, mut luz_reader) = registered_client(&mut state, "luz");
+    let (mut eda, mut eda_reader) = registered_client(&mut state, "eda");
+    run(&mut state, &mut amity, "JOIN #a").unwrap();
+    run(&mut state, &mut eda, "JOIN #a").unwrap();
+    run(&mut state, &mut amity, "MODE #a +b *!guest@*").unwrap();
+    for _ in 0..5 {
+        read_reply(&mut amity_reader);
+    }
+    for _ in 0..4 {
+        read_reply(&mut eda_reader);
+    }
+
+    run(&mut state, &mut luz, "JOIN #a").unwrap();
+    assert_eq!(
+        read_reply(&mut luz_reader),
+        ":irc.localhost 474 luz #a :Cannot join channel (+b)\r\n"
+    );
+    // Banned members can't speak or change their nickname, operators aren't held back
+    run(&mut state, &mut eda, "PRIVMSG #a :hi").unwrap();
+    assert_eq!(
+        read_reply(&mut eda_reader),
+        ":irc.localhost 404 eda #a :Cannot send to channel\r\n"
+    );
+    let err = run(&mut state, &mut eda, "NICK owl").unwrap_err();
+    assert_eq!(err.downcast_ref::<IrcError>().unwrap().numeric_code(), 435);
+    run(&mut state, &mut amity, "PRIVMSG #a :hi").unwrap();
+    assert_eq!(
+        read_reply(&mut eda_reader),
+        ":amity!guest@127.0.0.1 PRIVMSG #a :hi\r\n"
+    );
+
+    // An exception lets luz in despite the ban
+    run(&mut state, &mut amity, "MODE #a +e luz").unwrap();
+    run(&mut state, &mut luz, "JOIN #a").unwrap();
+    assert_eq!(
+        read_reply(&mut luz_reader),
+        ":luz!guest@127.0.0.1 JOIN #a\r\n"
+    );
+    for _ in 0..2 {
+        read_reply(&mut luz_reader);
+    }
+    // but not under a nickname the exception doesn't cover
+    let err = run(&mut state, &mut luz, "NICK hunter").unwrap_err();
+    assert_eq!(err.downcast_ref::<IrcError>().unwrap().numeric_code(), 435);
+
+    // An invite exception gets past +i, but not past a ban
+    run(&mut state, &mut amity, "MODE #a -b+iI *!guest@* hunter").unwrap();
+    run(&mut state, &mut amity, "MODE #a +b hunter").unwrap();
+    let (mut hunter, mut hunter_reader) = registered_client(&mut state, "hunter");
+    run(&mut state, &mut hunter, "JOIN #a").unwrap();
+    assert_eq!(
+        read_reply(&mut hunter_reader),
+        ":irc.localhost 474 hunter #a :Cannot join channel (+b)\r\n"
+    );
+    run(&mut state, &mut amity, "MODE #a -b hunter").unwrap();
+    run(&mut state, &mut hunter, "JOIN #a").unwrap();
+    assert_eq!(
+        read_reply(&mut hunter_reader),
+        ":hunter!guest@127.0.0.1 JOIN #a\r\n"
+    );
+    let (mut willow, mut willow_reader) = registered_client(&mut state, "willow");
+    run(&mut state, &mut willow, "JOIN #a").unwrap();
+    assert_eq!(
+        read_reply(&mut willow_reader),
+        ":irc.localhost 473 willow #a :Cannot join channel (+i)\r\n"
+    );
+}
+
+#[test]
+fn parse_kick_and_who() {
+    let mut line = "KICK #a,#b luz,eda :Out".to_owned();
+    let command = try_parse_from_line(&mut line).unwrap();
+    assert_eq!(
+        command.kind,
+        CommandKind::Kick {
+            channels: vec!["#a".to_owned(), "#b".to_owned()],
+            users: vec!["luz".to_owned(), "eda".to_owned()],
+            comment: Some("Out".to_owned()),
+        }
+    );
+    assert_eq!(command.to_wire(), "KICK #a,#b luz,eda :Out\r\n");
+    assert_eq!(parse_error_code("KICK #a"), Some(461));
+    // Two channels can't be shared between three users
+    assert_eq!(parse_error_code("KICK #a,#b luz,eda,king"), Some(461));
+
+    let mut line = "WHO #a o".to_owned();
+    let command = try_parse_from_line(&mut line).unwrap();
+    assert_eq!(
+        command.kind,
+        CommandKind::Who {
+            mask: Some("#a".to_owned()),
+            operators: true,
+        }
+    );
+    assert_eq!(command.to_wire(), "WHO #a o\r\n");
+}
+
+#[test]
+fn channel_ranks_are_given_and_shown() {
+    let (mut state, _path) = test_state();
+    let (mut amity, mut amity_reader) = registered_client(&mut state, "amity");
+    let (mut luz, mut luz_reader) = registered_client(&mut state, "luz");
+    run(&mut state, &mut amity, "JOIN #a").unwrap();
+    run(&mut state, &mut luz, "JOIN #a").unwrap();
+    run(&mut state, &mut amity, "MODE #a +mv LUZ").unwrap();
+    for _ in 0..4 {
+        read_reply(&mut amity_reader);
+    }
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        ":amity!guest@127.0.0.1 MODE #a +mv luz\r\n"
+    );
+    for _ in 0..4 {
+        read_reply(&mut luz_reader);
+    }
+
+    run(&mut state, &mut luz, "WHO #a").unwrap();
+    assert_eq!(
+        read_reply(&mut luz_reader),
+        ":irc.localhost 352 luz #a guest 127.0.0.1 irc.localhost amity H@ :0 Test User\r\n"
+    );
+    assert_eq!(
+        read_reply(&mut luz_reader),
+        ":irc.localhost 352 luz #a guest 127.0.0.1 irc.localhost luz H+ :0 Test User\r\n"
+    );
+    assert_eq!(
+        read_reply(&mut luz_reader),
+        ":irc.localhost 315 luz #a :End of WHO list\r\n"
+    );
+
+    // Voice lets luz speak while the channel is moderated, but not change its modes
+    run(&mut state, &mut luz, "PRIVMSG #a :hi").unwrap();
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        ":luz!guest@127.0.0.1 PRIVMSG #a :hi\r\n"
+    );
+    run(&mut state, &mut luz, "MODE #a +o luz").unwrap();
+    assert_eq!(
+        read_reply(&mut luz_reader),
+        ":irc.localhost 482 luz #a :You're not channel operator\r\n"
+    );
+    // Halfop isn't on offer without extended ranks
+    run(&mut state, &mut amity, "MODE #a +h luz").unwrap();
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        ":irc.localhost 472 amity h :is unknown mode char to me\r\n"
+    );
+    run(&mut state, &mut amity, "MODE #a +o eda").unwrap();
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        ":irc.localhost 401 amity eda :No such nick/channel\r\n"
+    );
+
+    run(&mut state, &mut amity, "MODE #a -v+o luz luz").unwrap();
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        ":amity!guest@127.0.0.1 MODE #a -v+o luz luz\r\n"
+    );
+    let channel = state.find_channel("#a").unwrap();
+    assert_eq!(channel.names(|_| true), ["@amity", "@luz"]);
+}
+
+#[test]
+fn members_are_kicked_by_rank() {
+    let (mut state, _path) = test_state();
+    let (mut amity, mut amity_reader) = registered_client(&mut state, "amity");
+    let (mut luz, mut luz_reader) = registered_client(&mut state, "luz");
+    run(&mut state, &mut amity, "JOIN #a").unwrap();
+    run(&mut state, &mut luz, "JOIN #a").unwrap();
+    for _ in 0..4 {
+        read_reply(&mut amity_reader);
+    }
+    for _ in 0..3 {
+        read_reply(&mut luz_reader);
+    }
+
+    run(&mut state, &mut luz, "KICK #a amity").unwrap();
+    assert_eq!(
+        read_reply(&mut luz_reader),
+        ":irc.localhost 482 luz #a :You're not channel operator\r\n"
+    );
+    run(&mut state, &mut amity, "KICK #a,#b eda,luz").unwrap();
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        ":irc.localhost 441 amity eda #a :They aren't on that channel\r\n"
+    );
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        ":irc.localhost 403 amity #b :No such channel\r\n"
+    );
+    run(&mut state, &mut amity, "KICK #a Luz").unwrap();
+    let kick = ":amity!guest@127.0.0.1 KICK #a luz :amity\r\n";
+    assert_eq!(read_reply(&mut amity_reader), kick);
+    assert_eq!(read_reply(&mut luz_reader), kick);
+    assert!(!state.find_channel("#a").unwrap().is_member("luz"));
+
+    run(&mut state, &mut luz, "KICK #a amity :bye").unwrap();
+    assert_eq!(
+        read_reply(&mut luz_reader),
+        ":irc.localhost 442 luz #a :You're not on that channel\r\n"
+    );
+}
+
+#[test]
+fn extended_ranks() {
+    let path = state_path("extended-ranks");
+    let mut state = State::build(&crate::Config {
+        state_path: path.to_path_buf(),
+        extended_ranks: true,
+        ..Default::default()
+    })
+    .unwrap();
+    let (mut client, mut reader) = test_client();
+    run(&mut state, &mut client, "NICK amity").unwrap();
+    run(&mut state, &mut client, "USER guest 0 * :Test User").unwrap();
+    for _ in 0..3 {
+        read_reply(&mut reader);
+    }
+    assert!(read_reply(&mut reader).ends_with(" abehIiklmnopqstv\r\n"));
+    assert!(read_reply(&mut reader).contains(" PREFIX=(qaohv)~&@%+ "));
+    let (mut amity, mut amity_reader) = (client, reader);
+    let (mut luz, mut luz_reader) = registered_client(&mut state, "luz");
+    let (mut eda, _eda_reader) = registered_client(&mut state, "eda");
+    run(&mut state, &mut amity, "JOIN #a").unwrap();
+    run(&mut state, &mut luz, "JOIN #a").unwrap();
+    run(&mut state, &mut eda, "JOIN #a").unwrap();
+    for _ in 0..5 {
+        read_reply(&mut amity_reader);
+    }
+    for _ in 0..4 {
+        read_reply(&mut luz_reader);
+    }
+
+    // Only owners make owners, but operators hand out halfop
+    run(&mut state, &mut amity, "MODE #a +qh amity luz").unwrap();
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        ":irc.localhost 482 amity #a :You're not channel operator\r\n"
+    );
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        ":amity!guest@127.0.0.1 MODE #a +h luz\r\n"
+    );
+    read_reply(&mut luz_reader);
+    // A halfop gives voice and kicks those below it, but not operators
+    run(&mut state, &mut luz, "MODE #a +vo eda eda").unwrap();
+    assert_eq!(
+        read_reply(&mut luz_reader),
+        ":irc.localhost 482 luz #a :You're not channel operator\r\n"
+    );
+    assert_eq!(
+        read_reply(&mut luz_reader),
+        ":luz!guest@127.0.0.1 MODE #a +v eda\r\n"
+    );
+    run(&mut state, &mut luz, "KICK #a amity").unwrap();
+    assert_eq!(
+        read_reply(&mut luz_reader),
+        ":irc.localhost 482 luz #a :You're not channel operator\r\n"
+    );
+    assert_eq!(
+        state.find_channel("#a").unwrap().names(|_| true),
+        ["@amity", "+eda", "%luz"]
+    );
+    run(&mut state, &mut luz, "KICK #a eda :quiet").unwrap();
+    assert_eq!(
+        read_reply(&mut luz_reader),
+        ":luz!guest@127.0.0.1 KICK #a eda :quiet\r\n"
+    );
+}
+
+#[test]
+fn who_matches_masks() {
+    let (mut state, _path) = test_state();
+    let (mut amity, mut amity_reader) = registered_client(&mut state, "amity");
+    let (mut luz, _luz_reader) = registered_client(&mut state, "luz");
+    let (_willow, _willow_reader) = registered_client(&mut state, "willow");
+
+    run(&mut state, &mut amity, "WHO *l*").unwrap();
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        ":irc.localhost 352 amity * guest 127.0.0.1 irc.localhost luz H :0 Test User\r\n"
+    );
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        ":irc.localhost 352 amity * guest 127.0.0.1 irc.localhost willow H :0 Test User\r\n"
+    );
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        ":irc.localhost 315 amity *l* :End of WHO list\r\n"
+    );
+
+    // Secret channels keep their members to themselves
+    run(&mut state, &mut luz, "JOIN #secret").unwrap();
+    run(&mut state, &mut luz, "MODE #secret +s").unwrap();
+    run(&mut state, &mut amity, "WHO #secret").unwrap();
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        ":irc.localhost 315 amity #secret :End of WHO list\r\n"
+    );
+}
+
+#[test]
+fn user_modes_from_registration() {
+    let (mut state, _path) = test_state();
+    let (mut client, mut reader) = test_client();
+    run(&mut state, &mut client, "NICK amity").unwrap();
+    run(&mut state, &mut client, "USER guest 12 * :Test User").unwrap();
+    for _ in 0..5 {
+        read_reply(&mut reader);
+    }
+    run(&mut state, &mut client, "MODE amity").unwrap();
+    assert_eq!(read_reply(&mut reader), ":irc.localhost 221 amity +iw\r\n");
+    assert!(state.find_client("amity").unwrap().modes.contains(&'i'));
+
+    let (mut client, mut reader) = test_client();
+    run(&mut state, &mut client, "NICK luz").unwrap();
+    run(&mut state, &mut client, "USER guest 8 * :Test User").unwrap();
+    for _ in 0..5 {
+        read_reply(&mut reader);
+    }
+    run(&mut state, &mut client, "MODE luz").unwrap();
+    assert_eq!(read_reply(&mut reader), ":irc.localhost 221 luz +i\r\n");
+}
+
+#[test]
+fn user_modes_are_changed() {
+    let (mut state, _path) = test_state();
+    let (mut amity, mut amity_reader) = registered_client(&mut state, "amity");
+    let (_luz, _luz_reader) = registered_client(&mut state, "luz");
+
+    // Unknown flags are reported, but don't stop the rest
+    run(&mut state, &mut amity, "MODE Amity +iwx").unwrap_err();
+    let err = run(&mut state, &mut amity, "MODE amity +y").unwrap_err();
+    assert_eq!(err.downcast_ref::<IrcError>().unwrap().numeric_code(), 501);
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        ":amity!guest@127.0.0.1 MODE amity +iw\r\n"
+    );
+    // Nothing that only the server may set, and nothing already set, is echoed back
+    run(&mut state, &mut amity, "MODE amity +orZi-w").unwrap();
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        ":amity!guest@127.0.0.1 MODE amity -w\r\n"
+    );
+    run(&mut state, &mut amity, "MODE amity").unwrap();
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        ":irc.localhost 221 amity +i\r\n"
+    );
+
+    // An operator may give up +o
+    amity.modes.insert('o');
+    run(&mut state, &mut amity, "MODE amity -o").unwrap();
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        ":amity!guest@127.0.0.1 MODE amity -o\r\n"
+    );
+    assert!(!amity.is_oper());
+
+    let err = run(&mut state, &mut amity, "MODE luz +i").unwrap_err();
+    assert_eq!(err.downcast_ref::<IrcError>().unwrap().numeric_code(), 502);
+    let err = run(&mut state, &mut amity, "MODE eda").unwrap_err();
+    assert_eq!(err.downcast_ref::<IrcError>().unwrap().numeric_code(), 401);
+}
+
+#[test]
+fn invisible_clients_are_hidden_from_who() {
+    let (mut state, _path) = test_state();
+    let (mut amity, mut amity_reader) = registered_client(&mut state, "amity");
+    let (mut luz, mut luz_reader) = registered_client(&mut state, "luz");
+    let (mut eda, _eda_reader) = registered_client(&mut state, "eda");
+    run(&mut state, &mut luz, "MODE luz +i").unwrap();
+    run(&mut state, &mut luz, "JOIN #a").unwrap();
+    for _ in 0..4 {
+        read_reply(&mut luz_reader);
+    }
+    eda.modes.insert('o');
+    state.set_user_modes("eda", &eda.modes);
+
+    run(&mut state, &mut amity, "WHO #a").unwrap();
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        ":irc.localhost 315 amity #a :End of WHO list\r\n"
+    );
+    run(&mut state, &mut amity, "WHO *").unwrap();
+    let listed: Vec<String> = (0..3).map(|_| read_reply(&mut amity_reader)).collect();
+    assert_eq!(
+        listed,
+        [
+            ":irc.localhost 352 amity * guest 127.0.0.1 irc.localhost amity H :0 Test User\r\n",
+            ":irc.localhost 352 amity * guest 127.0.0.1 irc.localhost eda H* :0 Test User\r\n",
+            ":irc.localhost 315 amity * :End of WHO list\r\n",
+        ]
+    );
+
+    // Sharing a channel makes luz visible
+    run(&mut state, &mut amity, "JOIN #a").unwrap();
+    for _ in 0..3 {
+        read_reply(&mut amity_reader);
+    }
+    run(&mut state, &mut amity, "WHO luz").unwrap();
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        ":irc.localhost 352 amity * guest 127.0.0.1 irc.localhost luz H :0 Test User\r\n"
+    );
+    read_reply(&mut amity_reader);
+    run(&mut state, &mut amity, "WHO * o").unwrap();
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        ":irc.localhost 352 amity * guest 127.0.0.1 irc.localhost eda H* :0 Test User\r\n"
+    );
+}
+
+#[test]
+fn names_follows_who_visibility() {
+    let (mut state, _path) = test_state();
+    let (mut amity, mut amity_reader) = registered_client(&mut state, "amity");
+    let (mut luz, mut luz_reader) = registered_client(&mut state, "luz");
+    let (mut eda, mut eda_reader) = registered_client(&mut state, "eda");
+    run(&mut state, &mut amity, "JOIN #a,#secret").unwrap();
+    run(&mut state, &mut amity, "MODE #secret +s").unwrap();
+    run(&mut state, &mut eda, "MODE eda +i").unwrap();
+    run(&mut state, &mut eda, "JOIN #a").unwrap();
+    for _ in 0..8 {
+        read_reply(&mut amity_reader);
+    }
+    for _ in 0..4 {
+        read_reply(&mut eda_reader);
+    }
+
+    // Members see everyone, with their ranks
+    run(&mut state, &mut amity, "NAMES #a").unwrap();
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        ":irc.localhost 353 amity = #a :@amity eda\r\n"
+    );
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        ":irc.localhost 366 amity #a :End of NAMES list\r\n"
+    );
+
+    // Others don't see invisible members, or hidden channels at all
+    run(&mut state, &mut luz, "NAMES #a,#secret,#nowhere").unwrap();
+    let listed: Vec<String> = (0..4).map(|_| read_reply(&mut luz_reader)).collect();
+    assert_eq!(
+        listed,
+        [
+            ":irc.localhost 353 luz = #a :@amity\r\n",
+            ":irc.localhost 366 luz #a :End of NAMES list\r\n",
+            ":irc.localhost 366 luz #secret :End of NAMES list\r\n",
+            ":irc.localhost 366 luz #nowhere :End of NAMES list\r\n",
+        ]
+    );
+    run(&mut state, &mut luz, "NAMES").unwrap();
+    assert_eq!(
+        read_reply(&mut luz_reader),
+        ":irc.localhost 366 luz * :End of NAMES list\r\n"
+    );
+
+    let mut line = "NAMES #a,#b".to_owned();
+    let command = try_parse_from_line(&mut line).unwrap();
+    assert_eq!(
+        command.kind,
+        CommandKind::Names {
+            channels: vec!["#a".to_owned(), "#b".to_owned()],
+        }
+    );
+    assert_eq!(command.to_wire(), "NAMES #a,#b\r\n");
+}
+
+#[test]
+fn long_names_replies_are_split_within_the_line_limit() {
+    use crate::server::line::MAX_LINE_LEN;
+
+    let path = state_path("long-names");
+    let mut state = State::build(&crate::Config {
+        state_path: path.to_path_buf(),
+        server_name: format!("{}.example", "s".repeat(55)),
+        ..Default::default()
+    })
+    .unwrap();
+    let (mut amity, mut amity_reader) = registered_client(&mut state, "amity");
+    let channel = format!("#{}", "c".repeat(200));
+    run(&mut state, &mut amity, &format!("JOIN {channel}")).unwrap();
+    for _ in 0..3 {
+        read_reply(&mut amity_reader);
+    }
+    let members: Vec<String> = (0..100).map(|n| format!("member{n:03}")).collect();
+    for member in &members {
+        state.join_channel(&channel, member);
+    }
+
+    run(&mut state, &mut amity, &format!("NAMES {channel}")).unwrap();
+    let mut listed = Vec::new();
+    loop {
+        let line = read_reply(&mut amity_reader);
+        assert!(line.len() <= MAX_LINE_LEN, "{} bytes: {line}", line.len());
+        let Some((_, names)) = line.split_once(" 353 ") else {
+            break;
+        };
+        let (_, names) = names.trim_end().split_once(" :").unwrap();
+        listed.extend(names.split(' ').map(str::to_owned));
+    }
+    assert_eq!(listed.len(), members.len() + 1);
+}
+
+#[test]
+fn parse_topic() {
+    let mut line = "TOPIC #a :Welcome to Hexside".to_owned();
+    let command = try_parse_from_line(&mut line).unwrap();
+    assert_eq!(
+        command.kind,
+        CommandKind::Topic {
+            channel: "#a".to_owned(),
+            topic: Some("Welcome to Hexside".to_owned()),
+        }
+    );
+    assert_eq!(command.to_wire(), "TOPIC #a :Welcome to Hexside\r\n");
+    let mut line = "TOPIC #a :".to_owned();
+    assert_eq!(
+        try_parse_from_line(&mut line).unwrap().kind,
+        CommandKind::Topic {
+            channel: "#a".to_owned(),
+            topic: Some(String::new()),
+        }
+    );
+    assert_eq!(parse_error_code("TOPIC"), Some(461));
+    assert_eq!(parse_error_code("TOPIC a"), Some(403));
+}
+
+#[test]
+fn topic_is_set_queried_and_cleared() {
+    let (mut state, _path) = test_state();
+    let (mut amity, mut amity_reader) = registered_client(&mut state, "amity");
+    let (mut luz, mut luz_reader) = registered_client(&mut state, "luz");
+    let (mut eda, mut eda_reader) = registered_client(&mut state, "eda");
+    run(&mut state, &mut amity, "JOIN #a").unwrap();
+    run(&mut state, &mut luz, "JOIN #a").unwrap();
+    for _ in 0..4 {
+        read_reply(&mut amity_reader);
+    }
+    for _ in 0..3 {
+        read_reply(&mut luz_reader);
+    }
+
+    run(&mut state, &mut luz, "TOPIC #a").unwrap();
+    assert_eq!(
+        read_reply(&mut luz_reader),
+        ":irc.localhost 331 luz #a :No topic is set\r\n"
+    );
+    // Channels start out +t
+    let err = run(&mut state, &mut luz, "TOPIC #a :Witches").unwrap_err();
+    assert_eq!(err.downcast_ref::<IrcError>().unwrap().numeric_code(), 482);
+    let err = run(&mut state, &mut eda, "TOPIC #a :Witches").unwrap_err();
+    assert_eq!(err.downcast_ref::<IrcError>().unwrap().numeric_code(), 442);
+    let err = run(&mut state, &mut eda, "TOPIC #b").unwrap_err();
+    assert_eq!(err.downcast_ref::<IrcError>().unwrap().numeric_code(), 403);
+
+    run(&mut state, &mut amity, "TOPIC #a :Hexside Witches").unwrap();
+    let change = ":amity!guest@127.0.0.1 TOPIC #a :Hexside Witches\r\n";
+    assert_eq!(read_reply(&mut amity_reader), change);
+    assert_eq!(read_reply(&mut luz_reader), change);
+    run(&mut state, &mut luz, "TOPIC #a").unwrap();
+    assert_eq!(
+        read_reply(&mut luz_reader),
+        ":irc.localhost 332 luz #a :Hexside Witches\r\n"
+    );
+    let who_time = read_reply(&mut luz_reader);
+    assert!(
+        who_time.starts_with(":irc.localhost 333 luz #a amity!guest@127.0.0.1 "),
+        "{who_time}"
+    );
+    // and joining shows it too
+    run(&mut state, &mut eda, "JOIN #a").unwrap();
+    read_reply(&mut eda_reader);
+    assert_eq!(
+        read_reply(&mut eda_reader),
+        ":irc.localhost 332 eda #a :Hexside Witches\r\n"
+    );
+    assert!(read_reply(&mut eda_reader).starts_with(":irc.localhost 333 eda #a "));
+
+    // Without +t any member may change it
+    run(&mut state, &mut amity, "MODE #a -t").unwrap();
+    run(&mut state, &mut luz, "TOPIC #a :").unwrap();
+    for _ in 0..2 {
+        read_reply(&mut amity_reader);
+    }
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        ":luz!guest@127.0.0.1 TOPIC #a :\r\n"
+    );
+    assert!(state.find_channel("#a").unwrap().topic.is_none());
+
+    // Secret channels keep their topic from outsiders
+    let (mut king, _king_reader) = registered_client(&mut state, "king");
+    run(&mut state, &mut amity, "MODE #a +s").unwrap();
+    let err = run(&mut state, &mut king, "TOPIC #a").unwrap_err();
+    assert_eq!(err.downcast_ref::<IrcError>().unwrap().numeric_code(), 442);
+}
+
+#[test]
+fn long_topics_are_cut_short() {
+    let path = state_path("topic-len");
+    let mut state = State::build(&crate::Config {
+        state_path: path.to_path_buf(),
+        topic_len: 7,
+        ..Default::default()
+    })
+    .unwrap();
+    let (mut client, mut reader) = test_client();
+    run(&mut state, &mut client, "NICK amity").unwrap();
+    run(&mut state, &mut client, "USER guest 0 * :Test User").unwrap();
+    for _ in 0..4 {
+        read_reply(&mut reader);
+    }
+    assert!(read_reply(&mut reader).contains(" TOPICLEN=7 "));
+    let (mut amity, mut amity_reader) = (client, reader);
+    run(&mut state, &mut amity, "JOIN #a").unwrap();
+    for _ in 0..3 {
+        read_reply(&mut amity_reader);
+    }
+
+    // Never in the middle of a character
+    run(&mut state, &mut amity, "TOPIC #a :Hexsidé Witches").unwrap();
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        ":amity!guest@127.0.0.1 TOPIC #a :Hexsid\r\n"
+    );
+}
+
+#[test]
+fn topics_are_saved() {
+    let path = state_path("topic");
+    std::fs::write(&path, SAVED_STATE).unwrap();
+    let mut state = build_state(&path).unwrap();
+    let (mut amity, _amity_reader) = registered_client(&mut state, "amity");
+    run(&mut state, &mut amity, "JOIN #hexside,#other").unwrap();
+    run(
+        &mut state,
+        &mut amity,
+        "TOPIC #other :Gone with the channel",
+    )
+    .unwrap();
+    run(&mut state, &mut amity, "TOPIC #hexside :Owl House").unwrap();
+    drop(state);
+
+    // Only journaled so far, as if the server had crashed
+    let mut state = build_state(&path).unwrap();
+    let channel = state.find_channel("#hexside").unwrap();
+    assert_eq!(channel.topic.as_deref(), Some("Owl House"));
+    assert_eq!(channel.topic_set_by, "amity!guest@127.0.0.1");
+    assert!(state.find_channel("#other").is_none());
+
+    save(&mut state);
+    let saved: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+    assert_eq!(saved["channels"][0]["topic"], "Owl House");
+    assert_eq!(
+        saved["channels"][0]["topic_set_by"],
+        "amity!guest@127.0.0.1"
+    );
+}
+
+#[test]
+fn parse_oper_commands() {
+    let mut line = "OPER eda :owl beast".to_owned();
+    let command = try_parse_from_line(&mut line).unwrap();
+    assert_eq!(
+        command.kind,
+        CommandKind::Oper {
+            name: "eda".to_owned(),
+            password: "owl beast".to_owned(),
+        }
+    );
+    assert_eq!(command.to_wire(), "OPER eda :owl beast\r\n");
+    assert_eq!(parse_error_code("OPER eda"), Some(461));
+    let mut line = "FLUSH".to_owned();
+    assert_eq!(
+        try_parse_from_line(&mut line).unwrap().kind,
+        CommandKind::Flush
+    );
+    let mut line = "REGCHAN #owls".to_owned();
+    let command = try_parse_from_line(&mut line).unwrap();
+    assert_eq!(
+        command.kind,
+        CommandKind::RegChan {
+            channel: "#owls".to_owned(),
+        }
+    );
+    assert_eq!(command.to_wire(), "REGCHAN #owls\r\n");
+    assert_eq!(parse_error_code("REGCHAN"), Some(461));
+}
+
+/// A config with "eda" as an operator, whose password is "owlbeast"
+fn oper_config(path: &std::path::Path) -> crate::Config {
+    let _ = std::fs::remove_file(path);
+    crate::Config {
+        state_path: path.to_path_buf(),
+        opers: vec![crate::config::OperConfig {
+            name: "eda".to_owned(),
+            password: "owlbeast".to_owned(),
+        }],
+        ..Default::default()
+    }
+}
+
+#[test]
+fn oper_needs_a_configured_login() {
+    let path = state_path("oper");
+    let mut state = State::build(&oper_config(&path)).unwrap();
+    let (mut amity, mut amity_reader) = registered_client(&mut state, "amity");
+
+    for line in ["OPER eda hunter2", "OPER amity owlbeast"] {
+        let err = run(&mut state, &mut amity, line).unwrap_err();
+        assert_eq!(err.downcast_ref::<IrcError>().unwrap().numeric_code(), 464);
+    }
+    assert!(!amity.is_oper());
+
+    run(&mut state, &mut amity, "OPER eda owlbeast").unwrap();
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        ":amity!guest@127.0.0.1 MODE amity +o\r\n"
+    );
+    assert_eq!(
+        read_reply(&mut amity_reader),
+        ":irc.localhost 381 amity :You are now an IRC operator\r\n"
+    );
+    assert!(amity.is_oper());
+    assert!(state.find_client("amity").unwrap().modes.contains(&'o'));
+}
+
+#[test]
+fn opers_from_config_file() {
+    use crate::config::OperConfig;
+    use crate::{Action, Config};
+
+    let files = state_path("oper-config");
+    let path = files.with_extension("toml");
+    std::fs::write(
+        &path,
+        "[[opers]]\nname = \"eda\"\npassword = \"owlbeast\"\n",
+    )
+    .unwrap();
+    let Action::Run(config) =
+        Config::from_args(args(&["-c", path.to_str().unwrap()]), |_| None).unwrap()
+    else {
+        panic!("expected to run");
+    };
+    assert_eq!(
+        config.opers,
+        [OperConfig {
+            name: "eda".to_owned(),
+            password: "owlbeast".to_owned(),
+        }]
+    );
+    // As printed by --check-config
+    let printed = format!("{config:#?}");
+    assert!(printed.contains("\"eda\""));
+    assert!(!printed.contains("owlbeast"));
+
+    std::fs::write(&path, "[[opers]]\nname = \"eda\"\npassword = \"\"\n").unwrap();
+    assert!(Config::from_args(args(&["-c", path.to_str().unwrap()]), |_| None).is_err());
+}
+
+/// Serves a connection with a burst of 10 and an excess of 5, returning the client's end
+fn flood_limited_connection(
+    config: crate::Config,
+) -> (
+    tokio::io::ReadHalf<tokio::io::DuplexStream>,
+    tokio::io::WriteHalf<tokio::io::DuplexStream>,
+) {
+    use crate::config::{FloodClasses, FloodLimits};
+    use crate::server::stream::Stream;
+    use std::sync::{Arc, RwLock};
+
+    let limits = FloodLimits {
+        burst: 10,
+        interval: std::time::Duration::from_millis(200),
+        excess: 5,
+    };
+    let state = State::build(&crate::Config {
+        flood: FloodClasses {
+            client: limits,
+            admin: limits,
+        },
+        ..config
+    })
+    .unwrap();
+    let (client_end, server_end) = tokio::io::duplex(64 * 1024);
+    let stream = Stream {
+        transport: Box::new(server_end),
+        peer_host: "127.0.0.1".to_owned(),
+        secure: false,
+    };
+    let state = Arc::new(RwLock::new(state));
+    RUNTIME.spawn(handle_client(state, stream, ListenerKind::Client));
+    tokio::io::split(client_end)
+}
+
+#[test]
+fn operators_are_not_held_back() {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+    let path = state_path("oper-flood");
+    let (reader, mut writer) = flood_limited_connection(oper_config(&path));
+    let mut lines = tokio::io::BufReader::new(reader).lines();
+    RUNTIME
+        .block_on(writer.write_all(b"NICK amity\r\nUSER guest 0 * :Amity\r\nOPER eda owlbeast\r\n"))
+        .unwrap();
+    RUNTIME.block_on(async {
+        while let Some(line) = lines.next_line().await.unwrap() {
+            if line.contains(" 381 ") {
+                break;
+            }
+        }
+    });
+
+    RUNTIME
+        .block_on(writer.write_all("PING token\r\n".repeat(20).as_bytes()))
+        .unwrap();
+    let replies = RUNTIME.block_on(async {
+        let mut replies = Vec::new();
+        while let Ok(Ok(Some(line))) =
+            tokio::time::timeout(std::time::Duration::from_secs(1), lines.next_line()).await
+        {
+            replies.push(line);
+        }
+        replies
+    });
+    assert_eq!(replies, [":irc.localhost PONG irc.localhost token"; 20]);
+}
+
+#[test]
+fn held_back_commands_run_after_eof() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let path = state_path("eof");
+    let (mut reader, mut writer) = flood_limited_connection(oper_config(&path));
+    // Enough to use up the burst, so the rest is held back until after the client has gone
+    let mut lines = "PING a\r\n".repeat(10);
+    lines.push_str("PING b\r\nQUIT :Goodbye\r\n");
+    RUNTIME
+        .block_on(writer.write_all(lines.as_bytes()))
+        .unwrap();
+    RUNTIME.block_on(writer.shutdown()).unwrap();
+
+    let mut replies = String::new();
+    RUNTIME
+        .block_on(reader.read_to_string(&mut replies))
+        .unwrap();
+    let replies: Vec<&str> = replies.lines().collect();
+    assert_eq!(replies.len(), 12);
+    assert_eq!(replies[10], ":irc.localhost PONG irc.localhost b");
+    assert_eq!(replies[11], "ERROR :Closing Link: 127.0.0.1 (Goodbye)");
+}
@@ -1,28 +1,474 @@
-use anyhow::Result;
-use std::fs::File;
+use anyhow::{Result, bail};
+use std::collections::{BTreeSet, HashMap};
+use std::net::IpAddr;
 use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use crate::config::{Config, FloodClasses, FloodLimits, InvalidUtf8, ListenerKind, OperConfig};
+use crate::server::client::Outbound;
+use crate::server::stream::peer_host;
+
+mod channel;
+mod file_format;
+mod journal;
+mod persistence;
+pub use channel::{Channel, ListEntry, Membership, ModeChange, Rank, mask_matches, normalize_mask};
+pub use file_format::to_unix_seconds;
+use file_format::{AccountRecord, ChannelRecord, ListRecord, StateFile, from_unix_seconds};
+use journal::JournalEntry;
+pub use journal::{Journal, JournalSync};
+use persistence::FlushRequests;
+pub use persistence::Persistence;
+
+pub const DEFAULT_SERVER_NAME: &str = "irc.localhost";
+/// How long a connection may be idle before it is sent a PING, and how long it then has to
+/// answer before being disconnected
+pub const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(120);
+/// How often the state file is rewritten, if anything has changed
+pub const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+/// How many bytes may be waiting to be written to a client before it is disconnected
+pub const DEFAULT_SENDQ: usize = 512 * 1024;
+/// Enough for a client to join a handful of channels at once, then a line a second
+pub const DEFAULT_CLIENT_FLOOD: FloodLimits = FloodLimits {
+    burst: 10,
+    interval: Duration::from_secs(1),
+    excess: 50,
+};
+/// Local tools on the admin listener are trusted to send much more
+pub const DEFAULT_ADMIN_FLOOD: FloodLimits = FloodLimits {
+    burst: 100,
+    interval: Duration::from_millis(10),
+    excess: 1000,
+};
+/// The most entries each of a channel's ban, exception and invite exception lists may hold
+pub const DEFAULT_MAX_LIST_ENTRIES: usize = 100;
+/// The longest topic a channel may have, in bytes, longer topics are cut short
+pub const DEFAULT_TOPIC_LEN: usize = 390;
+
+/// Lowercases a nickname or channel name using the RFC 1459 casemapping, where "[]\\~" are
+/// the uppercase forms of "{}|^"
+pub fn casemap(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '[' => '{',
+            ']' => '}',
+            '\\' => '|',
+            '~' => '^',
+            c => c.to_ascii_lowercase(),
+        })
+        .collect()
+}
+
+/// A registered client, as seen by everyone else on the server
+pub struct ClientEntry {
+    pub nickname: String,
+    pub user_name: String,
+    pub hostname: String,
+    pub real_name: String,
+    /// A copy of the client's user modes
+    pub modes: BTreeSet<char>,
+    pub outbound: Outbound,
+}
+
+/// A copy of the durable state, taken so that it can be written out without holding the
+/// state lock
+pub struct Snapshot {
+    file: StateFile,
+    path: PathBuf,
+    /// How much had been journaled when the copy was taken
+    journaled: u64,
+}
+
+impl Snapshot {
+    pub fn save(&self) -> Result<()> {
+        self.file.save(&self.path)
+    }
+}
 
 pub struct State {
-    _file_path: PathBuf,
-    _data: File,
+    file_path: PathBuf,
+    /// Changes made since the state file was last written
+    journal: Journal,
+    /// True when the state file is behind the journal
+    dirty: bool,
+    server_name: String,
+    created: SystemTime,
+    ping_interval: Duration,
+    sendq: usize,
+    invalid_utf8: InvalidUtf8,
+    flood: FloodClasses,
+    flood_exempt: Vec<String>,
+    max_list_entries: usize,
+    extended_ranks: bool,
+    topic_len: usize,
+    opers: Vec<OperConfig>,
+    /// Registered clients, keyed by casemapped nickname
+    clients: HashMap<String, ClientEntry>,
+    /// Keyed by casemapped channel name
+    channels: HashMap<String, Channel>,
+    /// Kept as loaded so that saving never drops them
+    accounts: Vec<AccountRecord>,
+    /// Every open connection, registered or not
+    connections: Vec<Outbound>,
+    /// Set once a Persistence has been started
+    flush_requests: Option<FlushRequests>,
+    shutting_down: bool,
 }
 impl State {
-    fn new(file_path: PathBuf, data: File) -> Self {
+    fn new(config: &Config, journal: Journal) -> Self {
         State {
-            _file_path: file_path,
-            _data: data,
+            file_path: config.state_path.clone(),
+            journal,
+            dirty: false,
+            server_name: config.server_name.clone(),
+            created: SystemTime::now(),
+            ping_interval: config.ping_interval,
+            sendq: config.sendq,
+            invalid_utf8: config.invalid_utf8,
+            flood: config.flood,
+            flood_exempt: config.flood_exempt.clone(),
+            max_list_entries: config.max_list_entries,
+            extended_ranks: config.extended_ranks,
+            topic_len: config.topic_len,
+            opers: config.opers.clone(),
+            clients: HashMap::new(),
+            channels: HashMap::new(),
+            accounts: Vec::new(),
+            connections: Vec::new(),
+            flush_requests: None,
+            shutting_down: false,
+        }
+    }
+    /// Loads the configured state file, creating it if it is missing or empty, then replays
+    /// anything journaled since it was last written
+    pub fn build(config: &Config) -> Result<Self> {
+        let path = &config.state_path;
+        if std::fs::metadata(path).map_or(true, |meta| meta.len() == 0) {
+            StateFile::default().save(path)?;
+        }
+        let journal = Journal::open(path)?;
+        let mut state = Self::new(config, journal);
+        state.reload_from_file()?;
+        Ok(state)
+    }
+    /// Copies what would be saved, or None if nothing has changed since the last save
+    pub fn snapshot_if_dirty(&self) -> Option<Snapshot> {
+        self.dirty.then(|| self.snapshot())
+    }
+    /// Copies what would be saved, for `Snapshot::save` to write out once the lock is released
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            file: self.to_file(),
+            path: self.file_path.clone(),
+            journaled: self.journal.appended(),
+        }
+    }
+    /// Empties the journal once a snapshot has been saved
+    /// Anything journaled after the snapshot was taken isn't in the state file yet, so then the
+    /// journal is kept whole for the next save to clear
+    pub fn saved(&mut self, snapshot: &Snapshot) -> Result<()> {
+        if self.journal.appended() == snapshot.journaled {
+            self.journal.clear()?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+    /// Has the state file written straight away, rather than waiting for the next interval
+    /// `done` is called with how it went once the write has finished, which happens without
+    /// the state lock held
+    pub fn request_flush(&self, done: impl FnOnce(Result<()>) + Send + 'static) -> Result<()> {
+        match &self.flush_requests {
+            Some(flush_requests) => flush_requests.request(done),
+            None => bail!("The state file isn't being saved in the background"),
+        }
+    }
+    /// Whatever is needed to sync the changes journaled since the last call to disk, so that it
+    /// can be done once the state lock has been released
+    pub fn take_unsynced_journal(&mut self) -> Option<JournalSync> {
+        self.journal.take_unsynced()
+    }
+    /// Merges the state file and journal back into the running state, without disconnecting
+    /// anyone
+    /// Persisted details replace the live ones, registered channels that are no longer
+    /// persisted are unregistered, and dropped if they are empty
+    /// Both files are loaded and checked before anything is changed, so if either can't be
+    /// loaded the running state is left as it was
+    pub fn reload_from_file(&mut self) -> Result<()> {
+        let file = StateFile::load(&self.file_path)?;
+        let journal = self.journal.entries()?;
+        let dirty = !journal.is_empty();
+
+        let mut records: Vec<ChannelRecord> = file.channels;
+        for entry in journal {
+            match entry {
+                JournalEntry::Channel(record) => {
+                    records.retain(|saved| casemap(&saved.name) != casemap(&record.name));
+                    records.push(record);
+                }
+            }
+        }
+        if let Some(record) = records
+            .iter()
+            .find(|record| !record.name.starts_with(['#', '&']) || record.name.len() < 2)
+        {
+            bail!(
+                "State file {} has a channel with an invalid name \"{}\"",
+                self.file_path.display(),
+                record.name
+            );
+        }
+
+        self.dirty = dirty;
+        for channel in self.channels.values_mut() {
+            channel.registered = false;
+        }
+        for record in records {
+            let channel = self
+                .channels
+                .entry(casemap(&record.name))
+                .or_insert_with(|| Channel::new(&record.name));
+            channel.registered = true;
+            channel.created = from_unix_seconds(record.created);
+            channel.topic = record.topic;
+            channel.topic_set_by = record.topic_set_by.unwrap_or_else(|| "*".to_owned());
+            channel.topic_set_at = from_unix_seconds(record.topic_set_at.unwrap_or_default());
+            channel.modes = record.modes.chars().collect();
+            channel.key = record.key;
+            channel.limit = record.limit;
+            channel.bans = record.bans.into_iter().map(list_entry).collect();
+            channel.exceptions = record.exceptions.into_iter().map(list_entry).collect();
+            channel.invite_exceptions = record
+                .invite_exceptions
+                .into_iter()
+                .map(list_entry)
+                .collect();
+        }
+        self.channels
+            .retain(|_, channel| channel.registered || !channel.is_empty());
+        self.accounts = file.accounts;
+        Ok(())
+    }
+
+    fn to_file(&self) -> StateFile {
+        let mut channels: Vec<ChannelRecord> = self
+            .channels
+            .values()
+            .filter(|channel| channel.registered)
+            .map(channel_record)
+            .collect();
+        channels.sort_by_key(|record| casemap(&record.name));
+        StateFile {
+            channels,
+            accounts: self.accounts.clone(),
+            ..StateFile::default()
+        }
+    }
+
+    pub fn server_name(&self) -> &str {
+        &self.server_name
+    }
+    pub fn created(&self) -> SystemTime {
+        self.created
+    }
+    pub fn ping_interval(&self) -> Duration {
+        self.ping_interval
+    }
+
+    /// The SendQ limit for new connections, in bytes
+    pub fn sendq(&self) -> usize {
+        self.sendq
+    }
+
+    /// What to do with lines that aren't valid UTF-8
+    pub fn invalid_utf8(&self) -> InvalidUtf8 {
+        self.invalid_utf8
+    }
+
+    /// How fast clients of a listener may send commands
+    pub fn flood_limits(&self, kind: ListenerKind) -> FloodLimits {
+        self.flood.get(kind)
+    }
+
+    /// True if the host is trusted not to flood
+    pub fn is_flood_exempt(&self, host: &str) -> bool {
+        // Addresses are compared as clients are shown, so that ::1 matches 0::1
+        self.flood_exempt
+            .iter()
+            .any(|exempt| match exempt.parse::<IpAddr>() {
+                Ok(ip) => peer_host(ip) == host,
+                Err(_) => exempt == host,
+            })
+    }
+
+    /// The most entries a channel's +b, +e or +I list may hold
+    pub fn max_list_entries(&self) -> usize {
+        self.max_list_entries
+    }
+    /// The longest topic a channel may have, in bytes
+    pub fn topic_len(&self) -> usize {
+        self.topic_len
+    }
+    /// True if `name` and `password` are those of a configured operator
+    pub fn is_oper_login(&self, name: &str, password: &str) -> bool {
+        self.opers
+            .iter()
+            .any(|oper| oper.name == name && oper.password == password)
+    }
+    /// The ranks channel members may be given, highest first
+    pub fn ranks(&self) -> Vec<Rank> {
+        Rank::ALL
+            .into_iter()
+            .filter(|rank| self.extended_ranks || !rank.is_extended())
+            .collect()
+    }
+
+    pub fn add_connection(&mut self, outbound: Outbound) {
+        self.connections.push(outbound);
+    }
+    pub fn remove_connection(&mut self, outbound: &Outbound) {
+        self.connections
+            .retain(|connection| !connection.is_same(outbound));
+    }
+    pub fn connections(&self) -> &[Outbound] {
+        &self.connections
+    }
+    /// The nickname of a registered client, looked up by its connection
+    pub fn nickname_of(&self, outbound: &Outbound) -> Option<&str> {
+        self.clients
+            .values()
+            .find(|entry| entry.outbound.is_same(outbound))
+            .map(|entry| entry.nickname.as_str())
+    }
+    /// Once the server is shutting down, departing clients are no longer announced
+    pub fn begin_shutdown(&mut self) {
+        self.shutting_down = true;
+    }
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down
+    }
+
+    pub fn add_client(&mut self, entry: ClientEntry) {
+        self.clients.insert(casemap(&entry.nickname), entry);
+    }
+    /// Removes a client, along with its membership of every channel
+    pub fn remove_client(&mut self, nickname: &str) -> Option<ClientEntry> {
+        for channel in self.channels_of(nickname) {
+            self.part_channel(&channel, nickname);
         }
+        self.clients.remove(&casemap(nickname))
     }
-    pub fn build(path: PathBuf) -> Result<Self> {
-        // TODO: this should create a new file probably
-        let data = File::open(&path)?;
-        Ok(Self::new(path, data))
+    pub fn rename_client(&mut self, old: &str, new: &str) {
+        if let Some(mut entry) = self.clients.remove(&casemap(old)) {
+            entry.nickname = new.to_owned();
+            self.clients.insert(casemap(new), entry);
+        }
+        for channel in self.channels.values_mut() {
+            channel.rename_member(old, new);
+        }
     }
-    // mut to prevent multiple threads from writing to the file at the same time
-    pub fn save(&mut self) -> Result<()> {
-        todo!()
+    pub fn find_client(&self, nickname: &str) -> Option<&ClientEntry> {
+        self.clients.get(&casemap(nickname))
     }
-    pub fn _reload_from_file(self) -> Result<Self> {
-        todo!()
+    pub fn clients(&self) -> impl Iterator<Item = &ClientEntry> {
+        self.clients.values()
+    }
+    /// Keeps everyone else's view of a client's user modes up to date
+    pub fn set_user_modes(&mut self, nickname: &str, modes: &BTreeSet<char>) {
+        if let Some(entry) = self.clients.get_mut(&casemap(nickname)) {
+            entry.modes = modes.clone();
+        }
+    }
+    /// True if a registered client other than `own_nickname` is using `nickname`
+    /// Comparison is case insensitive, so a client may change the case of its own nickname
+    pub fn is_nickname_in_use(&self, nickname: &str, own_nickname: Option<&str>) -> bool {
+        let nickname = casemap(nickname);
+        self.clients.contains_key(&nickname)
+            && own_nickname.is_none_or(|own| casemap(own) != nickname)
+    }
+
+    pub fn find_channel(&self, name: &str) -> Option<&Channel> {
+        self.channels.get(&casemap(name))
+    }
+    /// Changes a channel, journaling the change if the channel is registered
+    /// The journal isn't synced to disk until `take_unsynced_journal` is used
+    pub fn update_channel(&mut self, name: &str, update: impl FnOnce(&mut Channel)) -> Result<()> {
+        let Some(channel) = self.channels.get_mut(&casemap(name)) else {
+            return Ok(());
+        };
+        update(channel);
+        if channel.registered {
+            self.journal
+                .append(&JournalEntry::Channel(channel_record(channel)))?;
+            self.dirty = true;
+        }
+        Ok(())
+    }
+    /// The names of every channel a client is a member of
+    pub fn channels_of(&self, nickname: &str) -> Vec<String> {
+        self.channels
+            .values()
+            .filter(|channel| channel.is_member(nickname))
+            .map(|channel| channel.name.clone())
+            .collect()
+    }
+    /// Adds a client to a channel, creating the channel if needed
+    /// The client that creates a channel becomes its operator
+    pub fn join_channel(&mut self, name: &str, nickname: &str) -> &Channel {
+        let channel = self
+            .channels
+            .entry(casemap(name))
+            .or_insert_with(|| Channel::new(name));
+        let membership = match channel.is_empty() {
+            true => Membership::with(Rank::Operator),
+            false => Membership::default(),
+        };
+        channel.add_member(nickname, membership);
+        channel
+    }
+    /// Removes a client from a channel, the channel is removed once it is empty unless it is
+    /// registered
+    pub fn part_channel(&mut self, name: &str, nickname: &str) -> Option<Membership> {
+        let key = casemap(name);
+        let channel = self.channels.get_mut(&key)?;
+        let membership = channel.remove_member(nickname);
+        if channel.is_empty() && !channel.registered {
+            self.channels.remove(&key);
+        }
+        membership
+    }
+}
+
+fn channel_record(channel: &Channel) -> ChannelRecord {
+    ChannelRecord {
+        name: channel.name.clone(),
+        created: to_unix_seconds(channel.created),
+        topic: channel.topic.clone(),
+        topic_set_by: channel.topic.as_ref().map(|_| channel.topic_set_by.clone()),
+        topic_set_at: channel
+            .topic
+            .as_ref()
+            .map(|_| to_unix_seconds(channel.topic_set_at)),
+        modes: channel.modes.iter().collect(),
+        key: channel.key.clone(),
+        limit: channel.limit,
+        bans: channel.bans.iter().map(list_record).collect(),
+        exceptions: channel.exceptions.iter().map(list_record).collect(),
+        invite_exceptions: channel.invite_exceptions.iter().map(list_record).collect(),
+    }
+}
+
+fn list_record(entry: &ListEntry) -> ListRecord {
+    ListRecord {
+        mask: entry.mask.clone(),
+        set_by: entry.set_by.clone(),
+        set_at: to_unix_seconds(entry.set_at),
+    }
+}
+
+fn list_entry(record: ListRecord) -> ListEntry {
+    ListEntry {
+        mask: record.mask,
+        set_by: record.set_by,
+        set_at: from_unix_seconds(record.set_at),
     }
 }
@@ -0,0 +1,370 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::SystemTime;
+
+use super::casemap;
+
+/// A channel privilege, from least to most
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum Rank {
+    /// +v, may speak in a moderated channel
+    Voice,
+    /// +h
+    HalfOp,
+    /// +o
+    Operator,
+    /// +a
+    Admin,
+    /// +q
+    Owner,
+}
+
+impl Rank {
+    /// Highest first, the order ISUPPORT PREFIX lists them in
+    pub const ALL: [Rank; 5] = [
+        Rank::Owner,
+        Rank::Admin,
+        Rank::Operator,
+        Rank::HalfOp,
+        Rank::Voice,
+    ];
+
+    /// The channel mode that gives the rank
+    pub fn mode(self) -> char {
+        match self {
+            Rank::Voice => 'v',
+            Rank::HalfOp => 'h',
+            Rank::Operator => 'o',
+            Rank::Admin => 'a',
+            Rank::Owner => 'q',
+        }
+    }
+    /// What NAMES and WHO show in front of a member's nickname
+    pub fn prefix(self) -> char {
+        match self {
+            Rank::Voice => '+',
+            Rank::HalfOp => '%',
+            Rank::Operator => '@',
+            Rank::Admin => '&',
+            Rank::Owner => '~',
+        }
+    }
+    pub fn from_mode(mode: char) -> Option<Rank> {
+        Rank::ALL.into_iter().find(|rank| rank.mode() == mode)
+    }
+    /// Owner, admin and halfop only exist when extended ranks are turned on
+    pub fn is_extended(self) -> bool {
+        matches!(self, Rank::Owner | Rank::Admin | Rank::HalfOp)
+    }
+}
+
+/// A member's standing within a channel, every rank it has been given
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct Membership {
+    /// One bit per rank
+    ranks: u8,
+}
+
+impl Membership {
+    pub fn with(rank: Rank) -> Self {
+        let mut membership = Membership::default();
+        membership.set(rank, true);
+        membership
+    }
+    pub fn has(&self, rank: Rank) -> bool {
+        self.ranks & (1 << rank as u8) != 0
+    }
+    /// The highest rank held, the one NAMES and WHO show
+    pub fn highest(&self) -> Option<Rank> {
+        Rank::ALL.into_iter().find(|rank| self.has(*rank))
+    }
+    /// True if the member holds `rank` or any rank above it
+    pub fn is_at_least(&self, rank: Rank) -> bool {
+        self.highest().is_some_and(|highest| highest >= rank)
+    }
+    /// Gives or takes away a rank, returning false if that changed nothing
+    fn set(&mut self, rank: Rank, held: bool) -> bool {
+        let before = self.ranks;
+        match held {
+            true => self.ranks |= 1 << rank as u8,
+            false => self.ranks &= !(1 << rank as u8),
+        }
+        self.ranks != before
+    }
+}
+
+/// One mode being set or unset on a channel, eg +k with its key
+#[derive(Debug, PartialEq, Clone)]
+pub struct ModeChange {
+    pub set: bool,
+    pub mode: char,
+    pub param: Option<String>,
+}
+
+/// An entry in a ban (+b), exception (+e) or invite exception (+I) list
+#[derive(Debug, PartialEq, Clone)]
+pub struct ListEntry {
+    /// Always a full nick!user@host mask, which may contain '*' and '?' wildcards
+    pub mask: String,
+    /// Who added it, "*" if that isn't known
+    pub set_by: String,
+    pub set_at: SystemTime,
+}
+
+/// Modes set on every new channel, no messages from outside and only operators set the topic
+pub const DEFAULT_MODES: [char; 2] = ['n', 't'];
+
+pub struct Channel {
+    /// The name as it was first given, eg "#Foo" even if it is looked up as "#foo"
+    pub name: String,
+    pub created: SystemTime,
+    pub topic: Option<String>,
+    /// Who set the topic, "*" if that isn't known
+    pub topic_set_by: String,
+    pub topic_set_at: SystemTime,
+    /// Modes without a parameter, eg 'n' for no messages from outside
+    pub modes: BTreeSet<char>,
+    /// +k, needed to join
+    pub key: Option<String>,
+    /// +l, the most members the channel may have
+    pub limit: Option<usize>,
+    pub bans: Vec<ListEntry>,
+    /// Masks that are let in (and heard) even if they are banned
+    pub exceptions: Vec<ListEntry>,
+    /// Masks that may join while the channel is invite only
+    pub invite_exceptions: Vec<ListEntry>,
+    /// Registered channels are saved to the state file, and outlive their last member
+    pub registered: bool,
+    /// Keyed by nickname, ordered so NAMES output is stable
+    members: BTreeMap<String, Membership>,
+}
+
+impl Channel {
+    pub fn new(name: &str) -> Self {
+        Channel {
+            name: name.to_owned(),
+            created: SystemTime::now(),
+            topic: None,
+            topic_set_by: "*".to_owned(),
+            topic_set_at: SystemTime::UNIX_EPOCH,
+            modes: BTreeSet::from(DEFAULT_MODES),
+            key: None,
+            limit: None,
+            bans: Vec::new(),
+            exceptions: Vec::new(),
+            invite_exceptions: Vec::new(),
+            registered: false,
+            members: BTreeMap::new(),
+        }
+    }
+
+    pub fn is_member(&self, nickname: &str) -> bool {
+        self.members.contains_key(nickname)
+    }
+    pub fn nicknames(&self) -> impl Iterator<Item = &str> {
+        self.members.keys().map(|nick| nick.as_str())
+    }
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+    pub fn member_count(&self) -> usize {
+        self.members.len()
+    }
+    pub fn membership(&self, nickname: &str) -> Option<Membership> {
+        self.members.get(nickname).copied()
+    }
+
+    pub fn add_member(&mut self, nickname: &str, membership: Membership) {
+        self.members.insert(nickname.to_owned(), membership);
+    }
+    pub fn remove_member(&mut self, nickname: &str) -> Option<Membership> {
+        self.members.remove(nickname)
+    }
+    pub fn rename_member(&mut self, old: &str, new: &str) {
+        if let Some(membership) = self.members.remove(old) {
+            self.members.insert(new.to_owned(), membership);
+        }
+    }
+
+    /// The list behind a list mode, 'b', 'e' or 'I'
+    pub fn list(&self, mode: char) -> Option<&Vec<ListEntry>> {
+        match mode {
+            'b' => Some(&self.bans),
+            'e' => Some(&self.exceptions),
+            'I' => Some(&self.invite_exceptions),
+            _ => None,
+        }
+    }
+    fn list_mut(&mut self, mode: char) -> Option<&mut Vec<ListEntry>> {
+        match mode {
+            'b' => Some(&mut self.bans),
+            'e' => Some(&mut self.exceptions),
+            'I' => Some(&mut self.invite_exceptions),
+            _ => None,
+        }
+    }
+
+    /// True if `mask` (a client's nick!user@host) is banned, and not excepted
+    pub fn is_banned(&self, mask: &str) -> bool {
+        let listed =
+            |list: &Vec<ListEntry>| list.iter().any(|entry| mask_matches(&entry.mask, mask));
+        listed(&self.bans) && !listed(&self.exceptions)
+    }
+    /// True if `mask` may join even though the channel is invite only
+    pub fn is_invite_excepted(&self, mask: &str) -> bool {
+        self.invite_exceptions
+            .iter()
+            .any(|entry| mask_matches(&entry.mask, mask))
+    }
+
+    /// Applies a change that has already been checked, returning false if it changed nothing
+    /// `set_by` is recorded against anything added to a list
+    /// A rank change's parameter is the member's nickname as the channel knows it
+    pub fn apply_mode(&mut self, change: &ModeChange, set_by: &str) -> bool {
+        if let (Some(rank), Some(nickname)) = (Rank::from_mode(change.mode), &change.param) {
+            return self
+                .members
+                .get_mut(nickname)
+                .is_some_and(|membership| membership.set(rank, change.set));
+        }
+        if let (Some(list), Some(mask)) = (self.list_mut(change.mode), &change.param) {
+            let position = list
+                .iter()
+                .position(|entry| casemap(&entry.mask) == casemap(mask));
+            return match (change.set, position) {
+                (true, None) => {
+                    list.push(ListEntry {
+                        mask: mask.clone(),
+                        set_by: set_by.to_owned(),
+                        set_at: SystemTime::now(),
+                    });
+                    true
+                }
+                (false, Some(position)) => {
+                    list.remove(position);
+                    true
+                }
+                _ => false,
+            };
+        }
+        match (change.mode, change.set) {
+            ('k', true) => match self.key {
+                Some(_) => false,
+                None => {
+                    self.key = change.param.clone();
+                    true
+                }
+            },
+            ('k', false) => self.key.take().is_some(),
+            ('l', true) => {
+                let limit = change.param.as_deref().and_then(|limit| limit.parse().ok());
+                let changed = limit.is_some() && limit != self.limit;
+                if changed {
+                    self.limit = limit;
+                }
+                changed
+            }
+            ('l', false) => self.limit.take().is_some(),
+            (mode, true) => self.modes.insert(mode),
+            (mode, false) => self.modes.remove(&mode),
+        }
+    }
+
+    /// The modes as given in RPL_CHANNELMODEIS, eg "+klnt key 10"
+    /// The key is only shown to members
+    pub fn mode_string(&self, show_key: bool) -> String {
+        let letters: BTreeSet<char> = self
+            .modes
+            .iter()
+            .copied()
+            .chain(self.key.as_ref().map(|_| 'k'))
+            .chain(self.limit.map(|_| 'l'))
+            .collect();
+        let mut mode_string = format!("+{}", letters.into_iter().collect::<String>());
+        // In the same order as their letters
+        if let Some(key) = &self.key {
+            mode_string.push(' ');
+            mode_string.push_str(if show_key { key } else { "*" });
+        }
+        if let Some(limit) = self.limit {
+            mode_string.push_str(&format!(" {limit}"));
+        }
+        mode_string
+    }
+
+    /// The RPL_NAMREPLY channel type, "@" for secret, "*" for private and "=" for public
+    pub fn names_symbol(&self) -> char {
+        if self.modes.contains(&'s') {
+            '@'
+        } else if self.modes.contains(&'p') {
+            '*'
+        } else {
+            '='
+        }
+    }
+
+    /// True if the channel is +s or +p, and so kept from anyone who isn't a member
+    pub fn is_hidden(&self) -> bool {
+        self.modes.contains(&'s') || self.modes.contains(&'p')
+    }
+
+    /// The nicknames for RPL_NAMREPLY of the members `include` picks, with their privilege
+    /// prefixes
+    pub fn names(&self, include: impl Fn(&str) -> bool) -> Vec<String> {
+        self.members
+            .iter()
+            .filter(|(nick, _)| include(nick))
+            .map(|(nick, membership)| match membership.highest() {
+                Some(rank) => format!("{}{nick}", rank.prefix()),
+                None => nick.clone(),
+            })
+            .collect()
+    }
+}
+
+/// Fills in the missing parts of a ban style mask, eg "luz" becomes "luz!*@*" and
+/// "*@boiling.isles" becomes "*!*@boiling.isles"
+pub fn normalize_mask(mask: &str) -> String {
+    let (nick_user, host) = mask.rsplit_once('@').unwrap_or((mask, "*"));
+    let (nick, user) = match nick_user.split_once('!') {
+        Some((nick, user)) => (nick, user),
+        // Without a '!' a mask with an '@' is user@host, and one without is a nickname
+        None if mask.contains('@') => ("*", nick_user),
+        None => (nick_user, "*"),
+    };
+    let or_any = |part: &str| match part.is_empty() {
+        true => "*".to_owned(),
+        false => part.to_owned(),
+    };
+    format!("{}!{}@{}", or_any(nick), or_any(user), or_any(host))
+}
+
+/// Matches a mask with '*' (any run of characters) and '?' (any one character) wildcards,
+/// case insensitively
+pub fn mask_matches(mask: &str, target: &str) -> bool {
+    let mask: Vec<char> = casemap(mask).chars().collect();
+    let target: Vec<char> = casemap(target).chars().collect();
+    let (mut m, mut t) = (0, 0);
+    // Where the last '*' was, and how much of the target it has swallowed
+    let mut star = None;
+    while t < target.len() {
+        match mask.get(m) {
+            Some('*') => {
+                star = Some((m, t));
+                m += 1;
+            }
+            Some(&c) if c == '?' || c == target[t] => {
+                m += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_m, star_t)) => {
+                    star = Some((star_m, star_t + 1));
+                    m = star_m + 1;
+                    t = star_t + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    mask[m..].iter().all(|&c| c == '*')
+}
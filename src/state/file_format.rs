@@ -0,0 +1,158 @@
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Bumped whenever the layout changes in a way older servers can't read
+/// 2: list entries keep who set them and when, version 1 only had the mask
+pub const FORMAT_VERSION: u32 = 2;
+
+/// The durable part of the server state, as it is stored on disk (as JSON)
+/// Connected clients and channels that aren't registered are never saved
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct StateFile {
+    pub version: u32,
+    #[serde(default)]
+    pub channels: Vec<ChannelRecord>,
+    #[serde(default)]
+    pub accounts: Vec<AccountRecord>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct ChannelRecord {
+    pub name: String,
+    /// Seconds since the unix epoch
+    pub created: u64,
+    #[serde(default)]
+    pub topic: Option<String>,
+    /// Only saved along with a topic
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub topic_set_by: Option<String>,
+    /// Seconds since the unix epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub topic_set_at: Option<u64>,
+    /// Modes without a parameter
+    #[serde(default)]
+    pub modes: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+    #[serde(default)]
+    pub bans: Vec<ListRecord>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exceptions: Vec<ListRecord>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub invite_exceptions: Vec<ListRecord>,
+}
+
+/// A ban, exception or invite exception
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(from = "ListRecordFormat")]
+pub struct ListRecord {
+    pub mask: String,
+    pub set_by: String,
+    /// Seconds since the unix epoch
+    pub set_at: u64,
+}
+
+/// Every layout a ListRecord has been saved in
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ListRecordFormat {
+    /// Version 1, just the mask
+    Mask(String),
+    Full {
+        mask: String,
+        set_by: String,
+        set_at: u64,
+    },
+}
+
+impl From<ListRecordFormat> for ListRecord {
+    fn from(format: ListRecordFormat) -> Self {
+        match format {
+            ListRecordFormat::Mask(mask) => ListRecord {
+                mask,
+                set_by: "*".to_owned(),
+                set_at: 0,
+            },
+            ListRecordFormat::Full {
+                mask,
+                set_by,
+                set_at,
+            } => ListRecord {
+                mask,
+                set_by,
+                set_at,
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct AccountRecord {
+    pub name: String,
+    /// Seconds since the unix epoch
+    pub registered: u64,
+}
+
+impl Default for StateFile {
+    fn default() -> Self {
+        StateFile {
+            version: FORMAT_VERSION,
+            channels: Vec::new(),
+            accounts: Vec::new(),
+        }
+    }
+}
+
+impl StateFile {
+    pub fn load(path: &Path) -> Result<Self> {
+        let data = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read state file {}", path.display()))?;
+        let file: StateFile = serde_json::from_str(&data)
+            .with_context(|| format!("Malformed state file {}", path.display()))?;
+        if file.version > FORMAT_VERSION {
+            bail!(
+                "State file {} is version {}, but only versions up to {FORMAT_VERSION} are understood",
+                path.display(),
+                file.version
+            );
+        }
+        Ok(file)
+    }
+
+    /// Writes to a temporary file beside `path` and renames it into place, so a crash part way
+    /// through can never leave a half written state file behind
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".tmp");
+
+        let mut temp = File::create(&temp_path)
+            .with_context(|| format!("Failed to create {}", Path::new(&temp_path).display()))?;
+        temp.write_all(serde_json::to_string_pretty(self)?.as_bytes())?;
+        temp.sync_all()?;
+        fs::rename(&temp_path, path)
+            .with_context(|| format!("Failed to replace state file {}", path.display()))?;
+        // The rename only survives a crash once the directory it happened in has been synced
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()?;
+        Ok(())
+    }
+}
+
+pub fn to_unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+pub fn from_unix_seconds(seconds: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(seconds)
+}
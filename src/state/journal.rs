@@ -0,0 +1,115 @@
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use super::file_format::ChannelRecord;
+
+/// One change to the durable state, made since the state file was last written
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(tag = "kind")]
+pub enum JournalEntry {
+    /// The channel now looks like this, replacing whatever was saved before
+    Channel(ChannelRecord),
+}
+
+/// An append-only log of changes kept beside the state file, one JSON entry per line
+/// Replaying it over the state file gives back everything that was recorded before a crash
+/// Each entry is a channel's whole durable state, so replaying entries the state file
+/// already has is harmless
+pub struct Journal {
+    path: PathBuf,
+    file: Arc<File>,
+    /// Bytes appended since the journal was last emptied
+    len: u64,
+    /// True when an append hasn't been synced to disk yet
+    unsynced: bool,
+}
+
+/// Syncs a journal's appends to disk, so it can be done after the state lock is released
+pub struct JournalSync(Arc<File>);
+
+impl JournalSync {
+    pub fn sync(&self) -> Result<()> {
+        self.0.sync_data()?;
+        Ok(())
+    }
+}
+
+impl Journal {
+    pub fn path_for(state_path: &Path) -> PathBuf {
+        let mut path = state_path.as_os_str().to_owned();
+        path.push(".journal");
+        PathBuf::from(path)
+    }
+
+    pub fn open(state_path: &Path) -> Result<Self> {
+        let path = Self::path_for(state_path);
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open journal {}", path.display()))?;
+        let len = file.metadata()?.len();
+        Ok(Journal {
+            path,
+            file: Arc::new(file),
+            len,
+            unsynced: false,
+        })
+    }
+
+    /// Reads back every entry
+    /// A malformed final line is what a crash part way through an append leaves behind, so it
+    /// is dropped rather than treated as an error
+    pub fn entries(&self) -> Result<Vec<JournalEntry>> {
+        let data = fs::read_to_string(&self.path)
+            .with_context(|| format!("Failed to read journal {}", self.path.display()))?;
+        let lines: Vec<&str> = data.lines().filter(|line| !line.is_empty()).collect();
+
+        let mut entries = Vec::new();
+        for (i, line) in lines.iter().enumerate() {
+            match serde_json::from_str(line) {
+                Ok(entry) => entries.push(entry),
+                Err(_) if i + 1 == lines.len() && !data.ends_with('\n') => (),
+                Err(e) => bail!(
+                    "Malformed entry on line {} of journal {}: {e}",
+                    i + 1,
+                    self.path.display()
+                ),
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Appends an entry, which only reaches the disk once it has been synced
+    pub fn append(&mut self, entry: &JournalEntry) -> Result<()> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        (&*self.file).write_all(line.as_bytes())?;
+        self.len += line.len() as u64;
+        self.unsynced = true;
+        Ok(())
+    }
+
+    /// Bytes appended since the journal was last emptied
+    pub fn appended(&self) -> u64 {
+        self.len
+    }
+
+    /// Whatever is needed to sync the appends made since the last call, if there were any
+    pub fn take_unsynced(&mut self) -> Option<JournalSync> {
+        std::mem::take(&mut self.unsynced).then(|| JournalSync(Arc::clone(&self.file)))
+    }
+
+    /// Empties the journal, once everything in it has been written to the state file
+    /// Not synced, as replaying the old entries over the new state file changes nothing
+    pub fn clear(&mut self) -> Result<()> {
+        self.file.set_len(0)?;
+        self.len = 0;
+        self.unsynced = false;
+        Ok(())
+    }
+}
@@ -0,0 +1,102 @@
+use anyhow::{Result, anyhow};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use super::State;
+
+/// Called with how a requested flush went
+type FlushDone = Box<dyn FnOnce(Result<()>) + Send>;
+
+enum Request {
+    /// Flush now, and report back how it went
+    Flush(FlushDone),
+    /// Flush one last time and stop
+    Shutdown,
+}
+
+/// Writes the state file in the background, without holding the state lock while the disk is
+/// busy
+/// Changes are journaled as they happen, the state file itself is only rewritten when it is
+/// out of date: every `interval`, when asked to by an admin, and on shutdown
+pub struct Persistence {
+    requests: Sender<Request>,
+    handle: JoinHandle<()>,
+}
+
+/// Asks a running Persistence to flush, kept by the State so that admin commands can use it
+pub struct FlushRequests(Sender<Request>);
+
+impl FlushRequests {
+    /// `done` is called from the persistence thread once the state file has been written
+    pub fn request(&self, done: impl FnOnce(Result<()>) + Send + 'static) -> Result<()> {
+        self.0
+            .send(Request::Flush(Box::new(done)))
+            .map_err(|_| anyhow!("The persistence thread has stopped"))
+    }
+}
+
+impl Persistence {
+    pub fn start(state: &Arc<RwLock<State>>, interval: Duration) -> Result<Self> {
+        let (requests, receiver) = mpsc::channel();
+        state
+            .write()
+            .map_err(|_| anyhow!("The state lock is poisoned"))?
+            .flush_requests = Some(FlushRequests(requests.clone()));
+        let state = Arc::clone(state);
+
+        let handle = thread::spawn(move || {
+            loop {
+                let request = match receiver.recv_timeout(interval) {
+                    Ok(request) => Some(request),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => Some(Request::Shutdown),
+                };
+
+                let flushed = flush(&state);
+                match request {
+                    Some(Request::Flush(done)) => done(flushed),
+                    Some(Request::Shutdown) => {
+                        if let Err(e) = flushed {
+                            println!("Error: {e}");
+                        }
+                        break;
+                    }
+                    None => {
+                        if let Err(e) = flushed {
+                            println!("Error: {e}");
+                        }
+                    }
+                }
+            }
+        });
+
+        Ok(Persistence { requests, handle })
+    }
+
+    /// Performs a final flush and waits for it to finish
+    pub fn shutdown(self) -> Result<()> {
+        let _ = self.requests.send(Request::Shutdown);
+        self.handle
+            .join()
+            .map_err(|_| anyhow!("The persistence thread panicked"))
+    }
+}
+
+/// Copies the state while holding the lock for reading, writes the copy out with the lock
+/// released, and only takes the lock for writing again to empty the journal
+fn flush(state: &RwLock<State>) -> Result<()> {
+    let snapshot = state
+        .read()
+        .map_err(|_| anyhow!("The state lock is poisoned"))?
+        .snapshot_if_dirty();
+    let Some(snapshot) = snapshot else {
+        return Ok(());
+    };
+    snapshot.save()?;
+    state
+        .write()
+        .map_err(|_| anyhow!("The state lock is poisoned"))?
+        .saved(&snapshot)
+}
@@ -0,0 +1,115 @@
+use anyhow::{Context, Result, anyhow, bail};
+use rustls::server::{ClientHello, ResolvesServerCert, ServerConfig};
+use rustls::sign::CertifiedKey;
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+use crate::config::TlsPaths;
+
+/// The certificate a TLS listener presents, which can be swapped out while the server runs
+/// Connections that have already been made keep the certificate they were made with
+#[derive(Debug)]
+pub struct CertificateStore {
+    paths: TlsPaths,
+    provider: Arc<rustls::crypto::CryptoProvider>,
+    current: RwLock<Loaded>,
+}
+
+#[derive(Debug)]
+struct Loaded {
+    key: Arc<CertifiedKey>,
+    /// When the certificate and key files were last modified, as of loading them
+    modified: (Option<SystemTime>, Option<SystemTime>),
+}
+
+impl CertificateStore {
+    pub fn load(paths: &TlsPaths) -> Result<Arc<Self>> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let current = RwLock::new(load_certificate(paths, &provider)?);
+        Ok(Arc::new(CertificateStore {
+            paths: paths.clone(),
+            provider,
+            current,
+        }))
+    }
+
+    /// A rustls config for new connections, that uses whatever certificate is current
+    pub fn server_config(self: &Arc<Self>) -> Result<Arc<ServerConfig>> {
+        let config = ServerConfig::builder_with_provider(Arc::clone(&self.provider))
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::clone(self) as Arc<dyn ResolvesServerCert>);
+        Ok(Arc::new(config))
+    }
+
+    /// Loads the certificate and key again
+    /// If they can't be loaded the current certificate is kept, so a bad renewal never takes
+    /// the listener down
+    pub fn reload(&self) -> Result<()> {
+        let loaded = load_certificate(&self.paths, &self.provider)?;
+        *self
+            .current
+            .write()
+            .map_err(|_| anyhow!("Poisoned certificate store"))? = loaded;
+        Ok(())
+    }
+
+    /// Reloads if either file has been modified since it was last loaded
+    pub fn reload_if_changed(&self) -> Result<()> {
+        let modified = modified_times(&self.paths);
+        let changed = self
+            .current
+            .read()
+            .map_err(|_| anyhow!("Poisoned certificate store"))?
+            .modified
+            != modified;
+        match changed {
+            true => self.reload(),
+            false => Ok(()),
+        }
+    }
+}
+
+impl ResolvesServerCert for CertificateStore {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.current
+            .read()
+            .ok()
+            .map(|current| Arc::clone(&current.key))
+    }
+}
+
+fn modified_times(paths: &TlsPaths) -> (Option<SystemTime>, Option<SystemTime>) {
+    let modified = |path: &PathBuf| fs::metadata(path).and_then(|meta| meta.modified()).ok();
+    (modified(&paths.cert), modified(&paths.key))
+}
+
+fn load_certificate(paths: &TlsPaths, provider: &rustls::crypto::CryptoProvider) -> Result<Loaded> {
+    // Taken first, so that a change made while loading is picked up by the next check
+    let modified = modified_times(paths);
+
+    let open = |path: &PathBuf| {
+        File::open(path)
+            .map(BufReader::new)
+            .with_context(|| format!("Failed to open {}", path.display()))
+    };
+    let certs = rustls_pemfile::certs(&mut open(&paths.cert)?)
+        .collect::<io::Result<Vec<_>>>()
+        .with_context(|| format!("Malformed certificate {}", paths.cert.display()))?;
+    if certs.is_empty() {
+        bail!("No certificates found in {}", paths.cert.display());
+    }
+    let key = rustls_pemfile::private_key(&mut open(&paths.key)?)
+        .with_context(|| format!("Malformed private key {}", paths.key.display()))?
+        .ok_or_else(|| anyhow!("No private key found in {}", paths.key.display()))?;
+
+    let key = CertifiedKey::from_der(certs, key, provider)
+        .with_context(|| format!("Unusable certificate {}", paths.cert.display()))?;
+    Ok(Loaded {
+        key: Arc::new(key),
+        modified,
+    })
+}